@@ -0,0 +1,139 @@
+// This benchmark measures the throughput of walk over a large
+// synthetic tree, both listing entries only and reading all the file
+// contents, with and without speculative directory reads. Run it with:
+//
+//	cargo bench --bench walk
+//
+// The tree is created in a temporary directory and removed afterwards.
+// Set FSTREAM_BENCH_DIR to walk an existing directory instead.
+
+use fstream::fstream::{RecvData, RecvEntry};
+use fstream::{Engine, Registry, Tasks};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// The synthetic tree has DIRS top-level directories, each holding
+// SUBDIRS subdirectories of FILES files of FILE_SIZE bytes.
+const DIRS: usize = 20;
+const SUBDIRS: usize = 10;
+const FILES: usize = 50;
+const FILE_SIZE: usize = 4096;
+
+// RUNS holds the number of times each benchmark is run.
+const RUNS: usize = 3;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (root, temporary) = match std::env::var_os("FSTREAM_BENCH_DIR") {
+        Some(dir) => (PathBuf::from(dir), false),
+        None => {
+            let dir = std::env::temp_dir().join(format!("fstream-bench-{}", std::process::id()));
+            make_tree(&dir)?;
+            (dir, true)
+        }
+    };
+    let result = run(&root).await;
+    if temporary {
+        std::fs::remove_dir_all(&root)?;
+    }
+    result
+}
+
+async fn run(root: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    for &(flags, contents) in &[("", false), ("-j=8", false), ("", true), ("-j=8", true)] {
+        let mut best: Option<(Duration, Count)> = None;
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            let count = walk(root, flags, contents).await?;
+            let elapsed = start.elapsed();
+            if best.is_none_or(|(d, _)| elapsed < d) {
+                best = Some((elapsed, count));
+            }
+        }
+        let (elapsed, count) = best.unwrap();
+        let secs = elapsed.as_secs_f64();
+        println!(
+            "walk {:<4} {:<8} {:>8} entries {:>10.0} entries/s {:>8.1} MB/s ({:?})",
+            flags,
+            if contents { "contents" } else { "entries" },
+            count.entries,
+            count.entries as f64 / secs,
+            count.bytes as f64 / secs / 1e6,
+            elapsed,
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct Count {
+    entries: u64,
+    bytes: u64,
+}
+
+// walk runs a walk pipeline with the given flags on root and consumes
+// all its output, reading the contents of each file if contents is true.
+async fn walk(
+    root: &Path,
+    flags: &str,
+    contents: bool,
+) -> Result<Count, Box<dyn std::error::Error>> {
+    let mut registry = Registry::new();
+    let types = fstream::register(&mut registry)?;
+    let engine = Engine::with_registry(registry);
+    let quoted = root.display().to_string().replace('\'', "''");
+    let pipeline = engine.compile(&format!("walk {} '{}'", flags, quoted), types.fs())?;
+    let mut tasks = Tasks::new();
+    let root = types.to_fs(engine.start(pipeline, &mut tasks)?);
+
+    let (_, mut dir) = root.dir().await?;
+    let mut count = Count::default();
+    loop {
+        match dir.entry().await? {
+            RecvEntry::File(_, action) => {
+                count.entries += 1;
+                if !contents {
+                    dir = action.next().await?;
+                    continue;
+                }
+                let mut file = action.down().await?;
+                dir = loop {
+                    match file.data().await? {
+                        RecvData::Bytes(data, file1) => {
+                            count.bytes += data.len() as u64;
+                            file = file1;
+                        }
+                        RecvData::End(dir) => break dir,
+                    }
+                };
+            }
+            RecvEntry::Dir(_, action) => {
+                count.entries += 1;
+                dir = action.down().await?;
+            }
+            RecvEntry::Error(err, d) => {
+                eprintln!("{}", err);
+                dir = d;
+            }
+            RecvEntry::End(Some(parent)) => dir = parent,
+            RecvEntry::End(None) => break,
+        }
+    }
+    tasks.join().await?;
+    Ok(count)
+}
+
+// make_tree creates the synthetic tree underneath dir.
+fn make_tree(dir: &Path) -> std::io::Result<()> {
+    let data = vec![b'x'; FILE_SIZE];
+    for i in 0..DIRS {
+        for j in 0..SUBDIRS {
+            let sub = dir.join(format!("d{}", i)).join(format!("s{}", j));
+            std::fs::create_dir_all(&sub)?;
+            for k in 0..FILES {
+                std::fs::write(sub.join(format!("f{}", k)), &data)?;
+            }
+        }
+    }
+    Ok(())
+}
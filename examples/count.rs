@@ -0,0 +1,43 @@
+// This example shows how a pipeline's output can be consumed
+// directly from Rust. It counts the files and directories
+// produced by the expression given as its argument, for example:
+//
+//	cargo run --example count 'walk . | filter {mode d}'
+
+use fstream::fstream::RecvEntry;
+use fstream::{Engine, Registry, Tasks};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let expr = std::env::args().nth(1).unwrap_or_else(|| "walk .".to_string());
+    let mut registry = Registry::new();
+    let types = fstream::register(&mut registry)?;
+    let engine = Engine::with_registry(registry);
+    let pipeline = engine.compile(&expr, types.fs())?;
+    let mut tasks = Tasks::new();
+    let root = types.to_fs(engine.start(pipeline, &mut tasks)?);
+
+    let (_, mut dir) = root.dir().await?;
+    let (mut files, mut dirs) = (0, 0);
+    loop {
+        match dir.entry().await? {
+            RecvEntry::File(_, action) => {
+                files += 1;
+                dir = action.next().await?;
+            }
+            RecvEntry::Dir(_, action) => {
+                dirs += 1;
+                dir = action.down().await?;
+            }
+            RecvEntry::Error(err, d) => {
+                eprintln!("{}", err);
+                dir = d;
+            }
+            RecvEntry::End(Some(parent)) => dir = parent,
+            RecvEntry::End(None) => break,
+        }
+    }
+    tasks.join().await?;
+    println!("{} files, {} directories", files, dirs);
+    Ok(())
+}
@@ -1,98 +1,313 @@
+// The abc module implements the dynamic type system used by pipelines.
+//
+// Every type of value that can flow between commands is registered
+// with a Registry, which hands back a typed Converter that can be used
+// to convert between static Rust values and dynamic Values. Commands
+// are registered with the same Registry, so modules can add new types
+// and the commands that produce and consume them without any central
+// list of types.
+
+use snafu::Snafu;
+use std::any::Any;
+use std::collections::HashMap as Map;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use super::Command;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub")]
+pub enum Error {
+    #[snafu(display("type {} already registered", name))]
+    ErrAlreadyRegisteredType { name: String },
+    #[snafu(display("command {} already registered", name))]
+    ErrAlreadyRegisteredCommand { name: String },
+    #[snafu(display("conversion to type {} already registered", name))]
+    ErrAlreadyRegisteredConversion { name: String },
+}
+
+// Parser parses the textual form of a value, as used in flag values.
+type Parser = Box<dyn Fn(&str) -> std::result::Result<Value, String> + Send + Sync>;
+
+// Registry holds all the known types and commands.
 pub struct Registry {
-	id: u32,
-	types: std::container::MapHash<String, TypeID>,
-	commands: Map<String, Box<dyn Command>>,
+    id: u32,
+    types: Map<String, Type>,
+    commands: Map<String, Box<dyn Command>>,
+    conversions: Map<Type, String>,
+    parsers: Map<Type, Parser>,
+    std: StdTypes,
 }
 
-#[derive(Copy,Clone,Debug)]
-struct TypeID(u32);
+// StdTypes holds the types that are built in to every registry
+// because the pipeline language itself relies on them.
+#[derive(Clone)]
+pub struct StdTypes {
+    // void is the type of a command that doesn't return anything.
+    pub void: Converter<()>,
+    // string is the type of a literal word.
+    pub string: Converter<String>,
+    // int is the type of an integer, as used in flag values.
+    pub int: Converter<i64>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
 
 impl Registry {
-	pub fn new() -> Registry {
-		Registry{
-			id: 0,
-			types:  std::container::MapHash::new(),
-			commands: std::container::MapHash::new(),
-		}
-	}
-	pub fn new_type<T>(&mut self, name: &str) -> Result<Converter<T>> {
-		if let Some(_) = self.types.get(name) {
-			return Err(ErrAlreadyRegisteredType{name: name.to_string}).build())
-		}
-		self.id.0 += 1;			// or generate UUID?
-		self.types.insert(name.to_string(), self.id);
-		Ok(Converter{
-			abctype: Type{
-			id: self.self.id,
-			name: Arc::new(name.to_string()),
-		})
-	}
-	pub fn add_command(&mut self, name: &str, cmd: Box<dyn Command>) Result<()> {
-		if let Some(_) = self.commands.get(name) {
-			return Err(ErrAlreadyRegisteredCommand{name: name.to_string}.build())
-		}
-		self.commands.insert(name.to_string(), cmd);
-	}
-}
-
-#[derive(PartialEq, Debug, Clone)]
+    // new returns a registry holding only the standard types.
+    pub fn new() -> Registry {
+        let mut types = Map::new();
+        let mut id = 0;
+        let mut new_type = |name: &str| {
+            id += 1;
+            let t = Type {
+                id: TypeID(id),
+                name: Arc::from(name),
+            };
+            types.insert(name.to_string(), t.clone());
+            t
+        };
+        let std = StdTypes {
+            void: Converter::new(new_type("void")),
+            string: Converter::new(new_type("string")),
+            int: Converter::new(new_type("int")),
+        };
+        let mut registry = Registry {
+            id,
+            types,
+            commands: Map::new(),
+            conversions: Map::new(),
+            parsers: Map::new(),
+            std,
+        };
+        let std = registry.std.clone();
+        registry.add_parser(&std.string, |s| Ok(s.to_string()));
+        registry.add_parser(&std.int, |s| {
+            s.parse()
+                .map_err(|_| format!("invalid integer {:?}", s))
+        });
+        registry
+    }
+
+    // std returns the standard types.
+    pub fn std(&self) -> &StdTypes {
+        &self.std
+    }
+
+    // new_type registers a new type with the given name and returns
+    // a converter that can be used to convert values of that type
+    // to and from dynamic values.
+    pub fn new_type<V: Any + Send>(&mut self, name: &str) -> Result<Converter<V>> {
+        if self.types.contains_key(name) {
+            return ErrAlreadyRegisteredType { name }.fail();
+        }
+        self.id += 1; // or generate UUID?
+        let t = Type {
+            id: TypeID(self.id),
+            name: Arc::from(name),
+        };
+        self.types.insert(name.to_string(), t.clone());
+        Ok(Converter::new(t))
+    }
+
+    // lookup_type returns the type with the given name.
+    pub fn lookup_type(&self, name: &str) -> Option<&Type> {
+        self.types.get(name)
+    }
+
+    // add_command registers a command with the given name.
+    pub fn add_command(&mut self, name: &str, cmd: Box<dyn Command>) -> Result<()> {
+        if self.commands.contains_key(name) {
+            return ErrAlreadyRegisteredCommand { name }.fail();
+        }
+        self.commands.insert(name.to_string(), cmd);
+        Ok(())
+    }
+
+    // command returns the command with the given name.
+    pub fn command(&self, name: &str) -> Option<&dyn Command> {
+        self.commands.get(name).map(|c| c.as_ref())
+    }
+
+    // add_conversion registers the named command as the way to
+    // convert a value to the type to. The command must take a single
+    // argument; when a value of some other type is found where
+    // a value of type to is required, the type checker will
+    // wrap it in the command, converting the command's argument
+    // in turn if necessary.
+    pub fn add_conversion(&mut self, to: &Type, cmd_name: &str) -> Result<()> {
+        if self.conversions.contains_key(to) {
+            return ErrAlreadyRegisteredConversion {
+                name: to.name().to_string(),
+            }
+            .fail();
+        }
+        self.conversions.insert(to.clone(), cmd_name.to_string());
+        Ok(())
+    }
+
+    // conversion returns the name of the command registered
+    // to convert values to the given type.
+    pub fn conversion(&self, to: &Type) -> Option<&str> {
+        self.conversions.get(to).map(String::as_str)
+    }
+
+    // add_parser registers a function that parses the textual form of
+    // values of the converter's type, which allows the type to be
+    // used for flag values.
+    pub fn add_parser<V, F>(&mut self, cvt: &Converter<V>, parse: F)
+    where
+        V: Any + Send,
+        F: Fn(&str) -> std::result::Result<V, String> + Send + Sync + 'static,
+    {
+        let cvt = cvt.clone();
+        self.parsers.insert(
+            cvt.get_type().clone(),
+            Box::new(move |s| parse(s).map(|v| cvt.to_value(v))),
+        );
+    }
+
+    // parse parses s as a value of type t. It returns None
+    // if no parser has been registered for t.
+    pub fn parse(&self, t: &Type, s: &str) -> Option<std::result::Result<Value, String>> {
+        self.parsers.get(t).map(|parse| parse(s))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct TypeID(u32);
+
+// Type represents a registered type. Types compare
+// equal only if they were returned by the same call
+// to Registry::new_type.
+#[derive(Debug, Clone)]
 pub struct Type {
-	id: TypeId,
-	name: Arc<String>,
+    id: TypeID,
+    name: Arc<str>,
+}
+
+impl Type {
+    // name returns the name the type was registered with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PartialEq for Type {
+    fn eq(&self, other: &Type) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Type {}
+
+impl std::hash::Hash for Type {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
 }
 
 impl fmt::Display for Type {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		write!(f, "{}", self.name);
-	}
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
 }
 
-#[derive(Clone)]
+// Converter converts between values of the static type V
+// and dynamic values of its registered type.
 pub struct Converter<V> {
-	abctype: Type,
+    abctype: Type,
+    _v: PhantomData<fn(V) -> V>,
 }
 
-impl Converter<V> {
-	// get_type returns the alphabet type associated with the converter.
-	fn get_type(&self) -> &Type {
-		&self.abctype
-	}
+// Note: derive(Clone) would require V: Clone.
+impl<V> Clone for Converter<V> {
+    fn clone(&self) -> Self {
+        Converter::new(self.abctype.clone())
+    }
+}
+
+impl<V> Converter<V> {
+    fn new(abctype: Type) -> Converter<V> {
+        Converter {
+            abctype,
+            _v: PhantomData,
+        }
+    }
+
+    // get_type returns the type associated with the converter.
+    pub fn get_type(&self) -> &Type {
+        &self.abctype
+    }
+}
+
+impl<V: Any + Send> Converter<V> {
+    // to_value converts v to a dynamic Value.
+    pub fn to_value(&self, v: V) -> Value {
+        Value {
+            v: Box::new(v),
+            vtype: self.abctype.clone(),
+        }
+    }
 
-	// to_value converts v to a dynamic Value.
-	fn to_dyn(&self, v: 'a V) -> 'a Value {
-		Value{
-			v: Box::new(v)
-			vtype: v,
-		}
-	}
+    // try_from_value converts the dynamic Value v to the
+    // static type, returning v itself if it holds a value
+    // of some other type.
+    pub fn try_from_value(&self, v: Value) -> std::result::Result<V, Value> {
+        if v.vtype != self.abctype {
+            return Err(v);
+        }
+        match v.v.downcast::<V>() {
+            Ok(v) => Ok(*v),
+            Err(_) => unreachable!("value of type {} has unexpected dynamic type", v.vtype),
+        }
+    }
 
-	// from_value converts the dynamic Value v to the
-	// static type.
-	fn from_dyn(v: Value) -> Option<V> {
-		v.v.downcast::<V>
-	}
+    // from_value converts the dynamic Value v to the static
+    // type. It panics if v holds a value of some other type;
+    // the type checker guarantees that command arguments
+    // always have the types that the command declares.
+    pub fn from_value(&self, v: Value) -> V {
+        match self.try_from_value(v) {
+            Ok(v) => v,
+            Err(v) => unreachable!(
+                "unexpected value type; want {}, got {}",
+                self.abctype,
+                v.get_type()
+            ),
+        }
+    }
 }
 
 // Value represents a dynamic value holding a value
-// with a registered alphabet type.
-struct Value {
-	v: Box<dyn Any>)
-	vtype: Type,
-}
-
-struct CommandType {
-            flags: Vec<String>,
-            args: Vec<Type>,
-            var_args: Option<Type>,
-            ret: Type,
-}
-
-trait Command {
-            fn get_type(&self) -> &CommandType;
-            fn start(
-                &self,
-                tasks: &mut Tasks,
-                flags: MapHash<String, Value>,
-                args: Vec<Value>,
-            ) -> fstream::Result<Value>;
+// with a registered type.
+pub struct Value {
+    v: Box<dyn Any + Send>,
+    vtype: Type,
+}
+
+impl Value {
+    // get_type returns the type of the value.
+    pub fn get_type(&self) -> &Type {
+        &self.vtype
+    }
+
+    // downcast_ref returns a reference to the value held
+    // if it has the static type T.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.v.downcast_ref()
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} value", self.vtype)
+    }
 }
@@ -1,33 +1,330 @@
 use super::fstream;
+use futures::FutureExt;
+use std::sync::Arc;
 
+use super::fs;
 use super::CommandType;
+use super::Scanner;
+use super::Selection;
+use super::Selector;
 use super::Value;
 
-pub fn new_command() -> impl super::Command {
-    Command(CommandType {
-        flags: vec![],
-        args: vec![],
-        var_args: Some(super::Type::Selector),
-        ret: super::Type::Selector,
-    })
+// and returns a selector that selects the entries that all its
+// arguments select. The arguments are tried in order until one
+// of them doesn't select the entry.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![],
+            var_args: Some(types.selector()),
+            ret: types.selector(),
+        },
+    }
 }
 
-struct Command(CommandType);
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
 
 impl super::Command for Command {
     fn fs_type(&self) -> &super::CommandType {
-        return &self.0;
+        &self.ctype
     }
     fn start(
         &self,
         _tasks: &mut super::Tasks,
-        _flags: Vec<String>,
+        _flags: super::Flags,
         args: Vec<Value>,
-        _rest: Vec<Value>,
     ) -> fstream::Result<Value> {
-        let selectors = args.into_iter().map(|v| v.as_selector()).collect::<Result<Vec<_>, _>>()?;
-        Ok(super::Value::Selector(Box::new(move |entry, path| {
-                selectors.iter().all(|selector| selector(entry, path))
-         })))
+        let selectors: Vec<_> = args.into_iter().map(|v| self.types.to_selector(v)).collect();
+        Ok(self.types.from_selector(and(selectors)))
+    }
+}
+
+// and returns a selector that selects the entries that all
+// the given selectors select.
+pub fn and(selectors: Vec<Selector>) -> Selector {
+    short_circuit(selectors, false)
+}
+
+// short_circuit returns a selector that tries each of the given
+// selectors in turn until one returns stop or fails, and returns
+// that result, or !stop if none of them do. The scanners of any data
+// selectors that can't tell without the data in the file are combined
+// in the same way. The selector is asynchronous only if one of the
+// given selectors is, and a data selector only if one of them is.
+pub fn short_circuit(selectors: Vec<Selector>, stop: bool) -> Selector {
+    if selectors
+        .iter()
+        .any(|selector| matches!(selector, Selector::Data(_)))
+    {
+        let selectors = Arc::new(selectors);
+        return Selector::Data(Box::new(move |entry, path, depth| {
+            let selectors = selectors.clone();
+            async move { select(&selectors, entry, path, depth, stop).await }.boxed()
+        }));
+    }
+    if selectors
+        .iter()
+        .any(|selector| matches!(selector, Selector::Async(_)))
+    {
+        let selectors = Arc::new(selectors);
+        return Selector::new_async(move |entry, path, depth| {
+            let selectors = selectors.clone();
+            async move {
+                match select(&selectors, entry, path, depth, stop).await? {
+                    Selection::Known(selected) => Ok(selected),
+                    Selection::Scan(_) => unreachable!("no data selectors"),
+                }
+            }
+            .boxed()
+        });
+    }
+    let selectors: Vec<_> = selectors
+        .into_iter()
+        .map(|selector| match selector {
+            Selector::Sync(f) => f,
+            _ => unreachable!("no asynchronous selectors"),
+        })
+        .collect();
+    Selector::new(move |entry, path, depth| {
+        for f in &selectors {
+            if f(entry, path, depth)? == stop {
+                return Ok(stop);
+            }
+        }
+        Ok(!stop)
+    })
+}
+
+// select is the asynchronous part of short_circuit.
+async fn select(
+    selectors: &[Selector],
+    entry: &fstream::DirEntry,
+    path: &std::path::PathBuf,
+    depth: i32,
+    stop: bool,
+) -> std::io::Result<Selection> {
+    let mut scanners = vec![];
+    for selector in selectors {
+        match selector.select(entry, path, depth).await? {
+            Selection::Known(selected) if selected == stop => return Ok(Selection::Known(stop)),
+            Selection::Known(_) => (),
+            Selection::Scan(scanner) => scanners.push(scanner),
+        }
+    }
+    Ok(if scanners.is_empty() {
+        Selection::Known(!stop)
+    } else {
+        Selection::Scan(Box::new(ShortCircuit { scanners, stop }))
+    })
+}
+
+// ShortCircuit combines scanners in the same way as short_circuit
+// combines selectors, deciding as soon as one of them returns stop.
+struct ShortCircuit {
+    scanners: Vec<Box<dyn Scanner>>,
+    stop: bool,
+}
+
+impl Scanner for ShortCircuit {
+    fn write(&mut self, data: &[u8]) -> Option<bool> {
+        let stop = self.stop;
+        let mut stopped = false;
+        self.scanners
+            .retain_mut(|scanner| match scanner.write(data) {
+                Some(selected) => {
+                    stopped |= selected == stop;
+                    false
+                }
+                None => true,
+            });
+        if stopped {
+            Some(stop)
+        } else if self.scanners.is_empty() {
+            Some(!stop)
+        } else {
+            None
+        }
+    }
+
+    fn finish(&mut self) -> bool {
+        let stop = self.stop;
+        if self
+            .scanners
+            .iter_mut()
+            .any(|scanner| scanner.finish() == stop)
+        {
+            stop
+        } else {
+            !stop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn constant(value: bool) -> Selector {
+        Selector::new(move |_, _, _| Ok(value))
+    }
+
+    // slow returns an asynchronous selector that returns
+    // value after a delay.
+    fn slow(value: bool) -> Selector {
+        Selector::new_async(move |_, _, _| {
+            async move {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                Ok(value)
+            }
+            .boxed()
+        })
+    }
+
+    // failing returns an asynchronous selector that fails,
+    // counting the number of times that it's called.
+    fn failing(calls: &Arc<AtomicUsize>) -> Selector {
+        let calls = calls.clone();
+        Selector::new_async(move |_, _, _| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Err(std::io::Error::other("failed")) }.boxed()
+        })
+    }
+
+    async fn select(selector: &Selector) -> std::io::Result<bool> {
+        let entry = fstream::DirEntry::new("x", fstream::Kind::File);
+        match selector.select(&entry, &"t/x".into(), 1).await? {
+            Selection::Known(selected) => Ok(selected),
+            Selection::Scan(_) => panic!("unexpected scan"),
+        }
+    }
+
+    #[tokio::test]
+    async fn and_short_circuits() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        for first in [constant(false), slow(false)] {
+            let selector = and(vec![first, failing(&calls)]);
+            assert!(matches!(selector, Selector::Async(_)));
+            assert!(!select(&selector).await.unwrap());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn and_fails() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let selector = and(vec![slow(true), failing(&calls), constant(false)]);
+        let err = select(&selector).await.unwrap_err();
+        assert_eq!(err.to_string(), "failed");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn and_slow() {
+        for &(values, want) in &[
+            (&[true, true][..], true),
+            (&[true, false], false),
+            (&[false, true], false),
+            (&[], true),
+        ] {
+            let selectors = values.iter().map(|&value| slow(value)).collect();
+            assert_eq!(select(&and(selectors)).await.unwrap(), want, "{:?}", values);
+        }
+    }
+
+    #[tokio::test]
+    async fn or_short_circuits() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let selector = crate::or::or(vec![slow(true), failing(&calls)]);
+        assert!(select(&selector).await.unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    // compile returns the selector that the given expression evaluates to.
+    fn compile(expr: &str) -> Selector {
+        let mut registry = crate::Registry::new();
+        let types = crate::register(&mut registry).unwrap();
+        let engine = crate::Engine::with_registry(registry);
+        let pipeline = engine.compile(expr, types.selector()).unwrap();
+        let mut tasks = crate::Tasks::new();
+        types.to_selector(engine.start(pipeline, &mut tasks).unwrap())
+    }
+
+    // selected returns the names of those of the given entries
+    // that the selector selects.
+    async fn selected(selector: &Selector, entries: &[(&str, fstream::Kind)]) -> Vec<String> {
+        let mut names = vec![];
+        for &(name, kind) in entries {
+            let entry = fstream::DirEntry::new(name, kind);
+            let path = std::path::PathBuf::from("t").join(name);
+            if let Selection::Known(true) = selector.select(&entry, &path, 1).await.unwrap() {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+
+    #[tokio::test]
+    async fn selector_commands() {
+        let entries = [
+            ("a", fstream::Kind::File),
+            (".a", fstream::Kind::File),
+            ("d", fstream::Kind::Dir),
+            (".d", fstream::Kind::Dir),
+        ];
+        for &(expr, want) in &[
+            ("true", &["a", ".a", "d", ".d"][..]),
+            ("false", &[]),
+            ("not {true}", &[]),
+            ("not {false}", &["a", ".a", "d", ".d"]),
+            ("and", &["a", ".a", "d", ".d"]),
+            ("and {true} {false}", &[]),
+            ("and {true} {true}", &["a", ".a", "d", ".d"]),
+            ("not {mode d}", &["a", ".a"]),
+            ("and {mode d} {not {name '.*'}}", &["d"]),
+            ("not {and {mode d} {not {name '.*'}}}", &["a", ".a", ".d"]),
+        ] {
+            assert_eq!(selected(&compile(expr), &entries).await, want, "{}", expr);
+        }
+    }
+
+    #[tokio::test]
+    async fn filter_visible_dirs() {
+        let base = std::env::temp_dir().join(format!("fstream-and-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        for dir in &["src/.hidden", ".git"] {
+            std::fs::create_dir_all(base.join(dir)).unwrap();
+        }
+        for file in &["a", "src/main.rs", ".git/config"] {
+            std::fs::write(base.join(file), "").unwrap();
+        }
+        let mut registry = crate::Registry::new();
+        let types = crate::register(&mut registry).unwrap();
+        let engine = crate::Engine::with_registry(registry);
+        let expr = format!(
+            "walk {} | filter {{and {{mode d}} {{not {{name '.*'}}}}}}",
+            base.display()
+        );
+        let pipeline = engine.compile(&expr, types.fs()).unwrap();
+        let mut tasks = crate::Tasks::new();
+        let root = types.to_fs(engine.start(pipeline, &mut tasks).unwrap());
+        let (send_entries, mut recv_entries) = fstream::new_entries();
+        let entries = tokio::spawn(crate::entries::entries(root, send_entries));
+        let mut paths = vec![];
+        while let Some(msg) = recv_entries.recv().await {
+            let path = msg.unwrap().path;
+            paths.push(path.strip_prefix(&base).unwrap().to_path_buf());
+        }
+        entries.await.unwrap().unwrap();
+        tasks.join().await.unwrap();
+        // The file a isn't chosen, and the hidden directories
+        // are discarded along with everything in them.
+        assert_eq!(paths, [std::path::PathBuf::from("src")]);
+        std::fs::remove_dir_all(&base).unwrap();
     }
 }
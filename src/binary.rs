@@ -0,0 +1,68 @@
+use super::fstream;
+
+use super::fs;
+use super::CommandType;
+use super::Scanner;
+use super::Selection;
+use super::Selector;
+use super::Value;
+
+// binary returns a selector that selects regular files that look like
+// binary files rather than text: those with a NUL byte in the first
+// block of their data in the stream, which is like the test that
+// grep(1) uses.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![],
+            var_args: None,
+            ret: types.selector(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        _tasks: &mut super::Tasks,
+        _flags: super::Flags,
+        _args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        Ok(self.types.from_selector(binary()))
+    }
+}
+
+// binary returns the selector for the binary command.
+pub fn binary() -> Selector {
+    Selector::new_data(|entry, _path, _depth| {
+        Ok(if entry.is_file() {
+            Selection::Scan(Box::new(Binary))
+        } else {
+            Selection::Known(false)
+        })
+    })
+}
+
+// Binary scans a file for a NUL byte in its first block.
+struct Binary;
+
+impl Scanner for Binary {
+    fn write(&mut self, data: &[u8]) -> Option<bool> {
+        Some(data.contains(&0))
+    }
+
+    fn finish(&mut self) -> bool {
+        // The file is empty.
+        false
+    }
+}
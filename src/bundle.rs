@@ -0,0 +1,122 @@
+use super::fstream;
+use super::fstream::wire;
+use snafu::{ResultExt, Snafu};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::fs;
+use super::CommandType;
+use super::FlagType;
+use super::Value;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    ErrFstream { source: fstream::Error },
+    #[snafu(display("{}", source))]
+    ErrIO { source: std::io::Error },
+}
+
+// bundle writes the whole of its input stream, including the
+// contents of all files, in the format described in the
+// fstream::wire module. It writes to the standard output
+// unless the -o flag specifies a file.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![FlagType::value("o", types.string())],
+            args: vec![types.fs()],
+            var_args: None,
+            ret: types.void(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        tasks: &mut super::Tasks,
+        flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let root = self.types.to_fs(args.pop().unwrap());
+        let out = flags.string("o");
+        tasks.add(tokio::spawn(async move {
+            bundle_to(out, root).await.context(super::ErrBundle)
+        }));
+        Ok(self.types.from_void())
+    }
+}
+
+// bundle_to writes a bundle of root to the named file,
+// or to the standard output if there is no name.
+async fn bundle_to(out: Option<String>, root: fstream::RecvRoot) -> Result<()> {
+    match out {
+        Some(path) => {
+            let f = tokio::fs::File::create(path).await.context(ErrIO)?;
+            bundle(root, &mut tokio::io::BufWriter::new(f)).await
+        }
+        None => bundle(root, &mut tokio::io::BufWriter::new(tokio::io::stdout())).await,
+    }
+}
+
+// bundle writes all the data in root to w, including
+// the contents of every file.
+pub async fn bundle<W: AsyncWrite + Unpin>(root: fstream::RecvRoot, w: &mut W) -> Result<()> {
+    wire::write_header(w).await.context(ErrIO)?;
+    let (path, dir) = root.dir().await.context(ErrFstream)?;
+    write(w, fstream::FsData::Root(path, dir.order())).await?;
+    let mut dir = dir;
+    loop {
+        match dir.entry().await.context(ErrFstream)? {
+            fstream::RecvEntry::File(entry, action) => {
+                write(w, fstream::FsData::FileEntry(entry)).await?;
+                let mut file = action.down().await.context(ErrFstream)?;
+                loop {
+                    match file.data().await.context(ErrFstream)? {
+                        fstream::RecvData::Bytes(data, file1) => {
+                            write(w, fstream::FsData::Data(data)).await?;
+                            file = file1;
+                        }
+                        fstream::RecvData::End(dir1) => {
+                            write(w, fstream::FsData::End).await?;
+                            dir = dir1;
+                            break;
+                        }
+                    }
+                }
+            }
+            fstream::RecvEntry::Dir(entry, action) => {
+                write(w, fstream::FsData::DirEntry(entry)).await?;
+                dir = action.down().await.context(ErrFstream)?;
+            }
+            fstream::RecvEntry::Error(err, dir1) => {
+                write(w, fstream::FsData::Error(err)).await?;
+                dir = dir1;
+            }
+            fstream::RecvEntry::End(Some(dir1)) => {
+                write(w, fstream::FsData::End).await?;
+                dir = dir1;
+            }
+            fstream::RecvEntry::End(None) => {
+                write(w, fstream::FsData::End).await?;
+                return w.flush().await.context(ErrIO);
+            }
+        }
+    }
+}
+
+async fn write<W: AsyncWrite + Unpin>(w: &mut W, data: fstream::FsData) -> Result<()> {
+    wire::write_data(w, &data).await.context(ErrIO)
+}
@@ -0,0 +1,386 @@
+use super::fstream;
+use snafu::{ResultExt, Snafu};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use super::fs;
+use super::sum;
+use super::CommandType;
+use super::FlagType;
+use super::Value;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    ErrFstream { source: fstream::Error },
+    #[snafu(display("{}", source))]
+    ErrIO { source: std::io::Error },
+    #[snafu(display(
+        "found {} difference{} from the manifest",
+        count,
+        if *count == 1 { "" } else { "s" }
+    ))]
+    ErrMismatch { count: usize },
+}
+
+// check verifies the regular files in its input stream against a
+// manifest in the format printed by sum and sha256sum(1), where the
+// paths include the root path of the stream. It prints a line for each
+// file that's in both but is modified, because its checksum differs or
+// it's no longer a regular file, each file in the manifest that's
+// missing from the stream, and each file in the stream that's extra.
+// The check fails if there are any such files. The -a flag names the
+// algorithm used by the manifest, as for sum. Files that aren't in the
+// manifest aren't read.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![FlagType::value("a", types.string())],
+            args: vec![types.fs(), types.string()],
+            var_args: None,
+            ret: types.void(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        tasks: &mut super::Tasks,
+        flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let manifest_path = self.types.to_string(args.pop().unwrap());
+        let root = self.types.to_fs(args.pop().unwrap());
+        let algorithm = sum::Algorithm::parse(flags.string("a").as_deref())?;
+        let data = std::fs::read(&manifest_path).map_err(|err| {
+            fstream::ErrUsage {
+                msg: format!("cannot read manifest {}: {}", manifest_path, err),
+            }
+            .build()
+        })?;
+        let manifest = parse_manifest(&data, algorithm).map_err(|msg| {
+            fstream::ErrUsage {
+                msg: format!("invalid manifest {}: {}", manifest_path, msg),
+            }
+            .build()
+        })?;
+        tasks.add(tokio::spawn(async move {
+            check(root, manifest, algorithm, std::io::stdout())
+                .await
+                .context(super::ErrCheck)
+        }));
+        Ok(self.types.from_void())
+    }
+}
+
+// Manifest maps the path of each file in a manifest to its checksum.
+pub type Manifest = BTreeMap<PathBuf, String>;
+
+// parse_manifest parses a manifest in the format printed by sum.
+// Blank lines are ignored.
+pub fn parse_manifest(
+    data: &[u8],
+    algorithm: sum::Algorithm,
+) -> std::result::Result<Manifest, String> {
+    let sum_len = algorithm.hasher().finish().len();
+    let mut manifest = Manifest::new();
+    for (i, line) in data.split(|&b| b == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let (sum, path) = parse_line(line, sum_len)
+            .ok_or_else(|| format!("line {}: invalid checksum line", i + 1))?;
+        if manifest.insert(path, sum).is_some() {
+            return Err(format!("line {}: duplicate path", i + 1));
+        }
+    }
+    Ok(manifest)
+}
+
+// parse_line parses a single line of a manifest holding a checksum
+// of the given length. Like sha256sum -c, it accepts a * before the
+// path, as printed for files read in binary mode.
+fn parse_line(line: &[u8], sum_len: usize) -> Option<(String, PathBuf)> {
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    if line.len() < sum_len + 3 || !line[..sum_len].iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    if line[sum_len] != b' ' || !(line[sum_len + 1] == b' ' || line[sum_len + 1] == b'*') {
+        return None;
+    }
+    let sum = String::from_utf8_lossy(&line[..sum_len]).to_ascii_lowercase();
+    let name = &line[sum_len + 2..];
+    let name = if escaped {
+        unescape(name)?
+    } else {
+        name.to_vec()
+    };
+    Some((sum, PathBuf::from(OsStr::from_bytes(&name))))
+}
+
+// unescape reverses the escaping done by sum::manifest_line.
+fn unescape(name: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'\\' {
+            unescaped.push(b);
+            continue;
+        }
+        unescaped.push(match bytes.next()? {
+            b'\\' => b'\\',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            _ => return None,
+        });
+    }
+    Some(unescaped)
+}
+
+// check checks the files in root against the manifest,
+// writing a line to out for each difference.
+pub async fn check<W: Write>(
+    root: fstream::RecvRoot,
+    manifest: Manifest,
+    algorithm: sum::Algorithm,
+    out: W,
+) -> Result<()> {
+    let mut manifest = manifest;
+    let mut out = out;
+    let mut count: usize = 0;
+    let (path, dir) = root.dir().await.context(ErrFstream)?;
+    let mut path = path;
+    let mut dir = dir;
+    loop {
+        match dir.entry().await.context(ErrFstream)? {
+            fstream::RecvEntry::File(entry, action) => {
+                path.push(entry.file_name());
+                match manifest.remove(&path) {
+                    Some(want) if entry.is_file() => {
+                        let file = action.down().await.context(ErrFstream)?;
+                        let (got, dir1) =
+                            sum::sum_file(file, algorithm).await.context(ErrFstream)?;
+                        if got != want {
+                            report(&mut out, &path, "MODIFIED")?;
+                            count += 1;
+                        }
+                        dir = dir1;
+                    }
+                    Some(_) => {
+                        report(&mut out, &path, "MODIFIED")?;
+                        count += 1;
+                        dir = action.next().await.context(ErrFstream)?;
+                    }
+                    None => {
+                        if entry.is_file() {
+                            report(&mut out, &path, "EXTRA")?;
+                            count += 1;
+                        }
+                        dir = action.next().await.context(ErrFstream)?;
+                    }
+                }
+                path.pop();
+            }
+            fstream::RecvEntry::Dir(entry, action) => {
+                path.push(entry.file_name());
+                dir = action.down().await.context(ErrFstream)?;
+            }
+            fstream::RecvEntry::Error(err, dir1) => {
+                eprintln!("{}", err);
+                dir = dir1;
+            }
+            fstream::RecvEntry::End(Some(dir1)) => {
+                path.pop();
+                dir = dir1;
+            }
+            fstream::RecvEntry::End(None) => break,
+        }
+    }
+    for path in manifest.keys() {
+        report(&mut out, path, "MISSING")?;
+        count += 1;
+    }
+    out.flush().context(ErrIO)?;
+    if count > 0 {
+        return ErrMismatch { count }.fail();
+    }
+    Ok(())
+}
+
+// report writes a line to out saying what's wrong with the file at path.
+fn report<W: Write>(out: &mut W, path: &Path, what: &str) -> Result<()> {
+    let mut line = path.as_os_str().as_bytes().to_vec();
+    line.extend(format!(": {}\n", what).as_bytes());
+    out.write_all(&line).context(ErrIO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUM: &str = "0123456789abcdef";
+
+    #[test]
+    fn parse_manifest_line() {
+        for name in &[
+            &b"a"[..],
+            b"dir/file name",
+            b"back\\slash",
+            b"new\nline",
+            b"carriage\rreturn",
+            b"\\\\n",
+            b"not \xff utf-8",
+            b" leading space",
+        ] {
+            let path = Path::new(OsStr::from_bytes(name));
+            let line = sum::manifest_line(SUM, path);
+            let line = line.strip_suffix(b"\n").unwrap();
+            assert_eq!(
+                parse_line(line, SUM.len()),
+                Some((SUM.to_string(), path.to_path_buf())),
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn parse_line_variants() {
+        let path = PathBuf::from("a/b");
+        let want = Some((SUM.to_string(), path));
+        assert_eq!(parse_line(b"0123456789abcdef  a/b", 16), want);
+        assert_eq!(parse_line(b"0123456789ABCDEF  a/b", 16), want);
+        assert_eq!(parse_line(b"0123456789abcdef *a/b", 16), want);
+        assert_eq!(parse_line(b"\\0123456789abcdef  a/b", 16), want);
+        for line in &[
+            &b"0123456789abcdef a/b"[..],
+            b"0123456789abcdef  ",
+            b"0123456789abcde  a/b",
+            b"0123456789abcdef0  a/b",
+            b"0123456789abcdeg  a/b",
+            b"0123456789abcdef\t a/b",
+            b"\\0123456789abcdef  a\\tb",
+        ] {
+            assert_eq!(parse_line(line, 16), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn unescape_names() {
+        assert_eq!(unescape(b"abc"), Some(b"abc".to_vec()));
+        assert_eq!(unescape(b"a\\\\b\\nc\\r"), Some(b"a\\b\nc\r".to_vec()));
+        assert_eq!(unescape(b"a\\"), None);
+        assert_eq!(unescape(b"a\\t"), None);
+    }
+
+    #[test]
+    fn parse_manifest_errors() {
+        let algorithm = sum::Algorithm::Crc32;
+        let manifest = parse_manifest(b"01234567  a\n\n89abcdef  b\n", algorithm).unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(manifest[Path::new("b")], "89abcdef");
+        assert_eq!(
+            parse_manifest(b"01234567  a\n\nbad\n", algorithm),
+            Err("line 3: invalid checksum line".to_string())
+        );
+        assert_eq!(
+            parse_manifest(b"01234567  a\n89abcdef  a\n", algorithm),
+            Err("line 2: duplicate path".to_string())
+        );
+    }
+
+    // send sends a root directory t holding the given entries,
+    // with the given data in those that are regular files.
+    async fn send(
+        root: fstream::SendRoot,
+        entries: Vec<(&str, fstream::Kind, &[u8])>,
+    ) -> fstream::Result<()> {
+        let mut dir = root
+            .dir(PathBuf::from("t"), fstream::Order::Sorted)
+            .await?
+            .unwrap();
+        for (name, kind, data) in entries {
+            dir = match dir.file(fstream::DirEntry::new(name, kind)).await? {
+                fstream::SendFileEntryAction::Down(file) => match file.data(data.to_vec()).await? {
+                    fstream::SendFileAction::Next(file) => file.end().await?,
+                    fstream::SendFileAction::Skip(dir) => dir,
+                },
+                fstream::SendFileEntryAction::Next(dir) => dir,
+                _ => panic!("unexpected action"),
+            };
+        }
+        assert!(dir.end().await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_differences() {
+        let algorithm = sum::Algorithm::Sha256;
+        let sum_of = |data: &[u8]| {
+            let mut hasher = algorithm.hasher();
+            hasher.update(data);
+            hasher.finish()
+        };
+        let mut manifest = Manifest::new();
+        manifest.insert("t/same".into(), sum_of(b"same"));
+        manifest.insert("t/changed".into(), sum_of(b"before"));
+        manifest.insert("t/link".into(), sum_of(b"link"));
+        manifest.insert("t/gone".into(), sum_of(b"gone"));
+        let entries: Vec<(&str, fstream::Kind, &[u8])> = vec![
+            ("changed", fstream::Kind::File, b"after"),
+            ("link", fstream::Kind::Symlink, b""),
+            ("new", fstream::Kind::File, b"new"),
+            ("same", fstream::Kind::File, b"same"),
+        ];
+        let (send_root, recv_root) = fstream::new();
+        let sender = tokio::spawn(send(send_root, entries));
+        let mut out = vec![];
+        let err = check(recv_root, manifest, algorithm, &mut out)
+            .await
+            .unwrap_err();
+        sender.await.unwrap().unwrap();
+        assert_eq!(err.to_string(), "found 4 differences from the manifest");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "t/changed: MODIFIED\nt/link: MODIFIED\nt/new: EXTRA\nt/gone: MISSING\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn check_same() {
+        let algorithm = sum::Algorithm::Crc32;
+        let mut manifest = Manifest::new();
+        manifest.insert("t/a".into(), "352441c2".to_string());
+        let entries: Vec<(&str, fstream::Kind, &[u8])> = vec![
+            ("a", fstream::Kind::File, b"abc"),
+            ("d", fstream::Kind::Symlink, b""),
+        ];
+        let (send_root, recv_root) = fstream::new();
+        let sender = tokio::spawn(send(send_root, entries));
+        let mut out = vec![];
+        check(recv_root, manifest, algorithm, &mut out)
+            .await
+            .unwrap();
+        sender.await.unwrap().unwrap();
+        assert!(out.is_empty());
+    }
+}
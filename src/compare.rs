@@ -0,0 +1,270 @@
+use super::fstream;
+use std::time::SystemTime;
+
+use super::fs;
+use super::CommandType;
+use super::Selector;
+use super::Value;
+
+// Attr specifies the attribute of an entry that's compared.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Attr {
+    // Size compares the size in bytes. Values may have a k, M, G or T
+    // suffix, meaning units of 1024, 1024², 1024³ or 1024⁴ bytes.
+    Size,
+    // Mtime compares the time since the entry was last modified.
+    // Values may have an s, m, h, d or w suffix, meaning
+    // seconds, minutes, hours, days or weeks; the default is seconds.
+    Mtime,
+    // Depth compares the depth of the entry, where the
+    // entries in the root directory are at depth 1.
+    Depth,
+    Uid,
+    Gid,
+    Nlink,
+}
+
+const SIZE_UNITS: &[(char, i64)] = &[
+    ('k', 1 << 10),
+    ('K', 1 << 10),
+    ('M', 1 << 20),
+    ('G', 1 << 30),
+    ('T', 1 << 40),
+];
+
+const TIME_UNITS: &[(char, i64)] = &[
+    ('s', 1),
+    ('m', 60),
+    ('h', 60 * 60),
+    ('d', 24 * 60 * 60),
+    ('w', 7 * 24 * 60 * 60),
+];
+
+impl Attr {
+    fn units(&self) -> &'static [(char, i64)] {
+        match self {
+            Attr::Size => SIZE_UNITS,
+            Attr::Mtime => TIME_UNITS,
+            _ => &[],
+        }
+    }
+}
+
+// compare returns a selector that compares an attribute of each entry
+// against a value. The argument holds a comparison operator followed
+// by a number: one of <, <=, =, !=, >= or >, or + or - as synonyms for
+// > and < as used by find(1). With no operator, the attribute must be
+// equal to the number. For example, size '>10M' selects entries bigger
+// than ten megabytes and mtime '<7d' selects entries modified within
+// the last week. It's registered as size, mtime, depth, uid, gid
+// and nlink.
+pub fn new_command(types: &fs::Types, attr: Attr) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        attr,
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![types.string()],
+            var_args: None,
+            ret: types.selector(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    attr: Attr,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        _tasks: &mut super::Tasks,
+        _flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let spec = self.types.to_string(args.pop().unwrap());
+        let cmp = Comparison::parse(&spec, self.attr.units()).map_err(|msg| {
+            fstream::ErrUsage {
+                msg: format!("invalid comparison {:?}: {}", spec, msg),
+            }
+            .build()
+        })?;
+        let attr = self.attr;
+        // Measure all ages from the same time so that
+        // the results are consistent throughout the walk.
+        let now = SystemTime::now();
+        Ok(self
+            .types
+            .from_selector(Selector::new(move |entry, _path, depth| {
+                Ok(cmp.matches(match attr {
+                    Attr::Size => entry.size as i64,
+                    Attr::Mtime => age(now, entry.mtime),
+                    Attr::Depth => depth as i64,
+                    Attr::Uid => entry.uid as i64,
+                    Attr::Gid => entry.gid as i64,
+                    Attr::Nlink => entry.nlink as i64,
+                }))
+            })))
+    }
+}
+
+// age returns the number of seconds between t and now,
+// which is negative if t is in the future.
+fn age(now: SystemTime, t: SystemTime) -> i64 {
+    match now.duration_since(t) {
+        Ok(d) => d.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
+}
+
+// Op represents a comparison operator.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Op {
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    Ge,
+    Gt,
+}
+
+// Comparison holds a parsed comparison against a value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    pub op: Op,
+    pub value: i64,
+}
+
+impl Comparison {
+    // parse parses a comparison as described in the documentation
+    // of new_command above. The value may have a suffix from units,
+    // which holds the multiplier for each suffix character.
+    pub fn parse(s: &str, units: &[(char, i64)]) -> std::result::Result<Comparison, String> {
+        let ops = [
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("!=", Op::Ne),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+            ("=", Op::Eq),
+            ("+", Op::Gt),
+            ("-", Op::Lt),
+        ];
+        let (op, rest) = ops
+            .iter()
+            .find(|(prefix, _)| s.starts_with(prefix))
+            .map(|&(prefix, op)| (op, &s[prefix.len()..]))
+            .unwrap_or((Op::Eq, s));
+        let (digits, mult) = match rest.chars().last() {
+            Some(c) if !c.is_ascii_digit() => match units.iter().find(|(u, _)| *u == c) {
+                Some(&(_, mult)) => (&rest[..rest.len() - c.len_utf8()], mult),
+                None => return Err(format!("unknown unit {:?}", c)),
+            },
+            _ => (rest, 1),
+        };
+        let n: i64 = digits
+            .parse()
+            .map_err(|_| format!("invalid number {:?}", digits))?;
+        let value = n
+            .checked_mul(mult)
+            .ok_or_else(|| "value out of range".to_string())?;
+        Ok(Comparison { op, value })
+    }
+
+    // matches reports whether x satisfies the comparison.
+    pub fn matches(&self, x: i64) -> bool {
+        match self.op {
+            Op::Lt => x < self.value,
+            Op::Le => x <= self.value,
+            Op::Eq => x == self.value,
+            Op::Ne => x != self.value,
+            Op::Ge => x >= self.value,
+            Op::Gt => x > self.value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmp(op: Op, value: i64) -> Comparison {
+        Comparison { op, value }
+    }
+
+    #[test]
+    fn parse_ops() {
+        for &(s, op) in &[
+            ("<5", Op::Lt),
+            ("<=5", Op::Le),
+            ("=5", Op::Eq),
+            ("5", Op::Eq),
+            ("!=5", Op::Ne),
+            (">=5", Op::Ge),
+            (">5", Op::Gt),
+            ("+5", Op::Gt),
+            ("-5", Op::Lt),
+        ] {
+            assert_eq!(Comparison::parse(s, &[]), Ok(cmp(op, 5)), "{}", s);
+        }
+        assert_eq!(Comparison::parse(">-5", &[]), Ok(cmp(Op::Gt, -5)));
+    }
+
+    #[test]
+    fn parse_size() {
+        let units = Attr::Size.units();
+        assert_eq!(Comparison::parse(">10", units), Ok(cmp(Op::Gt, 10)));
+        assert_eq!(Comparison::parse(">10k", units), Ok(cmp(Op::Gt, 10 << 10)));
+        assert_eq!(Comparison::parse("<3K", units), Ok(cmp(Op::Lt, 3 << 10)));
+        assert_eq!(Comparison::parse("10M", units), Ok(cmp(Op::Eq, 10 << 20)));
+        assert_eq!(Comparison::parse("+2G", units), Ok(cmp(Op::Gt, 2 << 30)));
+        assert_eq!(Comparison::parse("-1T", units), Ok(cmp(Op::Lt, 1 << 40)));
+    }
+
+    #[test]
+    fn parse_mtime() {
+        let units = Attr::Mtime.units();
+        assert_eq!(Comparison::parse("<30", units), Ok(cmp(Op::Lt, 30)));
+        assert_eq!(Comparison::parse("<30s", units), Ok(cmp(Op::Lt, 30)));
+        assert_eq!(Comparison::parse(">5m", units), Ok(cmp(Op::Gt, 300)));
+        assert_eq!(Comparison::parse("<=2h", units), Ok(cmp(Op::Le, 7200)));
+        assert_eq!(Comparison::parse("<7d", units), Ok(cmp(Op::Lt, 604800)));
+        assert_eq!(Comparison::parse(">1w", units), Ok(cmp(Op::Gt, 604800)));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let size = Attr::Size.units();
+        let mtime = Attr::Mtime.units();
+        for &(s, units) in &[
+            ("", size),
+            (">", size),
+            ("10d", size),
+            ("10M", mtime),
+            ("5k", Attr::Depth.units()),
+            ("k", size),
+            ("1.5M", size),
+            ("<<5", size),
+            ("9999999T", size),
+            ("10é", mtime),
+        ] {
+            assert!(Comparison::parse(s, units).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let c = cmp(Op::Le, 5);
+        assert!(c.matches(4) && c.matches(5) && !c.matches(6));
+        let c = cmp(Op::Ne, 5);
+        assert!(c.matches(4) && !c.matches(5) && c.matches(6));
+        let c = cmp(Op::Gt, 5);
+        assert!(!c.matches(4) && !c.matches(5) && c.matches(6));
+    }
+}
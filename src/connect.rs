@@ -0,0 +1,49 @@
+use super::fstream;
+use snafu::ResultExt;
+
+use super::fs;
+use super::net;
+use super::CommandType;
+use super::Value;
+
+// connect connects to the address given as its argument, where
+// another process is running the serve command, and produces the
+// stream that's served there. Actions taken by the receiver are sent
+// back to the serving process, so the remote sender only reads
+// what's actually needed.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![types.string()],
+            var_args: None,
+            ret: types.fs(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        tasks: &mut super::Tasks,
+        _flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let addr = net::Addr::parse(&self.types.to_string(args.pop().unwrap()));
+        let (tx, recv_root) = fstream::new_raw();
+        tasks.add(tokio::spawn(async move {
+            net::connect(&addr, tx).await.context(super::ErrConnect)
+        }));
+        Ok(self.types.from_fs(recv_root))
+    }
+}
@@ -0,0 +1,45 @@
+use super::fstream;
+
+use super::fs;
+use super::CommandType;
+use super::Selector;
+use super::Value;
+
+// constant returns a command that takes no arguments and returns
+// a selector that always returns the given value. It's registered
+// as both true and false.
+pub fn new_command(types: &fs::Types, value: bool) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        value,
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![],
+            var_args: None,
+            ret: types.selector(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    value: bool,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        _tasks: &mut super::Tasks,
+        _flags: super::Flags,
+        _args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let value = self.value;
+        Ok(self
+            .types
+            .from_selector(Selector::new(move |_entry, _path, _depth| Ok(value))))
+    }
+}
@@ -0,0 +1,191 @@
+use super::fstream;
+use snafu::{ResultExt, Snafu};
+
+use super::fs;
+use super::CommandType;
+use super::Value;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    ErrFstream { source: fstream::Error },
+}
+
+// entries converts a stream to a flat stream of its entries, each
+// with its path and depth, in the order that they're received. The
+// data in files is skipped. It's used implicitly wherever an fs is
+// given in place of entries, so commands that don't care about the
+// structure of the tree can take entries instead.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![types.fs()],
+            var_args: None,
+            ret: types.entries(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        tasks: &mut super::Tasks,
+        _flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let root = self.types.to_fs(args.pop().unwrap());
+        let (send, recv) = fstream::new_entries();
+        tasks.add(tokio::spawn(async {
+            entries(root, send).await.context(super::ErrEntries)
+        }));
+        Ok(self.types.from_entries(recv))
+    }
+}
+
+// entries sends all the entries in root on send, along
+// with any errors reported in the stream.
+pub async fn entries(root: fstream::RecvRoot, send: fstream::SendEntries) -> Result<()> {
+    let (path, dir) = root.dir().await.context(ErrFstream)?;
+    let mut path = path;
+    let mut dir = dir;
+    loop {
+        let depth = dir.depth();
+        match dir.entry().await.context(ErrFstream)? {
+            fstream::RecvEntry::File(entry, action) => {
+                let msg = fstream::EntryMsg {
+                    path: path.join(entry.file_name()),
+                    entry,
+                    depth,
+                };
+                send.send(Ok(msg))
+                    .await
+                    .map_err(fstream::Error::from)
+                    .context(ErrFstream)?;
+                dir = action.next().await.context(ErrFstream)?;
+            }
+            fstream::RecvEntry::Dir(entry, action) => {
+                path.push(entry.file_name());
+                let msg = fstream::EntryMsg {
+                    path: path.clone(),
+                    entry,
+                    depth,
+                };
+                send.send(Ok(msg))
+                    .await
+                    .map_err(fstream::Error::from)
+                    .context(ErrFstream)?;
+                dir = action.down().await.context(ErrFstream)?;
+            }
+            fstream::RecvEntry::Error(err, dir1) => {
+                send.send(Err(err))
+                    .await
+                    .map_err(fstream::Error::from)
+                    .context(ErrFstream)?;
+                dir = dir1;
+            }
+            fstream::RecvEntry::End(Some(dir1)) => {
+                path.pop();
+                dir = dir1;
+            }
+            fstream::RecvEntry::End(None) => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // send sends a root directory t holding a file a, and a directory
+    // d holding an error, a file b and a directory e holding a file c.
+    // It expects entries to read only the directories.
+    async fn send(root: fstream::SendRoot) -> fstream::Result<()> {
+        let file = |name| fstream::DirEntry::new(name, fstream::Kind::File);
+        let dir = |name| fstream::DirEntry::new(name, fstream::Kind::Dir);
+        let next = |action| match action {
+            fstream::SendFileEntryAction::Next(dir) => dir,
+            _ => panic!("file not skipped"),
+        };
+        let down = |action| match action {
+            fstream::SendDirEntryAction::Down(dir) => dir,
+            _ => panic!("directory not read"),
+        };
+        let t = root
+            .dir(PathBuf::from("t"), fstream::Order::Sorted)
+            .await?
+            .unwrap();
+        let t = next(t.file(file("a")).await?);
+        let d = down(t.dir(dir("d")).await?);
+        let err = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let d = d.error(fstream::ErrorEntry::new("t/d/q", &err)).await?;
+        let d = next(d.file(file("b")).await?);
+        let e = down(d.dir(dir("e")).await?);
+        let e = next(e.file(file("c")).await?);
+        let d = e.end().await?.unwrap();
+        let t = d.end().await?.unwrap();
+        assert!(t.end().await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn entries_flat() {
+        let (send_root, recv_root) = fstream::new();
+        let (send_entries, mut recv_entries) = fstream::new_entries();
+        let sender = tokio::spawn(send(send_root));
+        let entries = tokio::spawn(entries(recv_root, send_entries));
+        let mut got = vec![];
+        while let Some(item) = recv_entries.recv().await {
+            got.push(match item {
+                Ok(msg) => format!(
+                    "{} {} {}",
+                    msg.entry.kind.letter(),
+                    msg.path.display(),
+                    msg.depth
+                ),
+                Err(err) => format!("error {} {:?}", err.path.display(), err.kind),
+            });
+        }
+        entries.await.unwrap().unwrap();
+        sender.await.unwrap().unwrap();
+        assert_eq!(
+            got,
+            [
+                "f t/a 1",
+                "d t/d 1",
+                "error t/d/q PermissionDenied",
+                "f t/d/b 2",
+                "d t/d/e 2",
+                "f t/d/e/c 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn entries_converted_implicitly() {
+        // print takes entries, so entries is inserted
+        // to convert the fs from walk.
+        let engine = crate::Engine::new();
+        let void = engine.registry().std().void.get_type().clone();
+        let pipeline = engine.compile("walk t | print", void.clone()).unwrap();
+        assert_eq!(pipeline.to_string(), "print {entries {walk t}}");
+        // The fs is converted to void by printing its entries.
+        let pipeline = engine.compile("walk t", void).unwrap();
+        assert_eq!(pipeline.to_string(), "print {entries {walk t}}");
+    }
+}
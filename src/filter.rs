@@ -1,131 +1,259 @@
 use super::fstream;
-use snafu::{ResultExt, Snafu};
+use snafu::{IntoError, ResultExt, Snafu};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
+use super::fs;
+use super::walk::ErrorPolicy;
 use super::CommandType;
+use super::FlagType;
+use super::Scanner;
+use super::Selection;
+use super::Selector;
 use super::Value;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub fn new_command() -> impl super::Command {
-    Command(CommandType {
-        flags: vec![],
-        args: vec![super::Type::Fs, super::Type::Selector],
-        var_args: None,
-        ret: super::Type::Fs,
-    })
+// filter keeps only the entries in its input stream that are chosen by
+// its selector argument. Directories that aren't chosen are discarded
+// along with everything underneath them, which are never read; the
+// -p flag asks for this explicitly. With the -d flag, it descends into
+// directories that aren't chosen, so that chosen entries underneath
+// them are still found; such a directory is only sent if something
+// underneath it is chosen. The -e flag
+// specifies what happens when the selector fails for an entry, which
+// isn't then chosen: with -e=abort (the default), the filter fails;
+// with -e=report, the error is sent in the stream and the filter
+// carries on; with -e=ignore, the filter carries on regardless. A
+// selector that decides from the data in a regular file, such as
+// binary, is given the file's data from the stream until it can tell;
+// the data that's read is kept until the file is sent on, in a
+// temporary file if there's much of it.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![
+                FlagType::bool("d"),
+                FlagType::bool("p"),
+                FlagType::value("e", types.string()),
+            ],
+            args: vec![types.fs(), types.selector()],
+            var_args: None,
+            ret: types.fs(),
+        },
+    }
 }
 
-struct Command(CommandType);
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
 
 impl super::Command for Command {
     fn fs_type(&self) -> &super::CommandType {
-        return &self.0;
+        &self.ctype
     }
     fn start(
         &self,
         tasks: &mut super::Tasks,
-        _flags: Vec<String>,
+        flags: super::Flags,
         args: Vec<Value>,
-        _rest: Vec<Value>,
     ) -> fstream::Result<Value> {
         let mut args = args;
-        let selector = args.pop().unwrap().as_selector()?;
-        let recv_root0 = args.pop().unwrap().as_fs()?;
+        let selector = self.types.to_selector(args.pop().unwrap());
+        let recv_root0 = self.types.to_fs(args.pop().unwrap());
+        let mode = match (flags.has("d"), flags.has("p")) {
+            (true, true) => {
+                return Err(fstream::ErrUsage {
+                    msg: "only one of -d and -p may be given".to_string(),
+                }
+                .build())
+            }
+            (true, false) => Mode::Descend,
+            _ => Mode::Prune,
+        };
+        let errors = ErrorPolicy::parse(flags.string("e").as_deref())?;
         let (send_root1, recv_root1) = fstream::new();
         tasks.add(tokio::spawn(async move {
-            let selector = selector;
-            filter(recv_root0, send_root1, |entry, path| selector(entry, path))
+            filter(recv_root0, send_root1, mode, errors, selector)
                 .await
                 .context(super::ErrFilter)
-                .unwrap();
-            Ok(())
         }));
-        Ok(Value::Fs(recv_root1))
+        Ok(self.types.from_fs(recv_root1))
     }
 }
 
 #[derive(Debug, Snafu)]
 pub enum Error {
+    #[snafu(display("{}", source))]
     ErrFstream { source: fstream::Error },
+    #[snafu(display("cannot buffer file data: {}", source))]
+    ErrBuffer { source: std::io::Error },
+    #[snafu(display("{}: {}", path.display(), source))]
+    ErrSelect {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+// Mode specifies what filter does with directories that aren't kept.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    // Descend descends into the directory anyway, and sends it
+    // only if some entry underneath it is kept.
+    Descend,
+    // Prune discards the directory and everything underneath it
+    // without asking the sender for any of it.
+    Prune,
 }
 
 // filter filters by reading from recv_root and sending to send_root,
-// keeping only entries for which keep returns true.
-pub async fn filter<F>(
+// keeping only entries that keep selects. The errors policy specifies
+// what happens when keep fails.
+pub async fn filter(
     recv_root: fstream::RecvRoot,
     send_root: fstream::SendRoot,
-    keep: F,
-) -> Result<()>
-where
-    F: Fn(&fstream::DirEntry, &std::path::PathBuf) -> bool,
-{
+    mode: Mode,
+    errors: ErrorPolicy,
+    keep: Selector,
+) -> Result<()> {
     let (path, recv_dir) = recv_root.dir().await.context(ErrFstream)?;
     let mut path = path;
-    Ok(
-        if let Some(send_dir) = send_root.dir(path.clone()).await.context(ErrFstream)? {
-            filter_dir(&mut path, recv_dir, send_dir, keep).await?
-        },
-    )
+    let order = recv_dir.order();
+    if let Some(send_dir) = send_root
+        .dir(path.clone(), order)
+        .await
+        .context(ErrFstream)?
+    {
+        filter_dir(&mut path, recv_dir, send_dir, mode, errors, &keep).await?
+    }
+    Ok(())
 }
 
-async fn filter_dir<F>(
+async fn filter_dir(
     path: &mut std::path::PathBuf,
     recv_dir: fstream::RecvDir,
     send_dir: fstream::SendDir,
-    keep: F,
-) -> Result<()>
-where
-    F: Fn(&fstream::DirEntry, &std::path::PathBuf) -> bool,
-{
+    mode: Mode,
+    errors: ErrorPolicy,
+    keep: &Selector,
+) -> Result<()> {
     let mut recv_dir = recv_dir;
     let mut send_dir = send_dir;
+    // pending holds the directories that we've descended into but
+    // haven't sent yet because nothing underneath them has been kept.
+    // The receiving side is inside the last of them and the sending
+    // side is inside the parent of the first.
+    let mut pending: Vec<fstream::DirEntry> = vec![];
     loop {
+        let depth = recv_dir.depth();
         let entry = recv_dir.entry().await.context(ErrFstream)?;
         match entry {
             fstream::RecvEntry::File(entry, action) => {
                 path.push(entry.file_name());
-                if !keep(&entry, &path) {
+                let (selection, send_dir1) =
+                    select(keep, &entry, path, depth, send_dir, errors).await?;
+                send_dir = send_dir1;
+                let (kept, candidate) = match selection {
+                    Selection::Known(kept) => (kept, Candidate::Entry(action)),
+                    Selection::Scan(mut scanner) if entry.is_file() => {
+                        // We can't tell without reading the file,
+                        // so read it until the scanner can.
+                        let recv_file = action.down().await.context(ErrFstream)?;
+                        let (kept, buffer, rest) = scan(scanner.as_mut(), recv_file).await?;
+                        (kept, Candidate::Read(buffer, rest))
+                    }
+                    Selection::Scan(mut scanner) => (scanner.finish(), Candidate::Entry(action)),
+                };
+                if !kept {
                     // The file doesn't pass the filter, so discard it.
-                    recv_dir = action.next().await.context(ErrFstream)?;
+                    path.pop();
+                    recv_dir = candidate.next().await?;
                     continue;
                 }
+                match send_pending(&mut pending, send_dir).await? {
+                    Pending::Sent(send_dir1) => send_dir = send_dir1,
+                    Pending::Rejected(send_dir1, levels) => {
+                        // Downstream doesn't want one of the directories
+                        // we're inside, so leave it.
+                        send_dir = send_dir1;
+                        recv_dir = candidate.leave(path, levels).await?;
+                        continue;
+                    }
+                    Pending::End => {
+                        candidate.end().await?;
+                        return Ok(());
+                    }
+                }
                 // Let's see if downstream wants it.
                 match send_dir.file(entry).await.context(ErrFstream)? {
                     fstream::SendFileEntryAction::Down(send_file) => {
                         // Downstream wants it.
-                        let recv_file = action.down().await.context(ErrFstream)?;
+                        path.pop();
                         // TODO use destructuring assignment if it's available.
-                        let (send_dir1, recv_dir1) = transfer_file(send_file, recv_file).await?;
+                        let (send_dir1, recv_dir1) = candidate.send(send_file).await?;
                         send_dir = send_dir1;
                         recv_dir = recv_dir1;
                     }
                     fstream::SendFileEntryAction::Next(next) => {
                         // Downstream doesn't want it.
+                        path.pop();
                         send_dir = next;
-                        recv_dir = action.next().await.context(ErrFstream)?;
+                        recv_dir = candidate.next().await?;
                     }
                     fstream::SendFileEntryAction::Skip(send_parent) => {
                         // Downstream doesn't want it or any of the rest of the directory.
-                        path.pop();
-                        // TODO can this actually return None?
-                        recv_dir = action.skip().await.context(ErrFstream)?.unwrap();
                         send_dir = send_parent;
+                        recv_dir = candidate.leave(path, 1).await?;
                     }
                     fstream::SendFileEntryAction::End => {
-                        path.pop();
-                        // We expect this to return None.
-                        action.skip().await.context(ErrFstream)?;
+                        candidate.end().await?;
                         return Ok(());
                     }
                 }
             }
             fstream::RecvEntry::Dir(entry, action) => {
                 path.push(entry.file_name());
-                if !keep(&entry, &path) {
-                    // The directory doesn't pass the filter, so discard it.
-                    recv_dir = action.next().await.context(ErrFstream)?;
+                let (selection, send_dir1) =
+                    select(keep, &entry, path, depth, send_dir, errors).await?;
+                send_dir = send_dir1;
+                let kept = match selection {
+                    Selection::Known(kept) => kept,
+                    // A directory has no data to scan.
+                    Selection::Scan(mut scanner) => scanner.finish(),
+                };
+                if !kept {
+                    match mode {
+                        Mode::Prune => {
+                            // The directory doesn't pass the filter, so discard it.
+                            path.pop();
+                            recv_dir = action.next().await.context(ErrFstream)?;
+                        }
+                        Mode::Descend => {
+                            // Look inside the directory but don't send
+                            // it until we find something to keep.
+                            pending.push(entry);
+                            recv_dir = action.down().await.context(ErrFstream)?;
+                        }
+                    }
                     continue;
                 }
+                match send_pending(&mut pending, send_dir).await? {
+                    Pending::Sent(send_dir1) => send_dir = send_dir1,
+                    Pending::Rejected(send_dir1, levels) => {
+                        send_dir = send_dir1;
+                        path.pop();
+                        let recv_parent = action.skip().await.context(ErrFstream)?;
+                        recv_dir = skip_levels(path, recv_parent, levels - 1).await?;
+                        continue;
+                    }
+                    Pending::End => {
+                        action.skip().await.context(ErrFstream)?;
+                        return Ok(());
+                    }
+                }
                 // Let's see if downstream wants it.
                 match send_dir.dir(entry).await.context(ErrFstream)? {
                     fstream::SendDirEntryAction::Down(child_dir) => {
@@ -135,18 +263,19 @@ where
                     }
                     fstream::SendDirEntryAction::Next(next) => {
                         // Downstream doesn't want it.
+                        path.pop();
                         send_dir = next;
                         recv_dir = action.next().await.context(ErrFstream)?;
                     }
                     fstream::SendDirEntryAction::Skip(send_parent) => {
                         // Downstream doesn't want it or any of the rest of the directory.
                         path.pop();
+                        path.pop();
                         // TODO can this actually return None?
                         recv_dir = action.skip().await.context(ErrFstream)?.unwrap();
                         send_dir = send_parent;
                     }
                     fstream::SendDirEntryAction::End => {
-                        path.pop();
                         // We expect this to return None.
                         if let Some(_d) = action.skip().await.context(ErrFstream)? {
                             unreachable!("should not have got continuation directory")
@@ -155,10 +284,27 @@ where
                     }
                 }
             }
+            fstream::RecvEntry::Error(err, recv_dir1) => {
+                // Errors are always passed on. The error holds the
+                // whole path, so there's no need to send any pending
+                // directories to put it in the right place.
+                send_dir = send_dir.error(err).await.context(ErrFstream)?;
+                recv_dir = recv_dir1;
+            }
             fstream::RecvEntry::End(opt_recv_dir) => {
+                if pending.pop().is_some() {
+                    // We found nothing to keep in the directory,
+                    // so it was never sent.
+                    path.pop();
+                    // Note: pending directories are never the root,
+                    // so there's always a parent.
+                    recv_dir = opt_recv_dir.unwrap();
+                    continue;
+                }
                 let opt_send_dir = send_dir.end().await.context(ErrFstream)?;
                 match (opt_recv_dir, opt_send_dir) {
                     (Some(recv_dir1), Some(send_dir1)) => {
+                        path.pop();
                         recv_dir = recv_dir1;
                         send_dir = send_dir1;
                     }
@@ -174,6 +320,319 @@ where
     }
 }
 
+// select returns keep's decision about the entry at the given path. If
+// keep fails, the entry isn't selected and the error is dealt with as
+// the errors policy specifies, which may involve sending it to send_dir,
+// so the directory to carry on sending to is returned too.
+async fn select(
+    keep: &Selector,
+    entry: &fstream::DirEntry,
+    path: &std::path::PathBuf,
+    depth: i32,
+    send_dir: fstream::SendDir,
+    errors: ErrorPolicy,
+) -> Result<(Selection, fstream::SendDir)> {
+    match keep.select(entry, path, depth).await {
+        Ok(selection) => Ok((selection, send_dir)),
+        Err(err) => match errors {
+            ErrorPolicy::Abort => Err(ErrSelect { path: path.clone() }.into_error(err)),
+            ErrorPolicy::Report => {
+                let err = fstream::ErrorEntry::new(path.clone(), &err);
+                Ok((
+                    Selection::Known(false),
+                    send_dir.error(err).await.context(ErrFstream)?,
+                ))
+            }
+            ErrorPolicy::Ignore => Ok((Selection::Known(false), send_dir)),
+        },
+    }
+}
+
+// Candidate holds a file that has been selected but not yet sent.
+enum Candidate {
+    // Entry holds the action for a file that hasn't been read.
+    Entry(fstream::RecvFileEntryAction),
+    // Read holds the data that was read from a file to select it
+    // and what's left of the file.
+    Read(Buffer, Rest),
+}
+
+impl Candidate {
+    // next discards the file and returns the directory it's in.
+    async fn next(self) -> Result<fstream::RecvDir> {
+        match self {
+            Candidate::Entry(action) => action.next().await.context(ErrFstream),
+            Candidate::Read(_, rest) => rest.skip().await,
+        }
+    }
+
+    // leave discards the file along with the rest of the given number
+    // of levels of directory above it, as for skip_levels. On entry,
+    // path holds the path of the file.
+    async fn leave(self, path: &mut std::path::PathBuf, levels: usize) -> Result<fstream::RecvDir> {
+        match self {
+            Candidate::Entry(action) => {
+                let parent = action.skip().await.context(ErrFstream)?;
+                path.pop();
+                skip_levels(path, parent, levels - 1).await
+            }
+            Candidate::Read(_, rest) => {
+                let dir = rest.skip().await?;
+                skip_levels(path, Some(dir), levels).await
+            }
+        }
+    }
+
+    // end discards the file when downstream wants nothing more.
+    async fn end(self) -> Result<()> {
+        match self {
+            Candidate::Entry(action) => {
+                // We expect this to return None.
+                action.skip().await.context(ErrFstream)?;
+            }
+            Candidate::Read(_, rest) => {
+                rest.skip().await?;
+            }
+        }
+        Ok(())
+    }
+
+    // send sends the file to send_file, and returns the directories
+    // that they leave off in.
+    async fn send(
+        self,
+        send_file: fstream::SendFile,
+    ) -> Result<(fstream::SendDir, fstream::RecvDir)> {
+        let (buffer, rest) = match self {
+            Candidate::Entry(action) => {
+                let recv_file = action.down().await.context(ErrFstream)?;
+                return transfer_file(send_file, recv_file).await;
+            }
+            Candidate::Read(buffer, rest) => (buffer, rest),
+        };
+        let send_file = match buffer.send(send_file).await? {
+            fstream::SendFileAction::Next(send_file) => send_file,
+            fstream::SendFileAction::Skip(send_dir) => return Ok((send_dir, rest.skip().await?)),
+        };
+        match rest {
+            Rest::File(recv_file) => transfer_file(send_file, recv_file).await,
+            Rest::End(recv_dir) => Ok((send_file.end().await.context(ErrFstream)?, recv_dir)),
+        }
+    }
+}
+
+// Rest holds what's left of a file after scanning it.
+enum Rest {
+    // File holds the file when there's more data to read.
+    File(fstream::RecvFile),
+    // End holds the directory that the file was in
+    // when all its data has been read.
+    End(fstream::RecvDir),
+}
+
+impl Rest {
+    // skip discards the rest of the file and returns
+    // the directory that it was in.
+    async fn skip(self) -> Result<fstream::RecvDir> {
+        match self {
+            Rest::File(file) => file.skip().await.context(ErrFstream),
+            Rest::End(dir) => Ok(dir),
+        }
+    }
+}
+
+// scan writes the data in file to scanner until it decides whether
+// the file is selected, and returns its decision along with the data
+// that was read, if the file is selected, and what's left of the file.
+async fn scan(scanner: &mut dyn Scanner, file: fstream::RecvFile) -> Result<(bool, Buffer, Rest)> {
+    let mut file = file;
+    let mut buffer = Buffer::new();
+    loop {
+        match file.data().await.context(ErrFstream)? {
+            fstream::RecvData::Bytes(data, file1) => {
+                file = file1;
+                let selected = scanner.write(&data);
+                if selected == Some(false) {
+                    return Ok((false, buffer, Rest::File(file)));
+                }
+                buffer.write(data).await?;
+                if selected == Some(true) {
+                    return Ok((true, buffer, Rest::File(file)));
+                }
+            }
+            fstream::RecvData::End(dir) => {
+                return Ok((scanner.finish(), buffer, Rest::End(dir)));
+            }
+        }
+    }
+}
+
+// MAX_BUFFERED holds the number of bytes of data that a Buffer
+// holds in memory before it writes the rest to a temporary file.
+const MAX_BUFFERED: usize = 1024 * 1024;
+
+// BLOCK_SIZE holds the size of the blocks in which
+// data is read back from a temporary file.
+const BLOCK_SIZE: usize = 8192;
+
+// Buffer holds the data that was read from a file to select it,
+// so that it can be sent on if the file is selected.
+struct Buffer {
+    // blocks holds the first MAX_BUFFERED bytes or so.
+    blocks: Vec<Vec<u8>>,
+    // size holds the number of bytes in blocks.
+    size: usize,
+    // spill holds the temporary file that holds the rest.
+    spill: Option<tokio::fs::File>,
+}
+
+impl Buffer {
+    fn new() -> Buffer {
+        Buffer {
+            blocks: vec![],
+            size: 0,
+            spill: None,
+        }
+    }
+
+    // write adds data to the end of the buffer.
+    async fn write(&mut self, data: Vec<u8>) -> Result<()> {
+        if self.spill.is_none() && self.size + data.len() <= MAX_BUFFERED {
+            self.size += data.len();
+            self.blocks.push(data);
+            return Ok(());
+        }
+        if self.spill.is_none() {
+            self.spill = Some(temp_file().await.context(ErrBuffer)?);
+        }
+        let spill = self.spill.as_mut().unwrap();
+        spill.write_all(&data).await.context(ErrBuffer)
+    }
+
+    // send sends all the data in the buffer to send_file,
+    // and returns what send_file asks for next.
+    async fn send(self, send_file: fstream::SendFile) -> Result<fstream::SendFileAction> {
+        let mut send_file = send_file;
+        for data in self.blocks {
+            match send_file.data(data).await.context(ErrFstream)? {
+                fstream::SendFileAction::Next(send_file1) => send_file = send_file1,
+                action => return Ok(action),
+            }
+        }
+        if let Some(mut spill) = self.spill {
+            spill.flush().await.context(ErrBuffer)?;
+            spill
+                .seek(std::io::SeekFrom::Start(0))
+                .await
+                .context(ErrBuffer)?;
+            loop {
+                let mut data = vec![0; BLOCK_SIZE];
+                let n = spill.read(&mut data).await.context(ErrBuffer)?;
+                if n == 0 {
+                    break;
+                }
+                data.truncate(n);
+                match send_file.data(data).await.context(ErrFstream)? {
+                    fstream::SendFileAction::Next(send_file1) => send_file = send_file1,
+                    action => return Ok(action),
+                }
+            }
+        }
+        Ok(fstream::SendFileAction::Next(send_file))
+    }
+}
+
+// temp_file creates a new temporary file and removes it straight
+// away, so that it disappears when it's closed.
+async fn temp_file() -> std::io::Result<tokio::fs::File> {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let path = std::env::temp_dir().join(format!(
+        ".fstream-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await?;
+    tokio::fs::remove_file(&path).await?;
+    Ok(file)
+}
+
+// Pending holds the result of send_pending.
+enum Pending {
+    // Sent holds the directory that the last pending
+    // directory was sent to.
+    Sent(fstream::SendDir),
+    // Rejected indicates that downstream didn't want one of the
+    // pending directories. It holds the directory to carry on
+    // sending to and the number of levels of directory that
+    // the receiving side needs to leave to get there.
+    Rejected(fstream::SendDir, usize),
+    // End indicates that downstream wants nothing more.
+    End,
+}
+
+// send_pending sends all the pending directories, leaving
+// pending empty.
+async fn send_pending(
+    pending: &mut Vec<fstream::DirEntry>,
+    send_dir: fstream::SendDir,
+) -> Result<Pending> {
+    let n = pending.len();
+    let mut send_dir = send_dir;
+    for (i, entry) in pending.drain(..).enumerate() {
+        send_dir = match send_dir.dir(entry).await.context(ErrFstream)? {
+            fstream::SendDirEntryAction::Down(child) => child,
+            fstream::SendDirEntryAction::Next(next) => {
+                return Ok(Pending::Rejected(next, n - i));
+            }
+            fstream::SendDirEntryAction::Skip(parent) => {
+                return Ok(Pending::Rejected(parent, n - i + 1));
+            }
+            fstream::SendDirEntryAction::End => return Ok(Pending::End),
+        };
+    }
+    Ok(Pending::Sent(send_dir))
+}
+
+// skip_levels skips the rest of the given number of levels of
+// directory, starting at dir, and returns the directory that's left.
+// On entry, path holds the path of the last entry received from dir;
+// it's left holding the path of the returned directory.
+async fn skip_levels(
+    path: &mut std::path::PathBuf,
+    dir: Option<fstream::RecvDir>,
+    levels: usize,
+) -> Result<fstream::RecvDir> {
+    let mut dir = dir;
+    path.pop();
+    for _ in 0..levels {
+        // Note: we never leave more levels than the sending side
+        // is below the root, so there's always a directory here.
+        let mut d = dir.unwrap();
+        dir = loop {
+            d = match d.entry().await.context(ErrFstream)? {
+                fstream::RecvEntry::File(_, action) => {
+                    break action.skip().await.context(ErrFstream)?
+                }
+                fstream::RecvEntry::Dir(_, action) => {
+                    break action.skip().await.context(ErrFstream)?
+                }
+                // Errors are skipped along with everything else.
+                fstream::RecvEntry::Error(_, d1) => d1,
+                fstream::RecvEntry::End(parent) => break parent,
+            };
+        };
+        path.pop();
+    }
+    Ok(dir.unwrap())
+}
+
+// transfer_file sends the rest of the data in recv_file to send_file,
+// and returns the directories that they leave off in.
 async fn transfer_file(
     send_file: fstream::SendFile,
     recv_file: fstream::RecvFile,
@@ -201,3 +660,529 @@ async fn transfer_file(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binary;
+    use crate::not;
+    use futures::FutureExt;
+
+    // send sends a root directory holding a regular file for each
+    // of the given names, with the data in the given blocks.
+    async fn send(root: fstream::SendRoot, files: Vec<(&str, Vec<&[u8]>)>) -> fstream::Result<()> {
+        let mut dir = match root.dir(PathBuf::from("t"), fstream::Order::Sorted).await? {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        'files: for (name, blocks) in files {
+            let entry = fstream::DirEntry::new(name, fstream::Kind::File);
+            let mut file = match dir.file(entry).await? {
+                fstream::SendFileEntryAction::Down(file) => file,
+                fstream::SendFileEntryAction::Next(next) => {
+                    dir = next;
+                    continue;
+                }
+                _ => return Ok(()),
+            };
+            for block in blocks {
+                file = match file.data(block.to_vec()).await? {
+                    fstream::SendFileAction::Next(file) => file,
+                    fstream::SendFileAction::Skip(next) => {
+                        dir = next;
+                        continue 'files;
+                    }
+                };
+            }
+            dir = file.end().await?;
+        }
+        dir.end().await?;
+        Ok(())
+    }
+
+    // receive receives the whole of root, which holds only
+    // regular files, and returns the name and data of each.
+    async fn receive(root: fstream::RecvRoot) -> fstream::Result<Vec<(String, Vec<u8>)>> {
+        let mut files = vec![];
+        let (_, mut dir) = root.dir().await?;
+        loop {
+            dir = match dir.entry().await? {
+                fstream::RecvEntry::File(entry, action) => {
+                    let mut data = vec![];
+                    let mut file = action.down().await?;
+                    loop {
+                        file = match file.data().await? {
+                            fstream::RecvData::Bytes(block, file) => {
+                                data.extend(block);
+                                file
+                            }
+                            fstream::RecvData::End(dir) => {
+                                files.push((entry.name.to_string_lossy().into_owned(), data));
+                                break dir;
+                            }
+                        }
+                    }
+                }
+                fstream::RecvEntry::End(_) => return Ok(files),
+                _ => panic!("unexpected entry"),
+            }
+        }
+    }
+
+    async fn filter_files(keep: Selector) -> Vec<(String, Vec<u8>)> {
+        let files: Vec<(&str, Vec<&[u8]>)> = vec![
+            ("a", vec![b"text\n"]),
+            ("b", vec![b"x\0y", b"more"]),
+            ("c", vec![]),
+            ("d", vec![b"text", b"\0"]),
+        ];
+        filter_some(files, keep).await
+    }
+
+    async fn filter_some(
+        files: Vec<(&'static str, Vec<&'static [u8]>)>,
+        keep: Selector,
+    ) -> Vec<(String, Vec<u8>)> {
+        let (send_root0, recv_root0) = fstream::new();
+        let (send_root1, recv_root1) = fstream::new();
+        let sender = tokio::spawn(send(send_root0, files));
+        let receiver = tokio::spawn(receive(recv_root1));
+        filter(
+            recv_root0,
+            send_root1,
+            Mode::Prune,
+            ErrorPolicy::Abort,
+            keep,
+        )
+        .await
+        .unwrap();
+        sender.await.unwrap().unwrap();
+        receiver.await.unwrap().unwrap()
+    }
+
+    fn file(name: &str, data: &[u8]) -> (String, Vec<u8>) {
+        (name.to_string(), data.to_vec())
+    }
+
+    #[tokio::test]
+    async fn filter_binary() {
+        let files = filter_files(binary::binary()).await;
+        assert_eq!(files, vec![file("b", b"x\0ymore")]);
+    }
+
+    #[tokio::test]
+    async fn filter_not_binary() {
+        let files = filter_files(not::not(binary::binary())).await;
+        assert_eq!(
+            files,
+            vec![file("a", b"text\n"), file("c", b""), file("d", b"text\0")]
+        );
+    }
+
+    // Last selects a file if the last byte of its data is 1.
+    struct Last(u8);
+
+    impl Scanner for Last {
+        fn write(&mut self, data: &[u8]) -> Option<bool> {
+            if let Some(&b) = data.last() {
+                self.0 = b;
+            }
+            None
+        }
+
+        fn finish(&mut self) -> bool {
+            self.0 == 1
+        }
+    }
+
+    #[tokio::test]
+    async fn filter_large_file() {
+        // Each file is larger than MAX_BUFFERED, so it has to be
+        // written to a temporary file while it's read.
+        static BLOCK: [u8; 300_000] = [7; 300_000];
+        let files: Vec<(&str, Vec<&[u8]>)> = vec![
+            ("a", vec![&BLOCK; 5]),
+            ("b", vec![&BLOCK, &BLOCK, &BLOCK, &BLOCK, &BLOCK, b"\x01"]),
+            ("c", vec![&BLOCK; 5]),
+        ];
+        let keep = Selector::new_data(|_, _, _| Ok(Selection::Scan(Box::new(Last(0)))));
+        let files = filter_some(files, keep).await;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, "b");
+        let mut want = BLOCK.repeat(5);
+        want.push(1);
+        assert!(files[0].1 == want);
+    }
+
+    // send_tree sends a tree holding the given entries, which are
+    // paths in depth-first order, with a trailing slash on those of
+    // directories. It returns the paths of the directories that
+    // the receiver asked to read.
+    async fn send_tree(root: fstream::SendRoot, paths: &[&str]) -> fstream::Result<Vec<String>> {
+        let mut read = vec![];
+        let mut dir = match root.dir(PathBuf::from("t"), fstream::Order::Sorted).await? {
+            Some(dir) => dir,
+            None => return Ok(read),
+        };
+        // depth holds the number of slashes in the path of an entry
+        // in dir.
+        let mut depth = 0;
+        let depth_of = |path: &str| path.trim_end_matches('/').matches('/').count();
+        let mut i = 0;
+        loop {
+            while i == paths.len() || depth_of(paths[i]) < depth {
+                match dir.end().await? {
+                    Some(parent) => {
+                        dir = parent;
+                        depth -= 1;
+                    }
+                    None => return Ok(read),
+                }
+            }
+            let path = paths[i];
+            let name = path.trim_end_matches('/').rsplit('/').next().unwrap();
+            i += 1;
+            // skip_to holds the depth of the entries that are skipped.
+            let skip_to = if path.ends_with('/') {
+                match dir
+                    .dir(fstream::DirEntry::new(name, fstream::Kind::Dir))
+                    .await?
+                {
+                    fstream::SendDirEntryAction::Down(child) => {
+                        read.push(path.to_string());
+                        dir = child;
+                        depth += 1;
+                        continue;
+                    }
+                    fstream::SendDirEntryAction::Next(next) => {
+                        dir = next;
+                        depth + 1
+                    }
+                    fstream::SendDirEntryAction::Skip(parent) => {
+                        dir = parent;
+                        depth -= 1;
+                        depth + 1
+                    }
+                    fstream::SendDirEntryAction::End => return Ok(read),
+                }
+            } else {
+                match dir
+                    .file(fstream::DirEntry::new(name, fstream::Kind::File))
+                    .await?
+                {
+                    fstream::SendFileEntryAction::Down(file) => {
+                        dir = file.end().await?;
+                        continue;
+                    }
+                    fstream::SendFileEntryAction::Next(next) => {
+                        dir = next;
+                        continue;
+                    }
+                    fstream::SendFileEntryAction::Skip(parent) => {
+                        dir = parent;
+                        depth -= 1;
+                        depth + 1
+                    }
+                    fstream::SendFileEntryAction::End => return Ok(read),
+                }
+            };
+            while i < paths.len() && depth_of(paths[i]) >= skip_to {
+                i += 1;
+            }
+        }
+    }
+
+    // receive_tree receives the whole of root and returns a record of
+    // each entry in it, asking for the next entry instead of reading
+    // the directories in next, and leaving the directory that holds
+    // each of the entries in skip.
+    async fn receive_tree(
+        root: fstream::RecvRoot,
+        next: &[&str],
+        skip: &[&str],
+    ) -> fstream::Result<Vec<String>> {
+        let mut records = vec![];
+        let (mut path, mut dir) = root.dir().await?;
+        loop {
+            dir = match dir.entry().await? {
+                fstream::RecvEntry::Dir(entry, action) => {
+                    path.push(entry.file_name());
+                    let p = path.to_str().unwrap().to_string();
+                    records.push(format!("d {}", p));
+                    if next.contains(&p.as_str()) {
+                        path.pop();
+                        action.next().await?
+                    } else if skip.contains(&p.as_str()) {
+                        path.pop();
+                        path.pop();
+                        match action.skip().await? {
+                            Some(dir) => dir,
+                            None => return Ok(records),
+                        }
+                    } else {
+                        action.down().await?
+                    }
+                }
+                fstream::RecvEntry::File(entry, action) => {
+                    records.push(format!("f {}", path.join(entry.file_name()).display()));
+                    action.next().await?
+                }
+                fstream::RecvEntry::Error(err, dir) => {
+                    records.push(format!("error {}", err));
+                    dir
+                }
+                fstream::RecvEntry::End(dir) => {
+                    records.push("end".to_string());
+                    path.pop();
+                    match dir {
+                        Some(dir) => dir,
+                        None => return Ok(records),
+                    }
+                }
+            }
+        }
+    }
+
+    // filter_tree runs filter from a sender of the given tree to a
+    // receiver that behaves as for receive_tree, and returns the
+    // sender's result and what was received.
+    async fn filter_tree(
+        paths: &'static [&'static str],
+        mode: Mode,
+        keep: Selector,
+        next: &'static [&'static str],
+        skip: &'static [&'static str],
+    ) -> (fstream::Result<Vec<String>>, Vec<String>) {
+        let (send_root0, recv_root0) = fstream::new();
+        let (send_root1, recv_root1) = fstream::new();
+        let sender = tokio::spawn(send_tree(send_root0, paths));
+        let receiver = tokio::spawn(receive_tree(recv_root1, next, skip));
+        filter(recv_root0, send_root1, mode, ErrorPolicy::Abort, keep)
+            .await
+            .unwrap();
+        (sender.await.unwrap(), receiver.await.unwrap().unwrap())
+    }
+
+    // name selects the entries with any of the given names.
+    fn name(names: &'static [&'static str]) -> Selector {
+        Selector::new(move |entry, _, _| Ok(names.iter().any(|name| entry.name == *name)))
+    }
+
+    static TREE: [&str; 9] = [
+        "a", "d/", "d/b", "d/e/", "d/e/c", "d/e/g", "d/f", "x/", "x/y",
+    ];
+
+    #[tokio::test]
+    async fn filter_prune() {
+        let (read, records) = filter_tree(
+            &TREE,
+            Mode::Prune,
+            name(&["a", "d", "b", "x", "y"]),
+            &[],
+            &[],
+        )
+        .await;
+        // The directory e isn't chosen, so it's never read.
+        assert_eq!(read.unwrap(), ["d/", "x/"]);
+        assert_eq!(
+            records,
+            ["f t/a", "d t/d", "f t/d/b", "end", "d t/x", "f t/x/y", "end", "end"]
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_descend() {
+        let (read, records) = filter_tree(&TREE, Mode::Descend, name(&["c"]), &[], &[]).await;
+        assert_eq!(read.unwrap(), ["d/", "d/e/", "x/"]);
+        // The directories d and e are sent only once c is found inside
+        // them, and x isn't sent at all.
+        assert_eq!(
+            records,
+            ["d t/d", "d t/d/e", "f t/d/e/c", "end", "end", "end"]
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_descend_chosen_dir() {
+        // The directory e is chosen, so it's sent
+        // along with its parent, which isn't.
+        let (_, records) =
+            filter_tree(&TREE, Mode::Descend, name(&["e", "g", "y"]), &[], &[]).await;
+        assert_eq!(
+            records,
+            [
+                "d t/d",
+                "d t/d/e",
+                "f t/d/e/g",
+                "end",
+                "end",
+                "d t/x",
+                "f t/x/y",
+                "end",
+                "end"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_descend_pending_next() {
+        // The receiver doesn't want e, which is pending when c is
+        // found, so the rest of e is passed over, but not the rest of d.
+        let (read, records) = filter_tree(
+            &TREE,
+            Mode::Descend,
+            name(&["c", "g", "f"]),
+            &["t/d/e"],
+            &[],
+        )
+        .await;
+        assert_eq!(read.unwrap(), ["d/", "d/e/", "x/"]);
+        assert_eq!(records, ["d t/d", "d t/d/e", "f t/d/f", "end", "end"]);
+    }
+
+    #[tokio::test]
+    async fn filter_descend_pending_skip() {
+        // The receiver doesn't want e or the rest of d,
+        // so both are left, but not the root.
+        let (read, records) = filter_tree(
+            &TREE,
+            Mode::Descend,
+            name(&["c", "f", "y"]),
+            &[],
+            &["t/d/e"],
+        )
+        .await;
+        assert_eq!(read.unwrap(), ["d/", "d/e/", "x/"]);
+        assert_eq!(
+            records,
+            ["d t/d", "d t/d/e", "d t/x", "f t/x/y", "end", "end"]
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_descend_pending_skip_root() {
+        // The receiver leaves the root when the pending directory d
+        // is sent, so filter finishes, and the sender finds that
+        // nothing more is wanted.
+        let (read, records) =
+            filter_tree(&TREE, Mode::Descend, name(&["c", "y"]), &[], &["t/d"]).await;
+        assert!(matches!(read, Err(fstream::Error::ErrChanSend { .. })));
+        assert_eq!(records, ["d t/d"]);
+    }
+
+    #[tokio::test]
+    async fn prune() {
+        let (send_root0, recv_root0) = fstream::new();
+        let (send_root1, recv_root1) = fstream::new();
+        let sender = tokio::spawn(send_tree(send_root0, &TREE));
+        let receiver = tokio::spawn(receive_tree(recv_root1, &[], &[]));
+        crate::prune::prune(
+            recv_root0,
+            send_root1,
+            ErrorPolicy::Abort,
+            name(&["e", "y"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sender.await.unwrap().unwrap(), ["d/", "x/"]);
+        assert_eq!(
+            receiver.await.unwrap().unwrap(),
+            ["f t/a", "d t/d", "f t/d/b", "f t/d/f", "end", "d t/x", "end", "end"]
+        );
+    }
+
+    // slow_failing selects every entry except b after a delay, and
+    // fails for b.
+    fn slow_failing() -> Selector {
+        Selector::new_async(|entry, _, _| {
+            async move {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                if entry.name == "b" {
+                    Err(std::io::Error::other("cannot decide"))
+                } else {
+                    Ok(true)
+                }
+            }
+            .boxed()
+        })
+    }
+
+    #[tokio::test]
+    async fn filter_errors_abort() {
+        let (send_root0, recv_root0) = fstream::new();
+        let (send_root1, recv_root1) = fstream::new();
+        let sender = tokio::spawn(send_tree(send_root0, &TREE));
+        let receiver = tokio::spawn(receive_tree(recv_root1, &[], &[]));
+        let err = filter(
+            recv_root0,
+            send_root1,
+            Mode::Prune,
+            ErrorPolicy::Abort,
+            slow_failing(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "t/d/b: cannot decide");
+        // The sender waits for a reply that never comes,
+        // until it's cancelled as it would be by Tasks::join.
+        sender.abort();
+        assert!(receiver.await.unwrap().is_err());
+    }
+
+    // filter_errors filters TREE with slow_failing
+    // and returns what was received.
+    async fn filter_errors(errors: ErrorPolicy) -> Vec<String> {
+        let (send_root0, recv_root0) = fstream::new();
+        let (send_root1, recv_root1) = fstream::new();
+        let sender = tokio::spawn(send_tree(send_root0, &TREE));
+        let receiver = tokio::spawn(receive_tree(recv_root1, &[], &[]));
+        filter(recv_root0, send_root1, Mode::Prune, errors, slow_failing())
+            .await
+            .unwrap();
+        sender.await.unwrap().unwrap();
+        receiver.await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn filter_errors_report() {
+        assert_eq!(
+            filter_errors(ErrorPolicy::Report).await,
+            [
+                "f t/a",
+                "d t/d",
+                "error t/d/b: cannot decide",
+                "d t/d/e",
+                "f t/d/e/c",
+                "f t/d/e/g",
+                "end",
+                "f t/d/f",
+                "end",
+                "d t/x",
+                "f t/x/y",
+                "end",
+                "end"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_errors_ignore() {
+        assert_eq!(
+            filter_errors(ErrorPolicy::Ignore).await,
+            [
+                "f t/a",
+                "d t/d",
+                "d t/d/e",
+                "f t/d/e/c",
+                "f t/d/e/g",
+                "end",
+                "f t/d/f",
+                "end",
+                "d t/x",
+                "f t/x/y",
+                "end",
+                "end"
+            ]
+        );
+    }
+}
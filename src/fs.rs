@@ -1,52 +1,82 @@
+use super::abc;
+use super::fstream;
+use super::Selector;
 
-module abcstd {
-	struct Types {
-		string_cvt: abc::Converter<String>,
-		string: abc::Type,
-		status_cvt: abc::Converter<Status>,
-		status: abc::Type,
-	}
-}
+// Fs is the type of a stream of file system data.
+pub type Fs = fstream::RecvRoot;
+
+// Entries is the type of a flat stream of entries,
+// without the structure of the tree or any file data.
+pub type Entries = fstream::RecvEntries;
 
-module fs {
-	#[derive(Clone)]
-	struct Types {
-		std: abcstd.Types,
-		fs_cvt: abc::Converter<Fs>,
-		string_cvt: abc::Converter<String>,
-		selector_cvt: abc::Converter<Selector>,
-	}
-
-	impl Types {
-		fn new(registry: &mut abc.Registry, std: &abcstd::Types) -> Result<Types> {
-			let fs_cvt = registry.new_type<Fs>("fs");
-			let selector_cvt = registry.new_type<Selector>("selector");
-			Types{
-				fs_cvt: fs_cvt,
-				fs: fs_cvt.get_type(),
-				string_cvt: string_cvt,
-				string: string_cvt.get_type(),
-				selector_cvt: selector_cvt,
-				selector: selector_cvt.get_type(),
-			}
-		}
-		fn fs(self: &Types) -> abc::Type {
-			return self.fs_cvt.get_type().clone()
-		}
-		fn to_fs(
-		fn string(self: &Types) -> abc::Type {
-			return self.std.string()
-		}
-		fn selector
-	}
-
-	type Fs = recv::Root
+// Types holds the types used by the file system commands.
+// It's passed to each command when it's created so that
+// the command can declare its type and convert its arguments
+// and return value.
+#[derive(Clone)]
+pub struct Types {
+    pub std: abc::StdTypes,
+    pub fs_cvt: abc::Converter<Fs>,
+    pub entries_cvt: abc::Converter<Entries>,
+    pub selector_cvt: abc::Converter<Selector>,
 }
-			let string_cvt = registry.new_type<Void>("void");
-			let void_cvt = registry
 
+impl Types {
+    // new registers the file system types with the registry.
+    pub fn new(registry: &mut abc::Registry) -> abc::Result<Types> {
+        Ok(Types {
+            std: registry.std().clone(),
+            fs_cvt: registry.new_type("fs")?,
+            entries_cvt: registry.new_type("entries")?,
+            selector_cvt: registry.new_type("selector")?,
+        })
+    }
+
+    pub fn void(&self) -> abc::Type {
+        self.std.void.get_type().clone()
+    }
+    pub fn from_void(&self) -> abc::Value {
+        self.std.void.to_value(())
+    }
+
+    pub fn string(&self) -> abc::Type {
+        self.std.string.get_type().clone()
+    }
+    pub fn to_string(&self, v: abc::Value) -> String {
+        self.std.string.from_value(v)
+    }
 
-typeset has:
+    pub fn int(&self) -> abc::Type {
+        self.std.int.get_type().clone()
+    }
 
-	defined type for each
-	list of types
+    pub fn fs(&self) -> abc::Type {
+        self.fs_cvt.get_type().clone()
+    }
+    pub fn to_fs(&self, v: abc::Value) -> Fs {
+        self.fs_cvt.from_value(v)
+    }
+    pub fn from_fs(&self, root: Fs) -> abc::Value {
+        self.fs_cvt.to_value(root)
+    }
+
+    pub fn entries(&self) -> abc::Type {
+        self.entries_cvt.get_type().clone()
+    }
+    pub fn to_entries(&self, v: abc::Value) -> Entries {
+        self.entries_cvt.from_value(v)
+    }
+    pub fn from_entries(&self, entries: Entries) -> abc::Value {
+        self.entries_cvt.to_value(entries)
+    }
+
+    pub fn selector(&self) -> abc::Type {
+        self.selector_cvt.get_type().clone()
+    }
+    pub fn to_selector(&self, v: abc::Value) -> Selector {
+        self.selector_cvt.from_value(v)
+    }
+    pub fn from_selector(&self, selector: Selector) -> abc::Value {
+        self.selector_cvt.to_value(selector)
+    }
+}
@@ -3,6 +3,7 @@ use tokio::sync::mpsc;
 mod common;
 mod recv;
 mod send;
+pub mod wire;
 
 pub use common::*;
 
@@ -21,6 +22,17 @@ pub use recv::File as RecvFile;
 pub use recv::FileEntryAction as RecvFileEntryAction;
 pub use recv::Root as RecvRoot;
 
+// SendEntries and RecvEntries are the sending and receiving halves of
+// a channel that carries a flat stream of entries, as created by
+// new_entries. An error is sent in place of an entry when the source
+// of the entries reported one in its stream.
+pub type SendEntries = mpsc::Sender<std::result::Result<EntryMsg, ErrorEntry>>;
+pub type RecvEntries = mpsc::Receiver<std::result::Result<EntryMsg, ErrorEntry>>;
+
+// ENTRIES_BUFFER holds the number of entries that can be
+// sent on an entries channel before they're received.
+const ENTRIES_BUFFER: usize = 64;
+
 // new creates sending and receiving halves of
 // a channel that can be used to send the contents
 // of a directory.
@@ -28,3 +40,20 @@ pub fn new() -> (send::Root, recv::Root) {
     let (tx, rx) = mpsc::channel(1);
     (send::new_root(tx), recv::new_root(rx))
 }
+
+// new_raw is like new except that it returns the raw channel
+// instead of the sending half. This is for relaying the protocol
+// from elsewhere, such as a network connection; the caller is
+// responsible for sending messages in the correct order.
+pub fn new_raw() -> (mpsc::Sender<FsMsg>, recv::Root) {
+    let (tx, rx) = mpsc::channel(1);
+    (tx, recv::new_root(rx))
+}
+
+// new_entries creates sending and receiving halves of a channel
+// that carries a flat stream of entries. Unlike the channel created
+// by new, there's no reply to each entry, so the sender can run
+// ahead of the receiver.
+pub fn new_entries() -> (SendEntries, RecvEntries) {
+    mpsc::channel(ENTRIES_BUFFER)
+}
@@ -1,4 +1,8 @@
 use snafu::Snafu;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::PathBuf;
+use std::time::SystemTime;
 use tokio::sync::mpsc;
 use tokio::task;
 
@@ -18,7 +22,189 @@ pub enum Action {
     Skip,
 }
 
-pub type DirEntry = std::fs::DirEntry;
+// DirEntry holds information about a file or directory. It doesn't
+// refer to anything on disk, so it can be constructed by any source of
+// file system data (an archive, a manifest or a synthetic tree, for
+// example) and read by a receiver without any further system calls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    // name holds the name of the entry within its directory.
+    pub name: OsString,
+    // kind holds the kind of the entry.
+    pub kind: Kind,
+    // mode holds the permission bits of the entry,
+    // including the setuid, setgid and sticky bits.
+    pub mode: u32,
+    // size holds the size of the entry in bytes.
+    pub size: u64,
+    // mtime holds the last modification time of the entry.
+    pub mtime: SystemTime,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    // dev and ino hold the device and inode numbers of the entry.
+    // They are zero if the source has no such notion.
+    pub dev: u64,
+    pub ino: u64,
+    // link holds the target of a symbolic link.
+    pub link: Option<PathBuf>,
+    // rdev holds the device number of a block or character device.
+    pub rdev: u64,
+}
+
+// Kind represents the kind of a directory entry.
+// Only entries of kind File have any data; all kinds
+// other than Dir are sent as file entries.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+}
+
+impl Kind {
+    // letter returns the single letter used to represent
+    // the kind, as used by find(1)'s -type predicate.
+    pub fn letter(&self) -> char {
+        match self {
+            Kind::File => 'f',
+            Kind::Dir => 'd',
+            Kind::Symlink => 'l',
+            Kind::BlockDevice => 'b',
+            Kind::CharDevice => 'c',
+            Kind::Fifo => 'p',
+            Kind::Socket => 's',
+        }
+    }
+}
+
+impl DirEntry {
+    // new returns an entry with the given name and kind. Its other
+    // fields are set to plausible defaults for an entry of that kind.
+    pub fn new<N: Into<OsString>>(name: N, kind: Kind) -> DirEntry {
+        DirEntry {
+            name: name.into(),
+            kind,
+            mode: match kind {
+                Kind::Dir => 0o755,
+                Kind::Symlink => 0o777,
+                _ => 0o644,
+            },
+            size: 0,
+            mtime: SystemTime::UNIX_EPOCH,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            dev: 0,
+            ino: 0,
+            link: None,
+            rdev: 0,
+        }
+    }
+
+    // from_metadata returns an entry with the given name holding
+    // the information from the given metadata, which should
+    // have been obtained without following symbolic links.
+    // The link target is left as None.
+    pub fn from_metadata<N: Into<OsString>>(name: N, md: &std::fs::Metadata) -> DirEntry {
+        let ftype = md.file_type();
+        let kind = if ftype.is_dir() {
+            Kind::Dir
+        } else if ftype.is_symlink() {
+            Kind::Symlink
+        } else if ftype.is_block_device() {
+            Kind::BlockDevice
+        } else if ftype.is_char_device() {
+            Kind::CharDevice
+        } else if ftype.is_fifo() {
+            Kind::Fifo
+        } else if ftype.is_socket() {
+            Kind::Socket
+        } else {
+            Kind::File
+        };
+        DirEntry {
+            name: name.into(),
+            kind,
+            mode: md.mode() & 0o7777,
+            size: md.size(),
+            mtime: md.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            uid: md.uid(),
+            gid: md.gid(),
+            nlink: md.nlink(),
+            dev: md.dev(),
+            ino: md.ino(),
+            link: None,
+            rdev: md.rdev(),
+        }
+    }
+
+    // file_name returns the name of the entry within its directory.
+    pub fn file_name(&self) -> &OsStr {
+        &self.name
+    }
+
+    // is_dir reports whether the entry represents a directory.
+    pub fn is_dir(&self) -> bool {
+        self.kind == Kind::Dir
+    }
+
+    // is_file reports whether the entry represents a regular file,
+    // the only kind of entry that has any data.
+    pub fn is_file(&self) -> bool {
+        self.kind == Kind::File
+    }
+}
+
+// ErrorEntry describes an error that a sender encountered while
+// reading part of the file system, such as a directory that couldn't
+// be read. It's sent in place of whatever couldn't be read so that
+// the rest of the stream can still be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorEntry {
+    // path holds the path of the file or directory that
+    // couldn't be read, including the root path.
+    pub path: PathBuf,
+    // kind holds the kind of the error.
+    pub kind: std::io::ErrorKind,
+    // message holds a description of the error.
+    pub message: String,
+}
+
+impl ErrorEntry {
+    // new returns an entry describing the given
+    // error from reading path.
+    pub fn new<P: Into<PathBuf>>(path: P, err: &std::io::Error) -> ErrorEntry {
+        ErrorEntry {
+            path: path.into(),
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+// Order describes the order of the entries within
+// each directory of a stream.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Order {
+    // Sorted means that the entries in each directory are
+    // in increasing byte order of their names.
+    #[default]
+    Sorted,
+    // Unsorted means that the entries can be in any order,
+    // such as the order that they were read from disk.
+    Unsorted,
+}
 
 // FsMsg is the value that's sent on an Fs channel.
 // It consists of some information about what's being
@@ -30,23 +216,30 @@ pub struct FsMsg {
     pub reply: mpsc::Sender<Action>,
 }
 
-// EntryMsg is the value that's sent on an Entry channel.
+// EntryMsg is the value that's sent on an entries channel.
+#[derive(Debug)]
 pub struct EntryMsg {
     pub entry: DirEntry,
+    // path holds the path of the entry, including the root path.
     pub path: std::path::PathBuf,
+    // depth holds the depth of the entry.
+    // The entries in the root directory are at depth 1.
     pub depth: i32,
 }
 
 // FsData holds one of the possible items of
 // data that can be sent.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FsData {
     // Root represents the root entry, including
-    // the full path to the root.
-    Root(std::path::PathBuf),
+    // the full path to the root and the order
+    // of the entries in the stream.
+    Root(std::path::PathBuf, Order),
 
-    // FileEntry represents a file. The associated directory entry
-    // returns false from is_dir.
+    // FileEntry represents a file or any other entry that's
+    // not a directory, such as a symbolic link or a device.
+    // The associated directory entry returns false from is_dir.
+    // Only regular files have data.
     FileEntry(DirEntry),
 
     // FileEntry represents a directory. The associated directory entry
@@ -56,6 +249,13 @@ pub enum FsData {
     // Data represents a block of bytes within a file.
     Data(Vec<u8>),
 
+    // Error represents an error reading part of the file system.
+    // It's sent between the entries of a directory. An entry that
+    // couldn't be read isn't sent; an error reading the contents of
+    // a file or directory follows its entry, which is sent with as
+    // much of its contents as could be read.
+    Error(ErrorEntry),
+
     // End represents the end of a file or directory.
     // The next entry will be from the parent directory if there is one.
     End,
@@ -66,13 +266,13 @@ pub enum FsData {
 pub enum Error {
     #[snafu(display("bad usage: {}", msg))]
     ErrUsage { msg: String },
-    #[snafu(display("unexpected directory {}", entry.path().display()))]
-    ErrIsADirectory { entry: DirEntry },
-    #[snafu(display("unexpected non-directory {}", entry.path().display()))]
-    ErrNotADirectory { entry: DirEntry },
+    #[snafu(display("unexpected directory {}", entry.name.to_string_lossy()))]
+    ErrIsADirectory { entry: Box<DirEntry> },
+    #[snafu(display("unexpected non-directory {}", entry.name.to_string_lossy()))]
+    ErrNotADirectory { entry: Box<DirEntry> },
     #[snafu(display("receiving from unexpectedly closed channel"))]
     ErrUnexpectedClosedChannel,
-    #[snafu(display("IO error"))]
+    #[snafu(display("IO error: {}", source))]
     ErrIO { source: std::io::Error },
     #[snafu(display("send on closed channel of type {}", type_name))]
     ErrChanSend { type_name: String },
@@ -6,7 +6,7 @@ type Receiver = mpsc::Receiver<common::FsMsg>;
 
 // TODO how can we make this available only to the fstream module?
 pub fn new_root(c: Receiver) -> Root {
-    Root { c: c }
+    Root { c }
 }
 
 #[derive(Debug)]
@@ -15,17 +15,26 @@ pub struct Root {
 }
 
 impl Root {
+    // into_raw returns the raw channel underlying the root.
+    // This is for relaying the protocol elsewhere, such as to
+    // a network connection; the caller is responsible for
+    // replying to every message received on it.
+    pub fn into_raw(self) -> Receiver {
+        self.c
+    }
+
     // dir returns the top level directory entry and
     // the directory that's underneath it.
     pub async fn dir(mut self) -> common::Result<(std::path::PathBuf, Dir)> {
         let msg = common::recv(&mut self.c).await?;
-        if let common::FsData::Root(path) = msg.data {
+        if let common::FsData::Root(path, order) = msg.data {
             msg.reply.send(common::Action::Down).await?;
             Ok((
                 path,
                 Dir {
                     c: self.c,
                     depth: 1,
+                    order,
                 },
             ))
         } else {
@@ -39,25 +48,38 @@ impl Root {
 pub struct Dir {
     c: Receiver,
     depth: i32,
+    order: common::Order,
 }
 
 impl Dir {
     fn down(self) -> Dir {
         Dir {
-            c: self.c,
             depth: self.depth + 1,
+            ..self
         }
     }
     fn up(self) -> Option<Dir> {
         if self.depth > 1 {
             Some(Dir {
-                c: self.c,
                 depth: self.depth - 1,
+                ..self
             })
         } else {
             None
         }
     }
+    // depth returns the depth of the entries in the directory.
+    // The entries in the root directory are at depth 1.
+    pub fn depth(&self) -> i32 {
+        self.depth
+    }
+
+    // order returns the order of the entries in each
+    // directory, as given by the root of the stream.
+    pub fn order(&self) -> common::Order {
+        self.order
+    }
+
     pub async fn entry(mut self) -> common::Result<Entry> {
         let msg = common::recv(&mut self.c).await?;
         Ok(match msg.data {
@@ -75,8 +97,12 @@ impl Dir {
                     reply: msg.reply,
                 },
             ),
+            common::FsData::Error(err) => {
+                msg.reply.send(common::Action::Next).await?;
+                Entry::Error(err, self)
+            }
             common::FsData::Data(_) => unreachable!("no data allowed at this level"),
-            common::FsData::Root(_) => unreachable!("root not allowed at this level"),
+            common::FsData::Root(..) => unreachable!("root not allowed at this level"),
             common::FsData::End => {
                 msg.reply.send(common::Action::Next).await?; // it doesn't actually matter which action we send.
                 Entry::End(self.up())
@@ -89,6 +115,9 @@ impl Dir {
 pub enum Entry {
     File(common::DirEntry, FileEntryAction),
     Dir(common::DirEntry, DirEntryAction),
+    // Error holds an error from the sender and the directory
+    // to carry on receiving from.
+    Error(common::ErrorEntry, Dir),
     End(Option<Dir>),
 }
 
@@ -124,6 +153,7 @@ impl FileEntryAction {
         self.reply.send(common::Action::Down).await?;
         Ok(File {
             dir: self.dir.down(),
+            reply: None,
         })
     }
     pub async fn next(self) -> common::Result<Dir> {
@@ -139,6 +169,10 @@ impl FileEntryAction {
 #[derive(Debug)]
 pub struct File {
     dir: Dir,
+    // reply holds the reply channel for the most recently
+    // received block of data. The reply is deferred until
+    // we know whether the receiver wants the rest of the file.
+    reply: Option<mpsc::Sender<common::Action>>,
 }
 
 #[derive(Debug)]
@@ -149,18 +183,44 @@ pub enum Data {
 
 impl File {
     pub async fn data(mut self) -> common::Result<Data> {
+        if let Some(reply) = self.reply.take() {
+            reply.send(common::Action::Next).await?;
+        }
         let msg = common::recv(&mut self.dir.c).await?;
         Ok(match msg.data {
-            common::FsData::Data(data) => Data::Bytes(data, self),
-            common::FsData::End =>
-            // Note: the up call can't fail because files are at least two levels deep.
-            {
+            common::FsData::Data(data) => Data::Bytes(
+                data,
+                File {
+                    dir: self.dir,
+                    reply: Some(msg.reply),
+                },
+            ),
+            common::FsData::End => {
+                msg.reply.send(common::Action::Next).await?; // it doesn't actually matter which action we send.
+                // Note: the up call can't fail because files are at least two levels deep.
                 Data::End(self.dir.up().unwrap())
             }
             _ => unreachable!("unexpected message received"),
         })
     }
-    pub async fn skip(self) -> common::Result<Dir> {
+    pub async fn skip(mut self) -> common::Result<Dir> {
+        let reply = match self.reply.take() {
+            Some(reply) => reply,
+            None => {
+                // We haven't received anything from the file yet,
+                // so wait for the first message so we can reply to it.
+                let msg = common::recv(&mut self.dir.c).await?;
+                match msg.data {
+                    common::FsData::Data(_) => msg.reply,
+                    common::FsData::End => {
+                        msg.reply.send(common::Action::Next).await?;
+                        return Ok(self.dir.up().unwrap());
+                    }
+                    _ => unreachable!("unexpected message received"),
+                }
+            }
+        };
+        reply.send(common::Action::Skip).await?;
         // Note: the up call can't fail because files are at least two levels deep.
         Ok(self.dir.up().unwrap())
     }
@@ -1,5 +1,4 @@
 use super::common;
-use snafu::ResultExt;
 use tokio::sync::mpsc;
 
 pub type Sender = mpsc::Sender<common::FsMsg>;
@@ -22,19 +21,25 @@ pub fn new_root(c: Sender) -> Root {
     Root {
         dir: Dir {
             depth_n: 0,
-            c: c,
-            reply_tx: reply_tx,
-            reply_rx: reply_rx,
+            c,
+            reply_tx,
+            reply_rx,
         },
     }
 }
 
 impl Root {
-    pub async fn dir(mut self, path: std::path::PathBuf) -> common::Result<Option<Dir>> {
+    // dir sends the root entry. The order holds the order in which
+    // the entries in each directory will be sent.
+    pub async fn dir(
+        mut self,
+        path: std::path::PathBuf,
+        order: common::Order,
+    ) -> common::Result<Option<Dir>> {
         self.dir
             .c
             .send(common::FsMsg {
-                data: common::FsData::Root(path),
+                data: common::FsData::Root(path, order),
                 reply: self.dir.reply_tx.clone(),
             })
             .await?;
@@ -46,12 +51,12 @@ impl Root {
 }
 
 impl Dir {
-    // file sends a file entry. The name should always compare
-    // greater than the previous entry sent for the directory.
+    // file sends a file entry. If the stream is sorted, the name should
+    // always compare greater than the previous entry sent for the directory.
     // It's an error if entry represents a directory.
     pub async fn file(mut self, entry: common::DirEntry) -> common::Result<FileEntryAction> {
-        if Self::is_dir(&entry)? {
-            return common::ErrNotADirectory { entry }.fail();
+        if entry.is_dir() {
+            return common::ErrIsADirectory { entry }.fail();
         }
         self.c
             .send(common::FsMsg {
@@ -76,15 +81,11 @@ impl Dir {
         self.depth_n
     }
 
-    fn is_dir(entry: &common::DirEntry) -> common::Result<bool> {
-        Ok(entry.metadata().context(common::ErrIO)?.is_dir())
-    }
-
-    // dir sends a directory entry. The name should always compare
-    // greater than the previous entry sent for the directory.
+    // dir sends a directory entry. If the stream is sorted, the name should
+    // always compare greater than the previous entry sent for the directory.
     // It's an error if entry doesn't represent a directory.
     pub async fn dir(mut self, entry: common::DirEntry) -> common::Result<DirEntryAction> {
-        if !Self::is_dir(&entry)? {
+        if !entry.is_dir() {
             return common::ErrNotADirectory { entry }.fail();
         }
         self.c
@@ -106,6 +107,19 @@ impl Dir {
         })
     }
 
+    // error sends an error entry in the directory. The receiver
+    // can't act on it, so the directory is returned as is.
+    pub async fn error(mut self, err: common::ErrorEntry) -> common::Result<Dir> {
+        self.c
+            .send(common::FsMsg {
+                data: common::FsData::Error(err),
+                reply: self.reply_tx.clone(),
+            })
+            .await?;
+        common::recv(&mut self.reply_rx).await?;
+        Ok(self)
+    }
+
     // end indicates the end of the directory. It returns the parent
     // directory or None if the parent is the root.
     pub async fn end(mut self) -> common::Result<Option<Dir>> {
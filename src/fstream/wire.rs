@@ -0,0 +1,572 @@
+// The wire module implements a byte encoding of the data sent on an
+// fstream, which is used both for bundles and for running the
+// protocol between processes.
+//
+// An encoded stream starts with a header consisting of the eight
+// bytes "fstream\n" followed by the format version as a four-byte
+// big-endian integer. The current version is 4.
+//
+// The header is followed by a sequence of records, one for each
+// FsData value. Each record starts with a single tag byte:
+//
+//	'R' Root: the root path as bytes followed by the order of the
+//		entries as one byte: 0 for sorted or 1 for unsorted.
+//		In version 1, there is no order and the entries are sorted.
+//	'D' DirEntry: an entry.
+//	'F' FileEntry: an entry.
+//	'B' Data: the data as bytes.
+//	'E' End: no payload.
+//	'X' Error: the path as bytes, the kind of error as one byte (see
+//		ERROR_KINDS; 0 means any other kind) and the message as bytes.
+//		Version 3 and later.
+//
+// All integers are big-endian. A bytes value is encoded as a four-byte
+// length followed by that many bytes. An entry is encoded as:
+//
+//	name: bytes
+//	kind: one byte: 0 for a file, 1 for a directory, 2 for a symbolic link,
+//		3 for a block device, 4 for a character device, 5 for a FIFO,
+//		6 for a socket. Kinds 3 to 6 are version 4 and later.
+//	mode: 4 bytes
+//	size: 8 bytes
+//	mtime: 8 bytes of signed seconds since the Unix epoch
+//		followed by 4 bytes of nanoseconds
+//	uid: 4 bytes
+//	gid: 4 bytes
+//	nlink: 8 bytes
+//	dev: 8 bytes
+//	ino: 8 bytes
+//	link: one byte, 0 if there is no link target; otherwise 1 followed
+//		by the target as bytes.
+//	rdev: 8 bytes, present only for block and character devices.
+//		Version 4 and later.
+//
+// Paths and names are encoded as their raw bytes.
+//
+// When the protocol is run between processes, the receiver replies
+// to each record with an action encoded as a single byte: 'd' for
+// Down, 'n' for Next or 's' for Skip. Actions have no header.
+
+use super::common::{Action, DirEntry, ErrorEntry, FsData, Kind, Order};
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const MAGIC: &[u8; 8] = b"fstream\n";
+
+// VERSION holds the current version of the encoding.
+pub const VERSION: u32 = 4;
+
+// MAX_BYTES holds the maximum length of a bytes value that will
+// be accepted when reading, to guard against corrupt input.
+const MAX_BYTES: u32 = 64 * 1024 * 1024;
+
+const TAG_ROOT: u8 = b'R';
+const TAG_DIR_ENTRY: u8 = b'D';
+const TAG_FILE_ENTRY: u8 = b'F';
+const TAG_DATA: u8 = b'B';
+const TAG_END: u8 = b'E';
+const TAG_ERROR: u8 = b'X';
+
+// ERROR_KINDS holds the kinds of error that can be encoded
+// in an error record. Each is encoded as its index plus one.
+const ERROR_KINDS: &[io::ErrorKind] = &[
+    io::ErrorKind::NotFound,
+    io::ErrorKind::PermissionDenied,
+    io::ErrorKind::ConnectionRefused,
+    io::ErrorKind::ConnectionReset,
+    io::ErrorKind::ConnectionAborted,
+    io::ErrorKind::NotConnected,
+    io::ErrorKind::AddrInUse,
+    io::ErrorKind::AddrNotAvailable,
+    io::ErrorKind::BrokenPipe,
+    io::ErrorKind::AlreadyExists,
+    io::ErrorKind::WouldBlock,
+    io::ErrorKind::InvalidInput,
+    io::ErrorKind::InvalidData,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::WriteZero,
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::Unsupported,
+    io::ErrorKind::UnexpectedEof,
+    io::ErrorKind::OutOfMemory,
+];
+
+const ACTION_DOWN: u8 = b'd';
+const ACTION_NEXT: u8 = b'n';
+const ACTION_SKIP: u8 = b's';
+
+// write_header writes the stream header.
+pub async fn write_header<W: AsyncWrite + Unpin + ?Sized>(w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC).await?;
+    w.write_u32(VERSION).await
+}
+
+// read_header reads the stream header and returns the
+// format version, which is never greater than VERSION.
+pub async fn read_header<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<u32> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic).await?;
+    if &magic != MAGIC {
+        return Err(invalid("not an fstream encoding".to_string()));
+    }
+    let version = r.read_u32().await?;
+    if version == 0 || version > VERSION {
+        return Err(invalid(format!("unsupported version {}", version)));
+    }
+    Ok(version)
+}
+
+// write_data writes a single record holding data.
+pub async fn write_data<W: AsyncWrite + Unpin + ?Sized>(
+    w: &mut W,
+    data: &FsData,
+) -> io::Result<()> {
+    match data {
+        FsData::Root(path, order) => {
+            w.write_u8(TAG_ROOT).await?;
+            write_bytes(w, path.as_os_str().as_bytes()).await?;
+            w.write_u8(match order {
+                Order::Sorted => 0,
+                Order::Unsorted => 1,
+            })
+            .await
+        }
+        FsData::DirEntry(entry) => {
+            w.write_u8(TAG_DIR_ENTRY).await?;
+            write_entry(w, entry).await
+        }
+        FsData::FileEntry(entry) => {
+            w.write_u8(TAG_FILE_ENTRY).await?;
+            write_entry(w, entry).await
+        }
+        FsData::Data(data) => {
+            w.write_u8(TAG_DATA).await?;
+            write_bytes(w, data).await
+        }
+        FsData::End => w.write_u8(TAG_END).await,
+        FsData::Error(err) => {
+            w.write_u8(TAG_ERROR).await?;
+            write_bytes(w, err.path.as_os_str().as_bytes()).await?;
+            w.write_u8(
+                ERROR_KINDS
+                    .iter()
+                    .position(|&kind| kind == err.kind)
+                    .map_or(0, |i| i as u8 + 1),
+            )
+            .await?;
+            write_bytes(w, err.message.as_bytes()).await
+        }
+    }
+}
+
+// read_data reads a single record encoded with the given
+// version of the format, as returned by read_header.
+pub async fn read_data<R: AsyncRead + Unpin + ?Sized>(
+    r: &mut R,
+    version: u32,
+) -> io::Result<FsData> {
+    let tag = r.read_u8().await?;
+    read_record(r, tag, version).await
+}
+
+// read_data_or_eof is like read_data except that it returns None
+// if the input ends cleanly before the start of a record.
+pub async fn read_data_or_eof<R: AsyncRead + Unpin + ?Sized>(
+    r: &mut R,
+    version: u32,
+) -> io::Result<Option<FsData>> {
+    let mut tag = [0; 1];
+    if r.read(&mut tag).await? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(read_record(r, tag[0], version).await?))
+}
+
+async fn read_record<R: AsyncRead + Unpin + ?Sized>(
+    r: &mut R,
+    tag: u8,
+    version: u32,
+) -> io::Result<FsData> {
+    Ok(match tag {
+        TAG_ROOT => {
+            let path = PathBuf::from(OsString::from_vec(read_bytes(r).await?));
+            let order = if version < 2 {
+                Order::Sorted
+            } else {
+                match r.read_u8().await? {
+                    0 => Order::Sorted,
+                    1 => Order::Unsorted,
+                    b => return Err(invalid(format!("unknown order {:#x}", b))),
+                }
+            };
+            FsData::Root(path, order)
+        }
+        TAG_DIR_ENTRY => FsData::DirEntry(read_entry(r, version).await?),
+        TAG_FILE_ENTRY => FsData::FileEntry(read_entry(r, version).await?),
+        TAG_DATA => FsData::Data(read_bytes(r).await?),
+        TAG_END => FsData::End,
+        TAG_ERROR if version >= 3 => {
+            let path = PathBuf::from(OsString::from_vec(read_bytes(r).await?));
+            let kind = r.read_u8().await? as usize;
+            let message = String::from_utf8_lossy(&read_bytes(r).await?).into_owned();
+            FsData::Error(ErrorEntry {
+                path,
+                kind: ERROR_KINDS
+                    .get(kind.wrapping_sub(1))
+                    .copied()
+                    .unwrap_or(io::ErrorKind::Other),
+                message,
+            })
+        }
+        tag => return Err(invalid(format!("unknown record tag {:#x}", tag))),
+    })
+}
+
+// write_action writes a reply action.
+pub async fn write_action<W: AsyncWrite + Unpin + ?Sized>(
+    w: &mut W,
+    action: Action,
+) -> io::Result<()> {
+    w.write_u8(match action {
+        Action::Down => ACTION_DOWN,
+        Action::Next => ACTION_NEXT,
+        Action::Skip => ACTION_SKIP,
+    })
+    .await
+}
+
+// read_action reads a reply action.
+pub async fn read_action<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Action> {
+    Ok(match r.read_u8().await? {
+        ACTION_DOWN => Action::Down,
+        ACTION_NEXT => Action::Next,
+        ACTION_SKIP => Action::Skip,
+        b => return Err(invalid(format!("unknown action {:#x}", b))),
+    })
+}
+
+async fn write_entry<W: AsyncWrite + Unpin + ?Sized>(
+    w: &mut W,
+    entry: &DirEntry,
+) -> io::Result<()> {
+    write_bytes(w, entry.name.as_bytes()).await?;
+    w.write_u8(match entry.kind {
+        Kind::File => 0,
+        Kind::Dir => 1,
+        Kind::Symlink => 2,
+        Kind::BlockDevice => 3,
+        Kind::CharDevice => 4,
+        Kind::Fifo => 5,
+        Kind::Socket => 6,
+    })
+    .await?;
+    w.write_u32(entry.mode).await?;
+    w.write_u64(entry.size).await?;
+    let (secs, nanos) = match entry.mtime.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(err) => {
+            // The time is before the epoch. Represent it as a negative
+            // number of seconds plus a positive number of nanoseconds.
+            let d = err.duration();
+            if d.subsec_nanos() == 0 {
+                (-(d.as_secs() as i64), 0)
+            } else {
+                (-(d.as_secs() as i64) - 1, 1_000_000_000 - d.subsec_nanos())
+            }
+        }
+    };
+    w.write_i64(secs).await?;
+    w.write_u32(nanos).await?;
+    w.write_u32(entry.uid).await?;
+    w.write_u32(entry.gid).await?;
+    w.write_u64(entry.nlink).await?;
+    w.write_u64(entry.dev).await?;
+    w.write_u64(entry.ino).await?;
+    match &entry.link {
+        Some(link) => {
+            w.write_u8(1).await?;
+            write_bytes(w, link.as_os_str().as_bytes()).await?;
+        }
+        None => w.write_u8(0).await?,
+    }
+    if has_rdev(entry.kind) {
+        w.write_u64(entry.rdev).await?;
+    }
+    Ok(())
+}
+
+fn has_rdev(kind: Kind) -> bool {
+    kind == Kind::BlockDevice || kind == Kind::CharDevice
+}
+
+async fn read_entry<R: AsyncRead + Unpin + ?Sized>(
+    r: &mut R,
+    version: u32,
+) -> io::Result<DirEntry> {
+    let name = OsString::from_vec(read_bytes(r).await?);
+    let kind = match r.read_u8().await? {
+        0 => Kind::File,
+        1 => Kind::Dir,
+        2 => Kind::Symlink,
+        3 if version >= 4 => Kind::BlockDevice,
+        4 if version >= 4 => Kind::CharDevice,
+        5 if version >= 4 => Kind::Fifo,
+        6 if version >= 4 => Kind::Socket,
+        k => return Err(invalid(format!("unknown entry kind {}", k))),
+    };
+    let mode = r.read_u32().await?;
+    let size = r.read_u64().await?;
+    let secs = r.read_i64().await?;
+    let nanos = r.read_u32().await?;
+    if nanos >= 1_000_000_000 {
+        return Err(invalid(format!("invalid nanoseconds {}", nanos)));
+    }
+    let mtime = if secs >= 0 {
+        SystemTime::UNIX_EPOCH + Duration::new(secs as u64, nanos)
+    } else {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + Duration::new(0, nanos)
+    };
+    let mut entry = DirEntry {
+        name,
+        kind,
+        mode,
+        size,
+        mtime,
+        uid: r.read_u32().await?,
+        gid: r.read_u32().await?,
+        nlink: r.read_u64().await?,
+        dev: r.read_u64().await?,
+        ino: r.read_u64().await?,
+        link: match r.read_u8().await? {
+            0 => None,
+            1 => Some(PathBuf::from(OsString::from_vec(read_bytes(r).await?))),
+            b => return Err(invalid(format!("invalid link marker {}", b))),
+        },
+        rdev: 0,
+    };
+    if version >= 4 && has_rdev(kind) {
+        entry.rdev = r.read_u64().await?;
+    }
+    Ok(entry)
+}
+
+async fn write_bytes<W: AsyncWrite + Unpin + ?Sized>(w: &mut W, b: &[u8]) -> io::Result<()> {
+    if b.len() > MAX_BYTES as usize {
+        return Err(invalid(format!("value too long ({} bytes)", b.len())));
+    }
+    w.write_u32(b.len() as u32).await?;
+    w.write_all(b).await
+}
+
+async fn read_bytes<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Vec<u8>> {
+    let n = r.read_u32().await?;
+    if n > MAX_BYTES {
+        return Err(invalid(format!("value too long ({} bytes)", n)));
+    }
+    let mut b = vec![0; n as usize];
+    r.read_exact(&mut b).await?;
+    Ok(b)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, kind: Kind) -> DirEntry {
+        DirEntry {
+            mode: 0o644,
+            size: 5,
+            mtime: SystemTime::UNIX_EPOCH + Duration::new(1, 2),
+            uid: 3,
+            gid: 4,
+            dev: 7,
+            ino: 8,
+            ..DirEntry::new(name, kind)
+        }
+    }
+
+    // old_entry returns an entry as encoded before version 4,
+    // with the same fields as entry.
+    fn old_entry(name: &str, kind: u8) -> Vec<u8> {
+        let mut b = bytes(name.as_bytes());
+        b.push(kind);
+        b.extend(&0o644u32.to_be_bytes());
+        b.extend(&5u64.to_be_bytes());
+        b.extend(&1i64.to_be_bytes());
+        b.extend(&2u32.to_be_bytes());
+        b.extend(&3u32.to_be_bytes());
+        b.extend(&4u32.to_be_bytes());
+        b.extend(&1u64.to_be_bytes());
+        b.extend(&7u64.to_be_bytes());
+        b.extend(&8u64.to_be_bytes());
+        b.push(0);
+        b
+    }
+
+    fn bytes(b: &[u8]) -> Vec<u8> {
+        let mut v = (b.len() as u32).to_be_bytes().to_vec();
+        v.extend(b);
+        v
+    }
+
+    fn header(version: u32) -> Vec<u8> {
+        let mut b = MAGIC.to_vec();
+        b.extend(&version.to_be_bytes());
+        b
+    }
+
+    // read_all reads a header and all the records that follow it.
+    async fn read_all(mut r: &[u8]) -> io::Result<Vec<FsData>> {
+        let version = read_header(&mut r).await?;
+        let mut records = Vec::new();
+        while let Some(data) = read_data_or_eof(&mut r, version).await? {
+            records.push(data);
+        }
+        Ok(records)
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let records = vec![
+            FsData::Root(PathBuf::from("/a/b"), Order::Unsorted),
+            FsData::DirEntry(entry("d", Kind::Dir)),
+            FsData::FileEntry(DirEntry {
+                mtime: SystemTime::UNIX_EPOCH - Duration::new(10, 1),
+                ..entry("f", Kind::File)
+            }),
+            FsData::Data(b"hello".to_vec()),
+            FsData::End,
+            FsData::FileEntry(DirEntry {
+                link: Some(PathBuf::from("../f")),
+                ..entry("l", Kind::Symlink)
+            }),
+            FsData::End,
+            FsData::FileEntry(DirEntry {
+                rdev: 0x0501,
+                ..entry("tty", Kind::CharDevice)
+            }),
+            FsData::End,
+            FsData::FileEntry(entry("p", Kind::Fifo)),
+            FsData::End,
+            FsData::Error(ErrorEntry {
+                path: PathBuf::from("/a/b/d/x"),
+                kind: io::ErrorKind::PermissionDenied,
+                message: "permission denied".to_string(),
+            }),
+            FsData::End,
+            FsData::End,
+        ];
+        let mut w = Vec::new();
+        write_header(&mut w).await.unwrap();
+        for data in &records {
+            write_data(&mut w, data).await.unwrap();
+        }
+        let mut r = &w[..];
+        assert_eq!(read_header(&mut r).await.unwrap(), VERSION);
+        assert_eq!(read_all(&w).await.unwrap(), records);
+    }
+
+    #[tokio::test]
+    async fn decode_v1() {
+        let mut b = header(1);
+        b.push(TAG_ROOT);
+        b.extend(bytes(b"/a"));
+        b.push(TAG_FILE_ENTRY);
+        b.extend(old_entry("f", 0));
+        b.push(TAG_DATA);
+        b.extend(bytes(b"hello"));
+        b.push(TAG_END);
+        b.push(TAG_END);
+        assert_eq!(
+            read_all(&b).await.unwrap(),
+            vec![
+                FsData::Root(PathBuf::from("/a"), Order::Sorted),
+                FsData::FileEntry(entry("f", Kind::File)),
+                FsData::Data(b"hello".to_vec()),
+                FsData::End,
+                FsData::End,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn decode_v2() {
+        let mut b = header(2);
+        b.push(TAG_ROOT);
+        b.extend(bytes(b"/a"));
+        b.push(1);
+        b.push(TAG_DIR_ENTRY);
+        b.extend(old_entry("d", 1));
+        b.push(TAG_END);
+        b.push(TAG_END);
+        assert_eq!(
+            read_all(&b).await.unwrap(),
+            vec![
+                FsData::Root(PathBuf::from("/a"), Order::Unsorted),
+                FsData::DirEntry(entry("d", Kind::Dir)),
+                FsData::End,
+                FsData::End,
+            ]
+        );
+
+        // Error records were added in version 3.
+        let mut b = header(2);
+        b.push(TAG_ERROR);
+        let err = read_all(&b).await.unwrap_err();
+        assert_eq!(err.to_string(), "unknown record tag 0x58");
+    }
+
+    #[tokio::test]
+    async fn decode_v3() {
+        let mut b = header(3);
+        b.push(TAG_ROOT);
+        b.extend(bytes(b"/a"));
+        b.push(0);
+        b.push(TAG_FILE_ENTRY);
+        b.extend(old_entry("l", 2));
+        b.push(TAG_END);
+        b.push(TAG_ERROR);
+        b.extend(bytes(b"/a/x"));
+        b.push(2);
+        b.extend(bytes(b"permission denied"));
+        b.push(TAG_END);
+        assert_eq!(
+            read_all(&b).await.unwrap(),
+            vec![
+                FsData::Root(PathBuf::from("/a"), Order::Sorted),
+                FsData::FileEntry(entry("l", Kind::Symlink)),
+                FsData::End,
+                FsData::Error(ErrorEntry {
+                    path: PathBuf::from("/a/x"),
+                    kind: io::ErrorKind::PermissionDenied,
+                    message: "permission denied".to_string(),
+                }),
+                FsData::End,
+            ]
+        );
+
+        // Devices, FIFOs and sockets were added in version 4.
+        let mut b = header(3);
+        b.push(TAG_FILE_ENTRY);
+        b.extend(old_entry("tty", 4));
+        let err = read_all(&b).await.unwrap_err();
+        assert_eq!(err.to_string(), "unknown entry kind 4");
+    }
+
+    #[tokio::test]
+    async fn unsupported_version() {
+        let err = read_all(&header(VERSION + 1)).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("unsupported version {}", VERSION + 1)
+        );
+    }
+}
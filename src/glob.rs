@@ -0,0 +1,197 @@
+use super::fstream;
+use regex::bytes::{Regex, RegexBuilder};
+use std::os::unix::ffi::OsStrExt;
+
+use super::fs;
+use super::CommandType;
+use super::FlagType;
+use super::Selector;
+use super::Value;
+
+// Subject specifies what a glob pattern is matched against.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Subject {
+    // Name matches against the name of the entry.
+    Name,
+    // Path matches against the whole path of the entry,
+    // including the root path.
+    Path,
+}
+
+// glob returns a selector that selects entries whose name (or path)
+// matches a shell glob pattern. In a pattern, * matches any sequence
+// of characters other than /, ? matches any single character other
+// than /, [...] matches any of the characters in the brackets (or any
+// other character when the first is ! or ^) and ** matches any sequence
+// of characters including /; **/ matches zero or more whole directories.
+// A backslash quotes the following character. With the -i flag, the
+// match is case-insensitive. It's registered as both name and path.
+pub fn new_command(types: &fs::Types, subject: Subject) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        subject,
+        ctype: CommandType {
+            flags: vec![FlagType::bool("i")],
+            args: vec![types.string()],
+            var_args: None,
+            ret: types.selector(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    subject: Subject,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        _tasks: &mut super::Tasks,
+        flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let pattern = self.types.to_string(args.pop().unwrap());
+        let re = compile(&pattern, flags.has("i")).map_err(|msg| {
+            fstream::ErrUsage {
+                msg: format!("invalid pattern {:?}: {}", pattern, msg),
+            }
+            .build()
+        })?;
+        Ok(self.types.from_selector(match self.subject {
+            Subject::Name => Selector::new(move |entry, _path, _depth| {
+                Ok(re.is_match(entry.file_name().as_bytes()))
+            }),
+            Subject::Path => Selector::new(move |_entry, path, _depth| {
+                Ok(re.is_match(path.as_os_str().as_bytes()))
+            }),
+        }))
+    }
+}
+
+// compile returns a regular expression that matches
+// the same strings as the given glob pattern.
+pub fn compile(pattern: &str, case_insensitive: bool) -> std::result::Result<Regex, String> {
+    RegexBuilder::new(&to_regex(pattern)?)
+        .case_insensitive(case_insensitive)
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+// to_regex translates a glob pattern to the text of
+// an anchored regular expression.
+pub fn to_regex(pattern: &str) -> std::result::Result<String, String> {
+    let mut re = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => {
+                if chars.peek() == Some(&'*') {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        re.push_str("(?:.*/)?");
+                    } else {
+                        re.push_str(".*");
+                    }
+                } else {
+                    re.push_str("[^/]*");
+                }
+            }
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if let Some(&c) = chars.peek() {
+                    if c == '!' || c == '^' {
+                        chars.next();
+                        re.push('^');
+                    }
+                }
+                // A ] immediately after the opening bracket
+                // is taken literally.
+                let mut first = true;
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated [".to_string()),
+                        Some(']') if !first => break,
+                        Some('-') if !first && chars.peek() != Some(&']') => re.push('-'),
+                        Some(c) => re.push_str(&regex::escape(&c.to_string())),
+                    }
+                    first = false;
+                }
+                re.push(']');
+            }
+            '\\' => match chars.next() {
+                Some(c) => re.push_str(&regex::escape(&c.to_string())),
+                None => return Err("trailing backslash".to_string()),
+            },
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Ok(re)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_regex_translation() {
+        for &(pattern, want) in &[
+            ("", "^$"),
+            ("*.rs", r"^[^/]*\.rs$"),
+            ("a?c", "^a[^/]c$"),
+            ("src/**", "^src/.*$"),
+            ("**/target", "^(?:.*/)?target$"),
+            ("[abc]", "^[abc]$"),
+            ("[!a-z]", "^[^a-z]$"),
+            ("[^.]", r"^[^\.]$"),
+            ("[]x]", r"^[\]x]$"),
+            ("[a-]", r"^[a\-]$"),
+            (r"\*", r"^\*$"),
+            ("a+b(c)", r"^a\+b\(c\)$"),
+        ] {
+            assert_eq!(to_regex(pattern), Ok(want.to_string()), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn to_regex_errors() {
+        for pattern in &["[abc", "[]", "[!", "abc\\"] {
+            assert!(to_regex(pattern).is_err(), "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn compile_matches() {
+        for &(pattern, s, want) in &[
+            ("*.rs", "main.rs", true),
+            ("*.rs", "src/main.rs", false),
+            ("*.rs", "main.rsx", false),
+            ("src/*", "src/main.rs", true),
+            ("src/*", "src/a/main.rs", false),
+            ("src/**", "src/a/main.rs", true),
+            ("**/*.rs", "main.rs", true),
+            ("**/*.rs", "src/a/main.rs", true),
+            ("src/**/*.rs", "src/main.rs", true),
+            ("src/**/*.rs", "srcmain.rs", false),
+            ("?.txt", "a.txt", true),
+            ("?.txt", "/.txt", false),
+            ("[!.]*", ".hidden", false),
+            ("[!.]*", "visible", true),
+            (r"\?", "?", true),
+            (r"\?", "a", false),
+        ] {
+            let re = compile(pattern, false).unwrap();
+            assert_eq!(re.is_match(s.as_bytes()), want, "{} {}", pattern, s);
+        }
+        let re = compile("*.RS", true).unwrap();
+        assert!(re.is_match(b"main.rs"));
+    }
+}
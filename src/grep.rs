@@ -0,0 +1,540 @@
+use super::filter;
+use super::fstream;
+use regex::bytes::{Regex, RegexBuilder};
+use snafu::{ResultExt, Snafu};
+use std::io::Write;
+
+use super::fs;
+use super::walk::ErrorPolicy;
+use super::CommandType;
+use super::FlagType;
+use super::Scanner;
+use super::Selection;
+use super::Selector;
+use super::Value;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// grep keeps only the regular files in its input stream whose contents
+// match a regular expression, using the syntax of the regex crate.
+// Like grep(1), it matches the expression against each line of the
+// file in turn. Directories are sent only if something underneath them
+// is kept. With the -i flag, the match is case-insensitive. With -l, the
+// paths of the matching files are printed on the standard output
+// instead of being sent on, as sum prints its checksums, and with -c,
+// each path is printed with the number of matching lines in the file.
+// In both cases, every regular file is read and the stream that's sent
+// on holds only the errors in the input stream, so grep -l or -c is
+// usually the last command in a pipeline, with its empty result
+// printing nothing.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![
+                FlagType::bool("i"),
+                FlagType::bool("l"),
+                FlagType::bool("c"),
+            ],
+            args: vec![types.fs(), types.string()],
+            var_args: None,
+            ret: types.fs(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        tasks: &mut super::Tasks,
+        flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let pattern = self.types.to_string(args.pop().unwrap());
+        let recv_root0 = self.types.to_fs(args.pop().unwrap());
+        let re = RegexBuilder::new(&pattern)
+            .case_insensitive(flags.has("i"))
+            .build()
+            .map_err(|err| {
+                fstream::ErrUsage {
+                    msg: format!("invalid regular expression {:?}: {}", pattern, err),
+                }
+                .build()
+            })?;
+        let output = match (flags.has("l"), flags.has("c")) {
+            (false, false) => Output::Files,
+            (true, false) => Output::Paths,
+            (false, true) => Output::Counts,
+            (true, true) => {
+                return Err(fstream::ErrUsage {
+                    msg: "only one of -l and -c may be given".to_string(),
+                }
+                .build())
+            }
+        };
+        let (send_root1, recv_root1) = fstream::new();
+        tasks.add(tokio::spawn(async move {
+            grep(recv_root0, send_root1, re, output)
+                .await
+                .context(super::ErrGrep)
+        }));
+        Ok(self.types.from_fs(recv_root1))
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    ErrFstream { source: fstream::Error },
+    #[snafu(display("{}", source))]
+    ErrFilter { source: filter::Error },
+    #[snafu(display("{}", source))]
+    ErrIO { source: std::io::Error },
+}
+
+// Output specifies what grep does with the files that match.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Output {
+    // Files sends the matching files on.
+    Files,
+    // Paths prints the path of each matching file.
+    Paths,
+    // Counts prints the path of each matching file
+    // with the number of lines that match.
+    Counts,
+}
+
+// grep reads from recv_root and sends to send_root, keeping only
+// the files with a line that matches re. When output isn't Files,
+// the matching files are printed to stdout instead.
+pub async fn grep(
+    recv_root: fstream::RecvRoot,
+    send_root: fstream::SendRoot,
+    re: Regex,
+    output: Output,
+) -> Result<()> {
+    if output == Output::Files {
+        return filter::filter(
+            recv_root,
+            send_root,
+            filter::Mode::Descend,
+            ErrorPolicy::Abort,
+            selector(re),
+        )
+        .await
+        .context(ErrFilter);
+    }
+    list(recv_root, send_root, &re, output, &mut std::io::stdout()).await
+}
+
+// selector returns a selector that selects the regular
+// files with a line that matches re.
+pub fn selector(re: Regex) -> Selector {
+    Selector::new_data(move |entry, _path, _depth| {
+        Ok(if entry.is_file() {
+            Selection::Scan(Box::new(Matcher::new(re.clone())))
+        } else {
+            Selection::Known(false)
+        })
+    })
+}
+
+// list reads from recv_root and writes a line to w for each regular
+// file with a line that matches re, as specified by output. The stream
+// sent to send_root holds only the errors in recv_root.
+pub async fn list(
+    recv_root: fstream::RecvRoot,
+    send_root: fstream::SendRoot,
+    re: &Regex,
+    output: Output,
+    w: &mut (dyn Write + Send),
+) -> Result<()> {
+    let (path, recv_dir) = recv_root.dir().await.context(ErrFstream)?;
+    let mut path = path;
+    let mut recv_dir = recv_dir;
+    let mut send_dir = send_root
+        .dir(path.clone(), recv_dir.order())
+        .await
+        .context(ErrFstream)?;
+    loop {
+        match recv_dir.entry().await.context(ErrFstream)? {
+            fstream::RecvEntry::File(entry, action) => {
+                if !entry.is_file() {
+                    // Only regular files have any contents to match.
+                    recv_dir = action.next().await.context(ErrFstream)?;
+                    continue;
+                }
+                path.push(entry.file_name());
+                let file = action.down().await.context(ErrFstream)?;
+                let (count, recv_dir1) = count_file(re, file, output).await?;
+                if count > 0 {
+                    if output == Output::Counts {
+                        writeln!(w, "{}:{}", path.display(), count).context(ErrIO)?;
+                    } else {
+                        writeln!(w, "{}", path.display()).context(ErrIO)?;
+                    }
+                }
+                path.pop();
+                recv_dir = recv_dir1;
+            }
+            fstream::RecvEntry::Dir(entry, action) => {
+                path.push(entry.file_name());
+                recv_dir = action.down().await.context(ErrFstream)?;
+            }
+            fstream::RecvEntry::Error(err, recv_dir1) => {
+                // Errors are passed on, as for filter.
+                if let Some(d) = send_dir {
+                    send_dir = Some(d.error(err).await.context(ErrFstream)?);
+                }
+                recv_dir = recv_dir1;
+            }
+            fstream::RecvEntry::End(Some(recv_dir1)) => {
+                path.pop();
+                recv_dir = recv_dir1;
+            }
+            fstream::RecvEntry::End(None) => {
+                w.flush().context(ErrIO)?;
+                if let Some(d) = send_dir {
+                    d.end().await.context(ErrFstream)?;
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+// count_file returns the number of lines in file that match re
+// and the directory that the file was in. Unless output is Counts,
+// it stops reading at the first match, so the count is at most one.
+async fn count_file(
+    re: &Regex,
+    file: fstream::RecvFile,
+    output: Output,
+) -> Result<(u64, fstream::RecvDir)> {
+    let mut file = file;
+    let mut matcher = Matcher::new(re.clone());
+    loop {
+        match file.data().await.context(ErrFstream)? {
+            fstream::RecvData::Bytes(data, file1) => {
+                matcher.match_lines(&data);
+                if matcher.count > 0 && output != Output::Counts {
+                    return Ok((matcher.count, file1.skip().await.context(ErrFstream)?));
+                }
+                file = file1;
+            }
+            fstream::RecvData::End(dir) => {
+                matcher.match_last_line();
+                return Ok((matcher.count, dir));
+            }
+        }
+    }
+}
+
+// MAX_LINE holds the number of bytes of a line that Matcher holds
+// before it matches what it has so far, so that a file with very long
+// lines doesn't use unbounded memory.
+const MAX_LINE: usize = 64 * 1024;
+
+// OVERLAP holds the number of bytes at the end of a long line that
+// Matcher keeps after matching what it has so far, so that a match
+// of up to this length isn't missed where the line is split.
+const OVERLAP: usize = 4 * 1024;
+
+// Matcher counts the lines that match a regular expression
+// in data that's written to it in blocks.
+struct Matcher {
+    re: Regex,
+    // line holds the start of a line that's continued in the next
+    // block. When the line is longer than MAX_LINE, it holds only
+    // the end of what's been written so far.
+    line: Vec<u8>,
+    // start holds the offset in line at which to start matching. It's
+    // 1 when the start of line has been discarded, so that the byte
+    // before what's matched is still there for context, as for \b.
+    start: usize,
+    // matched holds whether the line has already matched,
+    // in which case the rest of it is ignored.
+    matched: bool,
+    // count holds the number of lines that have matched.
+    count: u64,
+}
+
+impl Matcher {
+    fn new(re: Regex) -> Matcher {
+        Matcher {
+            re,
+            line: vec![],
+            start: 0,
+            matched: false,
+            count: 0,
+        }
+    }
+
+    // match_lines matches all the complete lines in data, taking into
+    // account any partial line left over from the previous block.
+    fn match_lines(&mut self, data: &[u8]) {
+        let mut lines = data.split(|&b| b == b'\n');
+        // There's always at least one line, which is the
+        // start of the next line if data ends with a newline.
+        let last = lines.next_back().unwrap();
+        for line in lines {
+            if self.line.is_empty() && self.start == 0 && !self.matched {
+                // There's nothing left over, so there's no
+                // need to copy the line.
+                self.match_line(line);
+            } else {
+                self.extend(line);
+                self.end_line();
+            }
+        }
+        self.extend(last);
+    }
+
+    // match_last_line matches the last line if it
+    // wasn't terminated with a newline.
+    fn match_last_line(&mut self) {
+        if !self.line.is_empty() || self.matched {
+            self.end_line();
+        }
+    }
+
+    // extend adds part of a line to the line that's left over. If that
+    // makes the line too long, it's matched as far as it goes and only
+    // its end is kept.
+    fn extend(&mut self, part: &[u8]) {
+        if self.matched {
+            return;
+        }
+        self.line.extend(part);
+        if self.line.len() <= MAX_LINE {
+            return;
+        }
+        // A match that reaches the end of what we have might
+        // depend on what comes next, as for $, so it doesn't count.
+        if let Some(m) = self.re.find_at(&self.line, self.start) {
+            if m.end() < self.line.len() {
+                self.matched = true;
+                self.line.clear();
+                return;
+            }
+        }
+        self.line.drain(..self.line.len() - OVERLAP - 1);
+        self.start = 1;
+    }
+
+    // end_line matches the line that's left over and
+    // starts a new one.
+    fn end_line(&mut self) {
+        if self.matched || self.re.is_match_at(&self.line, self.start) {
+            self.count += 1;
+        }
+        self.line.clear();
+        self.start = 0;
+        self.matched = false;
+    }
+
+    fn match_line(&mut self, line: &[u8]) {
+        if self.re.is_match(line) {
+            self.count += 1;
+        }
+    }
+}
+
+impl Scanner for Matcher {
+    fn write(&mut self, data: &[u8]) -> Option<bool> {
+        self.match_lines(data);
+        if self.count > 0 {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    fn finish(&mut self) -> bool {
+        self.match_last_line();
+        self.count > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // count returns the number of lines that match re in the given
+    // blocks of data, checking that the matcher never holds much
+    // more than MAX_LINE bytes of a line.
+    fn count(re: &str, blocks: &[&[u8]]) -> u64 {
+        let mut matcher = Matcher::new(Regex::new(re).unwrap());
+        for block in blocks {
+            matcher.match_lines(block);
+            assert!(matcher.line.len() <= MAX_LINE + block.len());
+        }
+        matcher.match_last_line();
+        matcher.count
+    }
+
+    #[test]
+    fn match_lines() {
+        assert_eq!(count("hello", &[b"hello\nworld\nhello again"]), 2);
+        assert_eq!(count("^world$", &[b"hello\nworld\nhello again\n"]), 1);
+        assert_eq!(count("o", &[b"hello\nworld\n", b"", b"no\n"]), 3);
+        assert_eq!(count("^$", &[b"\n\nx\n"]), 2);
+    }
+
+    #[test]
+    fn match_split_across_blocks() {
+        let data: &[u8] = b"one hello\ntwo\nhello three\nfour";
+        for i in 0..=data.len() {
+            for j in i..=data.len() {
+                let blocks = [&data[..i], &data[i..j], &data[j..]];
+                assert_eq!(count("hello", &blocks), 2, "{:?}", blocks);
+                assert_eq!(count("^two$", &blocks), 1, "{:?}", blocks);
+                assert_eq!(count(r"\bfour\b", &blocks), 1, "{:?}", blocks);
+            }
+        }
+    }
+
+    #[test]
+    fn match_long_line() {
+        let mut line = vec![b'x'; 5 * MAX_LINE];
+        let blocks = |line: &[u8]| -> u64 {
+            let blocks: Vec<&[u8]> = line.chunks(8192).collect();
+            count(r"\bneedle\b", &blocks)
+        };
+        assert_eq!(blocks(&line), 0);
+        // A match that's split between blocks, and so between the
+        // parts of the line that are matched, is still found.
+        let boundaries = (1..line.len() / 8192).map(|i| i * 8192 - 3);
+        for i in boundaries.chain(vec![0, line.len() - 6]) {
+            let mut line = line.clone();
+            line[i..i + 6].copy_from_slice(b"needle");
+            assert_eq!(blocks(&line), 0, "{}", i);
+            if i > 0 {
+                line[i - 1] = b' ';
+            }
+            if i + 6 < line.len() {
+                line[i + 6] = b' ';
+            }
+            assert_eq!(blocks(&line), 1, "{}", i);
+        }
+        // Anchors only match at the real ends of the line.
+        line.extend(b"y\nz");
+        let blocks: Vec<&[u8]> = line.chunks(8192).collect();
+        assert_eq!(count("x$", &blocks), 0);
+        assert_eq!(count("^x", &blocks), 1);
+        assert_eq!(count("^y", &blocks), 0);
+        assert_eq!(count("^z$", &blocks), 1);
+        assert_eq!(count("xy$", &blocks), 1);
+    }
+
+    // send sends a root directory t holding a file a, a directory d
+    // holding files b and c and an error, and a symbolic link e.
+    async fn send(root: fstream::SendRoot) -> fstream::Result<()> {
+        let dir = root
+            .dir(std::path::PathBuf::from("t"), fstream::Order::Sorted)
+            .await?
+            .unwrap();
+        let dir = send_file(dir, "a", b"hello\n").await?;
+        let dir = match dir
+            .dir(fstream::DirEntry::new("d", fstream::Kind::Dir))
+            .await?
+        {
+            fstream::SendDirEntryAction::Down(dir) => dir,
+            _ => panic!("directory not wanted"),
+        };
+        let dir = send_file(dir, "b", b"say hello\nhello\n").await?;
+        let dir = send_file(dir, "c", b"nope").await?;
+        let err = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let dir = dir.error(fstream::ErrorEntry::new("t/d/x", &err)).await?;
+        let dir = dir.end().await?.unwrap();
+        let link = fstream::DirEntry::new("e", fstream::Kind::Symlink);
+        let dir = match dir.file(link).await? {
+            fstream::SendFileEntryAction::Next(dir) => dir,
+            _ => panic!("symbolic link wanted"),
+        };
+        assert!(dir.end().await?.is_none());
+        Ok(())
+    }
+
+    async fn send_file(
+        dir: fstream::SendDir,
+        name: &str,
+        data: &[u8],
+    ) -> fstream::Result<fstream::SendDir> {
+        let entry = fstream::DirEntry::new(name, fstream::Kind::File);
+        match dir.file(entry).await? {
+            fstream::SendFileEntryAction::Down(file) => match file.data(data.to_vec()).await? {
+                fstream::SendFileAction::Next(file) => file.end().await,
+                fstream::SendFileAction::Skip(dir) => Ok(dir),
+            },
+            fstream::SendFileEntryAction::Next(dir) => Ok(dir),
+            _ => panic!("file not wanted"),
+        }
+    }
+
+    // receive receives the whole of root, which holds only errors,
+    // and returns their paths.
+    async fn receive(root: fstream::RecvRoot) -> fstream::Result<Vec<std::path::PathBuf>> {
+        let (_, mut dir) = root.dir().await?;
+        let mut errors = vec![];
+        loop {
+            dir = match dir.entry().await? {
+                fstream::RecvEntry::Error(err, dir) => {
+                    errors.push(err.path);
+                    dir
+                }
+                fstream::RecvEntry::End(None) => return Ok(errors),
+                _ => panic!("unexpected entry"),
+            }
+        }
+    }
+
+    async fn list_output(re: &str, output: Output) -> String {
+        let (send_root0, recv_root0) = fstream::new();
+        let (send_root1, recv_root1) = fstream::new();
+        let sender = tokio::spawn(send(send_root0));
+        let receiver = tokio::spawn(receive(recv_root1));
+        let mut w = vec![];
+        list(
+            recv_root0,
+            send_root1,
+            &Regex::new(re).unwrap(),
+            output,
+            &mut w,
+        )
+        .await
+        .unwrap();
+        sender.await.unwrap().unwrap();
+        let errors = receiver.await.unwrap().unwrap();
+        assert_eq!(errors, vec![std::path::PathBuf::from("t/d/x")]);
+        String::from_utf8(w).unwrap()
+    }
+
+    #[tokio::test]
+    async fn list_paths() {
+        assert_eq!(list_output("hello", Output::Paths).await, "t/a\nt/d/b\n");
+        assert_eq!(list_output("^no", Output::Paths).await, "t/d/c\n");
+        assert_eq!(list_output("xyz", Output::Paths).await, "");
+    }
+
+    #[tokio::test]
+    async fn list_counts() {
+        assert_eq!(
+            list_output("hello", Output::Counts).await,
+            "t/a:1\nt/d/b:2\n"
+        );
+        assert_eq!(
+            list_output("^hello$", Output::Counts).await,
+            "t/a:1\nt/d/b:1\n"
+        );
+    }
+}
@@ -0,0 +1,995 @@
+#![recursion_limit = "500"]
+
+use futures::FutureExt;
+use snafu::{IntoError, Snafu};
+use std::collections::HashMap as Map;
+use tokio::task;
+
+pub mod abc;
+pub mod and;
+pub mod binary;
+pub mod bundle;
+pub mod check;
+pub mod compare;
+pub mod connect;
+pub mod constant;
+pub mod entries;
+pub mod filter;
+pub mod fs;
+pub mod fstream;
+pub mod glob;
+pub mod grep;
+pub mod mode;
+pub mod net;
+pub mod newer;
+pub mod not;
+pub mod or;
+pub mod parse;
+pub mod print;
+pub mod prune;
+pub mod re;
+pub mod serve;
+pub mod sum;
+pub mod unbundle;
+pub mod walk;
+pub mod write;
+
+pub use abc::{Registry, Type, Value};
+
+// register registers the file system types and all the
+// standard commands with the registry, and returns the
+// types so that further commands can be registered that
+// use them.
+pub fn register(registry: &mut Registry) -> abc::Result<fs::Types> {
+    let types = fs::Types::new(registry)?;
+    let list: Vec<(&str, Box<dyn Command>)> = vec![
+        ("print", Box::new(print::new_command(&types))),
+        ("entries", Box::new(entries::new_command(&types))),
+        ("walk", Box::new(walk::new_command(&types))),
+        ("filter", Box::new(filter::new_command(&types))),
+        ("prune", Box::new(prune::new_command(&types))),
+        ("grep", Box::new(grep::new_command(&types))),
+        ("mode", Box::new(mode::new_command(&types))),
+        ("or", Box::new(or::new_command(&types))),
+        ("and", Box::new(and::new_command(&types))),
+        ("not", Box::new(not::new_command(&types))),
+        ("true", Box::new(constant::new_command(&types, true))),
+        ("false", Box::new(constant::new_command(&types, false))),
+        ("name", Box::new(glob::new_command(&types, glob::Subject::Name))),
+        ("path", Box::new(glob::new_command(&types, glob::Subject::Path))),
+        ("re", Box::new(re::new_command(&types))),
+        ("size", Box::new(compare::new_command(&types, compare::Attr::Size))),
+        ("mtime", Box::new(compare::new_command(&types, compare::Attr::Mtime))),
+        ("depth", Box::new(compare::new_command(&types, compare::Attr::Depth))),
+        ("uid", Box::new(compare::new_command(&types, compare::Attr::Uid))),
+        ("gid", Box::new(compare::new_command(&types, compare::Attr::Gid))),
+        ("nlink", Box::new(compare::new_command(&types, compare::Attr::Nlink))),
+        ("newer", Box::new(newer::new_command(&types))),
+        ("binary", Box::new(binary::new_command(&types))),
+        ("bundle", Box::new(bundle::new_command(&types))),
+        ("unbundle", Box::new(unbundle::new_command(&types))),
+        ("serve", Box::new(serve::new_command(&types))),
+        ("connect", Box::new(connect::new_command(&types))),
+        ("write", Box::new(write::new_command(&types))),
+        ("sum", Box::new(sum::new_command(&types))),
+        ("check", Box::new(check::new_command(&types))),
+    ];
+    for (name, cmd) in list {
+        registry.add_command(name, cmd)?;
+    }
+    registry.add_conversion(&types.fs(), "walk")?;
+    registry.add_conversion(&types.entries(), "entries")?;
+    registry.add_conversion(&types.void(), "print")?;
+    Ok(types)
+}
+
+// Engine compiles and runs pipeline expressions
+// against the commands in a registry.
+pub struct Engine {
+    registry: Registry,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::new()
+    }
+}
+
+impl Engine {
+    // new returns an engine that knows about all the
+    // standard types and commands.
+    pub fn new() -> Engine {
+        let mut registry = Registry::new();
+        register(&mut registry).expect("standard commands registered twice");
+        Engine::with_registry(registry)
+    }
+
+    // with_registry returns an engine that uses the types
+    // and commands in the given registry.
+    pub fn with_registry(registry: Registry) -> Engine {
+        Engine { registry }
+    }
+
+    // registry returns the registry used by the engine.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    // compile parses and type checks the given expression,
+    // arranging for its result to be converted to the type ret.
+    pub fn compile(&self, expr: &str, ret: Type) -> Result<Pipeline> {
+        let node = parse::parse(expr)?;
+        let node = depipe(node, &self.registry);
+        let node = typecheck(node, &self.registry)?;
+        let node = convert(&self.registry, node, &ret)?;
+        Ok(Pipeline { node, ret })
+    }
+
+    // start starts the pipeline running, adding any tasks it creates to
+    // tasks, and returns the pipeline's result. The value will
+    // have the type that the pipeline was compiled with. The caller
+    // is responsible for consuming the value and calling tasks.join
+    // to wait for the pipeline to complete.
+    pub fn start(&self, pipeline: Pipeline, tasks: &mut Tasks) -> Result<Value> {
+        start(pipeline.node, &self.registry, tasks)
+    }
+
+    // run compiles and runs the given expression, printing
+    // its result if necessary, and waits for it to complete.
+    pub async fn run(&self, expr: &str) -> Result<()> {
+        let void = self.registry.std().void.clone();
+        let pipeline = self.compile(expr, void.get_type().clone())?;
+        let mut tasks = Tasks::new();
+        let value = self.start(pipeline, &mut tasks)?;
+        if void.try_from_value(value).is_err() {
+            unreachable!("unexpected value type at top level");
+        }
+        tasks.join().await
+    }
+}
+
+// Pipeline holds a compiled pipeline expression.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    node: parse::ASTNode,
+    ret: Type,
+}
+
+impl Pipeline {
+    // ret_type returns the type of the value that the
+    // pipeline will produce when started.
+    pub fn ret_type(&self) -> &Type {
+        &self.ret
+    }
+}
+
+impl std::fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.node)
+    }
+}
+
+fn start(node: parse::ASTNode, registry: &Registry, tasks: &mut Tasks) -> Result<Value> {
+    Ok(match node {
+        parse::ASTNode::Word(s) => registry.std().string.to_value(s),
+        parse::ASTNode::Pipe(_, _) => {
+            unreachable!("pipes should have been eliminated");
+        }
+        parse::ASTNode::Command(c) => {
+            let cmd = get_command(registry, &c.name)?;
+            // TODO sanity check that the command is actually returning the type
+            // that's expected of it.
+            let flags = check_flags(&c, cmd.fs_type(), registry)?;
+            let args = c
+                .args
+                .into_iter()
+                .map(|arg| start(arg, registry, tasks))
+                .collect::<Result<_>>()?;
+            tasks.command = (c.name.clone(), c.pos);
+            cmd.start(tasks, flags, args)?
+        }
+    })
+}
+
+fn get_command<'a>(registry: &'a Registry, name: &str) -> Result<&'a dyn Command> {
+    if let Some(c) = registry.command(name) {
+        Ok(c)
+    } else {
+        Err(ErrCommandNotFound {
+            name: name.to_string(),
+        }
+        .build())
+    }
+}
+
+// convert converts node so that it returns a value of type to,
+// wrapping it in conversion commands if needed.
+fn convert(registry: &Registry, node: parse::ASTNode, to: &Type) -> Result<parse::ASTNode> {
+    let ntype = match &node {
+        parse::ASTNode::Command(c) => get_command(registry, &c.name)?.fs_type().ret.clone(),
+        parse::ASTNode::Word(_) => registry.std().string.get_type().clone(),
+        _ => {
+            unreachable!("pipes should have been converted to commands by this stage");
+        }
+    };
+    let node_descr = format!("{}", &node);
+    if let Some(node) = convert1(registry, node, &ntype, to, &mut vec![]) {
+        Ok(node)
+    } else {
+        Err(ErrConvert {
+            node: node_descr,
+            from: ntype,
+            to: to.clone(),
+        }
+        .build())
+    }
+}
+
+// convert1 is the recursive part of convert. The seen slice holds
+// the types we're already trying to convert to, which stops
+// us going round in circles.
+fn convert1(
+    registry: &Registry,
+    node: parse::ASTNode,
+    ntype: &Type,
+    to: &Type,
+    seen: &mut Vec<Type>,
+) -> Option<parse::ASTNode> {
+    if ntype == to {
+        return Some(node);
+    }
+    if seen.contains(to) {
+        return None;
+    }
+    let name = registry.conversion(to)?;
+    let from = match registry.command(name)?.fs_type().args.as_slice() {
+        [from] => from.clone(),
+        _ => return None,
+    };
+    seen.push(to.clone());
+    let arg = convert1(registry, node, ntype, &from, seen);
+    seen.pop();
+    Some(parse::ASTNode::Command(parse::Command {
+        name: name.to_string(),
+        pos: 0,
+        flags: vec![],
+        args: vec![arg?],
+    }))
+}
+
+// typecheck checks the types of all commands and arguments and inserts
+// conversion commands when necessary.
+fn typecheck(node: parse::ASTNode, registry: &Registry) -> Result<parse::ASTNode> {
+    match node {
+        parse::ASTNode::Command(c) => {
+            let ctype = get_command(registry, &c.name)?.fs_type();
+            if c.args.len() < ctype.args.len() {
+                return Err(ErrTooFewArgs {
+                    name: c.name.to_string(),
+                }
+                .build());
+            }
+            if ctype.var_args.is_none() && c.args.len() > ctype.args.len() {
+                return Err(ErrTooManyArgs {
+                    name: c.name.to_string(),
+                }
+                .build());
+            }
+            check_flags(&c, ctype, registry)?;
+            let arg_types = if let Some(t) = &ctype.var_args {
+                itertools::Either::Left(itertools::chain(
+                    ctype.args.iter().cloned(),
+                    std::iter::repeat(t.clone()),
+                ))
+            } else {
+                itertools::Either::Right(ctype.args.iter().cloned())
+            };
+            Ok(parse::ASTNode::Command(parse::Command {
+                name: c.name,
+                pos: c.pos,
+                flags: c.flags,
+                args: c
+                    .args
+                    .into_iter()
+                    .zip(arg_types)
+                    .map(|(arg, arg_type)| convert(registry, typecheck(arg, registry)?, &arg_type))
+                    .collect::<Result<_>>()?,
+            }))
+        }
+        parse::ASTNode::Word(_) => Ok(node),
+        parse::ASTNode::Pipe(_, _) => {
+            unreachable!("pipes should have been converted to commands by this stage");
+        }
+    }
+}
+
+// check_flags checks the flags given to the command c against
+// the flags declared in its type and returns their values.
+fn check_flags(c: &parse::Command, ctype: &CommandType, registry: &Registry) -> Result<Flags> {
+    let mut flags = Flags::new();
+    for flag in &c.flags {
+        let ftype = match ctype.flags.iter().find(|ftype| ftype.name == flag.name) {
+            Some(ftype) => ftype,
+            None => {
+                return Err(ErrUnknownFlag {
+                    name: c.name.to_string(),
+                    flag: flag.name.to_string(),
+                }
+                .build())
+            }
+        };
+        if flags.has(&flag.name) {
+            return Err(ErrBadFlag {
+                name: c.name.to_string(),
+                flag: flag.name.to_string(),
+                msg: "flag specified more than once",
+            }
+            .build());
+        }
+        let value = match (&ftype.value, &flag.value) {
+            (None, None) => None,
+            (None, Some(_)) => {
+                return Err(ErrBadFlag {
+                    name: c.name.to_string(),
+                    flag: flag.name.to_string(),
+                    msg: "flag does not take a value",
+                }
+                .build())
+            }
+            (Some(_), None) => {
+                return Err(ErrBadFlag {
+                    name: c.name.to_string(),
+                    flag: flag.name.to_string(),
+                    msg: "flag requires a value",
+                }
+                .build())
+            }
+            (Some(t), Some(v)) => match registry.parse(t, v) {
+                Some(Ok(value)) => Some(value),
+                Some(Err(msg)) => {
+                    return Err(ErrBadFlag {
+                        name: c.name.to_string(),
+                        flag: flag.name.to_string(),
+                        msg,
+                    }
+                    .build())
+                }
+                None => {
+                    return Err(ErrBadFlag {
+                        name: c.name.to_string(),
+                        flag: flag.name.to_string(),
+                        msg: format!("values of type {} cannot be parsed", t),
+                    }
+                    .build())
+                }
+            },
+        };
+        flags.values.insert(flag.name.to_string(), value);
+    }
+    for ftype in &ctype.flags {
+        if ftype.required && !flags.has(&ftype.name) {
+            return Err(ErrBadFlag {
+                name: c.name.to_string(),
+                flag: ftype.name.to_string(),
+                msg: "flag is required",
+            }
+            .build());
+        }
+    }
+    Ok(flags)
+}
+
+fn depipe(node: parse::ASTNode, registry: &Registry) -> parse::ASTNode {
+    match node {
+        parse::ASTNode::Word(_) => node,
+        parse::ASTNode::Command(c) => parse::ASTNode::Command(depipe_command(c, None, registry)),
+        parse::ASTNode::Pipe(left, right) => {
+            // The left hand of the pipe gets inserted as the first
+            // argument to the right hand side.
+            let left = depipe(*left, registry);
+            parse::ASTNode::Command(depipe_command(right, Some(left), registry))
+        }
+    }
+}
+
+// depipe_command depipes the arguments of c, inserting piped as its first
+// argument if there is one. Any flags to c that look like they're
+// really the first of its missing arguments are made into arguments
+// (see parse::Command::flags_to_args).
+fn depipe_command(
+    c: parse::Command,
+    piped: Option<parse::ASTNode>,
+    registry: &Registry,
+) -> parse::Command {
+    let mut c = parse::Command {
+        args: c
+            .args
+            .into_iter()
+            .map(|arg| depipe(arg, registry))
+            .collect(),
+        ..c
+    };
+    if let Some(cmd) = registry.command(&c.name) {
+        let ctype = cmd.fs_type();
+        let defined: Vec<&str> = ctype.flags.iter().map(|f| f.name.as_str()).collect();
+        let want = ctype.args.len().saturating_sub(piped.is_some() as usize);
+        c.flags_to_args(&defined, want);
+    }
+    if let Some(piped) = piped {
+        c.args.insert(0, piped);
+    }
+    c
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Tasks holds the set of tasks started by a running pipeline.
+pub struct Tasks {
+    tasks: Vec<(String, usize, task::JoinHandle<Result<()>>)>,
+    // command holds the name and position of the command
+    // that's being started, which owns any tasks added.
+    command: (String, usize),
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Tasks::new()
+    }
+}
+
+impl Tasks {
+    pub fn new() -> Tasks {
+        Tasks {
+            tasks: vec![],
+            command: (String::new(), 0),
+        }
+    }
+
+    // add adds a task to the list of tasks to wait for.
+    pub fn add(&mut self, t: task::JoinHandle<Result<()>>) {
+        let (name, pos) = self.command.clone();
+        self.tasks.push((name, pos, t));
+    }
+
+    // join waits for all the tasks to complete. As soon as one fails,
+    // the others are cancelled. The error holds the failures of all the
+    // tasks that failed before they could be cancelled, except that
+    // failures caused only by a neighbouring task going away are left
+    // out when there are others to report.
+    pub async fn join(self) -> Result<()> {
+        let mut tasks = self.tasks;
+        let mut errors = vec![];
+        while !tasks.is_empty() {
+            let (result, i, _) =
+                futures::future::select_all(tasks.iter_mut().map(|(_, _, t)| t)).await;
+            let (name, pos, _) = tasks.swap_remove(i);
+            let error = match result {
+                Ok(Ok(())) => continue,
+                Ok(Err(err)) => err,
+                Err(err) if err.is_cancelled() => continue,
+                Err(err) => ErrTaskJoin.into_error(err),
+            };
+            if errors.is_empty() {
+                for (_, _, t) in &tasks {
+                    t.abort();
+                }
+            }
+            errors.push(TaskError { name, pos, error });
+        }
+        if errors.iter().any(|err| !err.error.is_disconnected()) {
+            errors.retain(|err| !err.error.is_disconnected());
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        errors.sort_by_key(|err| err.pos);
+        Err(ErrTasks { errors }.build())
+    }
+}
+
+// TaskError holds the failure of a task started by a command.
+#[derive(Debug)]
+pub struct TaskError {
+    // name holds the name of the command.
+    pub name: String,
+    // pos holds the position of the command in the
+    // pipeline expression, as in parse::Command.
+    pub pos: usize,
+    pub error: Error,
+}
+
+impl std::fmt::Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.pos == 0 {
+            write!(f, "{}: {}", self.name, self.error)
+        } else {
+            write!(f, "{} (command {}): {}", self.name, self.pos, self.error)
+        }
+    }
+}
+
+impl Error {
+    // is_disconnected reports whether the error was caused only by the
+    // other end of a stream going away, as happens to the neighbours of
+    // a task that fails.
+    pub fn is_disconnected(&self) -> bool {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(self);
+        while let Some(err) = source {
+            if let Some(err) = err.downcast_ref::<fstream::Error>() {
+                return matches!(
+                    err,
+                    fstream::Error::ErrUnexpectedClosedChannel | fstream::Error::ErrChanSend { .. }
+                );
+            }
+            source = err.source();
+        }
+        false
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("task failed: {}", source))]
+    ErrTaskJoin { source: task::JoinError },
+    #[snafu(display("{}", source))]
+    ErrFstream { source: fstream::Error },
+    // The errors from the tasks started by commands don't mention
+    // the command because TaskError does that.
+    #[snafu(display("{}", source))]
+    ErrPrint { source: print::Error },
+    #[snafu(display("{}", source))]
+    ErrEntries { source: entries::Error },
+    #[snafu(display("{}", source))]
+    ErrWalk { source: walk::Error },
+    #[snafu(display("{}", source))]
+    ErrFilter { source: filter::Error },
+    #[snafu(display("{}", source))]
+    ErrPrune { source: filter::Error },
+    #[snafu(display("{}", source))]
+    ErrGrep { source: grep::Error },
+    #[snafu(display("{}", source))]
+    ErrBundle { source: bundle::Error },
+    #[snafu(display("{}", source))]
+    ErrUnbundle { source: unbundle::Error },
+    #[snafu(display("{}", source))]
+    ErrServe { source: net::Error },
+    #[snafu(display("{}", source))]
+    ErrConnect { source: net::Error },
+    #[snafu(display("{}", source))]
+    ErrWrite { source: write::Error },
+    #[snafu(display("{}", source))]
+    ErrSum { source: sum::Error },
+    #[snafu(display("{}", source))]
+    ErrCheck { source: check::Error },
+    #[snafu(display("{}", itertools::join(errors, "\n")))]
+    ErrTasks { errors: Vec<TaskError> },
+    #[snafu(display("syntax error: {}", source))]
+    ErrParse { source: parse::Error },
+    #[snafu(display("command {} not found", name))]
+    ErrCommandNotFound { name: String },
+    #[snafu(display("cannot convert {} from {} to {}", node, from, to))]
+    ErrConvert { node: String, from: Type, to: Type },
+    #[snafu(display("too few arguments to {}", name))]
+    ErrTooFewArgs { name: String },
+    #[snafu(display("too many arguments to {}", name))]
+    ErrTooManyArgs { name: String },
+    #[snafu(display("unknown flag -{} to {}", flag, name))]
+    ErrUnknownFlag { name: String, flag: String },
+    #[snafu(display("bad flag -{} to {}: {}", flag, name, msg))]
+    ErrBadFlag { name: String, flag: String, msg: String },
+    #[snafu(display("{}", source))]
+    ErrRegistry { source: abc::Error },
+}
+
+impl From<task::JoinError> for Error {
+    fn from(err: task::JoinError) -> Self {
+        ErrTaskJoin.into_error(err)
+    }
+}
+
+impl From<fstream::Error> for Error {
+    fn from(err: fstream::Error) -> Self {
+        ErrFstream.into_error(err)
+    }
+}
+
+impl From<abc::Error> for Error {
+    fn from(err: abc::Error) -> Self {
+        ErrRegistry.into_error(err)
+    }
+}
+
+impl From<parse::Error> for Error {
+    fn from(err: parse::Error) -> Self {
+        ErrParse.into_error(err)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CommandType {
+    pub flags: Vec<FlagType>,
+    pub args: Vec<Type>,
+    pub var_args: Option<Type>,
+    pub ret: Type,
+}
+
+// FlagType describes a flag that a command accepts.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FlagType {
+    // name holds the name of the flag without its leading hyphen.
+    pub name: String,
+    // value holds the type of the flag's value, or None
+    // if the flag doesn't take a value. The type must have
+    // a parser registered for it (see Registry::add_parser).
+    pub value: Option<Type>,
+    // required holds whether the flag must be specified.
+    pub required: bool,
+}
+
+impl FlagType {
+    // bool returns the type of an optional flag that doesn't take a value.
+    pub fn bool(name: &str) -> FlagType {
+        FlagType {
+            name: name.to_string(),
+            value: None,
+            required: false,
+        }
+    }
+
+    // value returns the type of an optional flag that takes a
+    // value of the given type.
+    pub fn value(name: &str, value: Type) -> FlagType {
+        FlagType {
+            name: name.to_string(),
+            value: Some(value),
+            required: false,
+        }
+    }
+
+    // required returns the flag type marked as required.
+    pub fn required(self) -> FlagType {
+        FlagType {
+            required: true,
+            ..self
+        }
+    }
+}
+
+// Flags holds the flags passed to a command.
+#[derive(Default)]
+pub struct Flags {
+    values: Map<String, Option<Value>>,
+}
+
+impl Flags {
+    pub fn new() -> Flags {
+        Flags { values: Map::new() }
+    }
+
+    // has reports whether the named flag was specified.
+    pub fn has(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    // string returns the value of the named flag, which
+    // must have been declared with a string value.
+    pub fn string(&self, name: &str) -> Option<String> {
+        self.get::<String>(name).map(String::to_string)
+    }
+
+    // int returns the value of the named flag, which
+    // must have been declared with an int value.
+    pub fn int(&self, name: &str) -> Option<i64> {
+        self.get::<i64>(name).copied()
+    }
+
+    // get returns the value of the named flag, which must
+    // have been declared with a value of type T.
+    pub fn get<T: std::any::Any>(&self, name: &str) -> Option<&T> {
+        match self.values.get(name) {
+            Some(Some(v)) => match v.downcast_ref() {
+                Some(v) => Some(v),
+                None => unreachable!("flag -{} has unexpected type {}", name, v.get_type()),
+            },
+            Some(None) => unreachable!("flag -{} does not have a value", name),
+            None => None,
+        }
+    }
+}
+
+pub trait Command {
+    fn fs_type(&self) -> &CommandType;
+    fn start(&self, tasks: &mut Tasks, flags: Flags, args: Vec<Value>) -> fstream::Result<Value>;
+}
+
+// Selector reports whether an entry should be selected. It's passed the
+// entry, its full path and its depth, where the entries in the root
+// directory are at depth 1. It returns an error if it can't tell,
+// for example because it couldn't read a file that it needed to.
+// A selector that does IO should be asynchronous so that it doesn't
+// hold up the other tasks in the pipeline. A selector that looks at
+// the data in regular files, as sent in the stream, is a data selector
+// (see Selection).
+pub enum Selector {
+    Sync(SyncSelector),
+    Async(AsyncSelector),
+    Data(DataSelector),
+}
+
+// SyncSelector is the function behind a synchronous selector.
+pub type SyncSelector = Box<
+    dyn Fn(&fstream::DirEntry, &std::path::PathBuf, i32) -> std::io::Result<bool> + Send + Sync,
+>;
+
+// AsyncSelector is the function behind an asynchronous selector.
+pub type AsyncSelector = Box<
+    dyn for<'a> Fn(
+            &'a fstream::DirEntry,
+            &'a std::path::PathBuf,
+            i32,
+        ) -> futures::future::BoxFuture<'a, std::io::Result<bool>>
+        + Send
+        + Sync,
+>;
+
+// DataSelector is the function behind a data selector. It's
+// asynchronous so that any selector can be combined with one.
+pub type DataSelector = Box<
+    dyn for<'a> Fn(
+            &'a fstream::DirEntry,
+            &'a std::path::PathBuf,
+            i32,
+        ) -> futures::future::BoxFuture<'a, std::io::Result<Selection>>
+        + Send
+        + Sync,
+>;
+
+// Selection holds a selector's decision about an entry.
+pub enum Selection {
+    // Known holds whether the entry is selected.
+    Known(bool),
+    // Scan holds a scanner that decides from the data in the file.
+    Scan(Box<dyn Scanner>),
+}
+
+// Scanner decides whether a regular file is selected from the data in
+// it. An entry that isn't a regular file has no data, so its scanner
+// is finished straight away.
+pub trait Scanner: Send {
+    // write is passed each block of the file's data in turn and
+    // returns whether the file is selected once that's known,
+    // after which it isn't called again.
+    fn write(&mut self, data: &[u8]) -> Option<bool>;
+    // finish returns whether the file is selected when write
+    // hasn't said by the end of the file.
+    fn finish(&mut self) -> bool;
+}
+
+impl Selector {
+    // new returns a synchronous selector that calls f.
+    pub fn new<F>(f: F) -> Selector
+    where
+        F: Fn(&fstream::DirEntry, &std::path::PathBuf, i32) -> std::io::Result<bool>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Selector::Sync(Box::new(f))
+    }
+
+    // new_async returns an asynchronous selector that calls f.
+    pub fn new_async<F>(f: F) -> Selector
+    where
+        F: for<'a> Fn(
+                &'a fstream::DirEntry,
+                &'a std::path::PathBuf,
+                i32,
+            ) -> futures::future::BoxFuture<'a, std::io::Result<bool>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Selector::Async(Box::new(f))
+    }
+
+    // new_data returns a data selector that calls f.
+    pub fn new_data<F>(f: F) -> Selector
+    where
+        F: Fn(&fstream::DirEntry, &std::path::PathBuf, i32) -> std::io::Result<Selection>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Selector::Data(Box::new(move |entry, path, depth| {
+            futures::future::ready(f(entry, path, depth)).boxed()
+        }))
+    }
+
+    // select returns the selector's decision about the given entry.
+    pub async fn select(
+        &self,
+        entry: &fstream::DirEntry,
+        path: &std::path::PathBuf,
+        depth: i32,
+    ) -> std::io::Result<Selection> {
+        match self {
+            Selector::Sync(f) => f(entry, path, depth).map(Selection::Known),
+            Selector::Async(f) => f(entry, path, depth).await.map(Selection::Known),
+            Selector::Data(f) => f(entry, path, depth).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Behaviour says what the task started by a TestCommand does.
+    #[derive(Clone, Copy)]
+    enum Behaviour {
+        // Hang waits until it's cancelled, holding on to its streams.
+        Hang,
+        // Fail fails straight away.
+        Fail,
+        // Stop succeeds straight away, dropping its streams.
+        Stop,
+        // Receive waits for the root of its input stream.
+        Receive,
+    }
+
+    // TestCommand starts a task that behaves as specified. With
+    // Receive, it takes a stream and returns void; otherwise it
+    // takes a stream and returns another, or takes no arguments
+    // and returns a stream if it's a source.
+    struct TestCommand {
+        types: fs::Types,
+        ctype: CommandType,
+        behaviour: Behaviour,
+    }
+
+    impl TestCommand {
+        fn new(types: &fs::Types, behaviour: Behaviour, source: bool) -> TestCommand {
+            let ret = match behaviour {
+                Behaviour::Receive => types.void(),
+                _ => types.fs(),
+            };
+            TestCommand {
+                types: types.clone(),
+                ctype: CommandType {
+                    flags: vec![],
+                    args: if source { vec![] } else { vec![types.fs()] },
+                    var_args: None,
+                    ret,
+                },
+                behaviour,
+            }
+        }
+    }
+
+    impl Command for TestCommand {
+        fn fs_type(&self) -> &CommandType {
+            &self.ctype
+        }
+        fn start(&self, tasks: &mut Tasks, _: Flags, args: Vec<Value>) -> fstream::Result<Value> {
+            let recv_root0 = args.into_iter().next().map(|arg| self.types.to_fs(arg));
+            let behaviour = self.behaviour;
+            if let Behaviour::Receive = behaviour {
+                tasks.add(tokio::spawn(async move {
+                    recv_root0.unwrap().dir().await?;
+                    Ok(())
+                }));
+                return Ok(self.types.from_void());
+            }
+            let (send_root1, recv_root1) = fstream::new();
+            tasks.add(tokio::spawn(async move {
+                match behaviour {
+                    Behaviour::Hang => {
+                        let _streams = (recv_root0, send_root1);
+                        futures::future::pending().await
+                    }
+                    Behaviour::Fail => Err(fstream::ErrUsage {
+                        msg: "failed".to_string(),
+                    }
+                    .build()
+                    .into()),
+                    _ => Ok(()),
+                }
+            }));
+            Ok(self.types.from_fs(recv_root1))
+        }
+    }
+
+    // test_engine returns an engine that knows about the
+    // standard commands and the test commands.
+    fn test_engine() -> Engine {
+        let mut registry = Registry::new();
+        let types = register(&mut registry).unwrap();
+        let list = vec![
+            ("source", Behaviour::Hang, true),
+            ("hang", Behaviour::Hang, false),
+            ("fail", Behaviour::Fail, false),
+            ("stop", Behaviour::Stop, false),
+            ("sink", Behaviour::Receive, false),
+        ];
+        for (name, behaviour, source) in list {
+            let cmd = TestCommand::new(&types, behaviour, source);
+            registry.add_command(name, Box::new(cmd)).unwrap();
+        }
+        Engine::with_registry(registry)
+    }
+
+    // run runs the given expression, and returns the name and position
+    // of each task that failed along with whether its error was caused
+    // only by a neighbour going away. The running tasks are only
+    // cancelled if another one fails, so a timeout means that
+    // they weren't.
+    async fn run(expr: &str) -> Vec<(String, usize, bool)> {
+        let engine = test_engine();
+        let run = engine.run(expr);
+        let result = tokio::time::timeout(std::time::Duration::from_secs(10), run)
+            .await
+            .expect("pipeline did not finish");
+        match result {
+            Err(Error::ErrTasks { errors }) => errors
+                .into_iter()
+                .map(|err| (err.name, err.pos, err.error.is_disconnected()))
+                .collect(),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    fn failure(name: &str, pos: usize, disconnected: bool) -> (String, usize, bool) {
+        (name.to_string(), pos, disconnected)
+    }
+
+    #[tokio::test]
+    async fn join_reports_every_failure() {
+        // Both fail tasks finish before either's failure is seen,
+        // so both are reported, in the order of the pipeline.
+        let failures = run("source | fail | hang | fail | sink").await;
+        assert_eq!(
+            failures,
+            [failure("fail", 2, false), failure("fail", 4, false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn join_aborts_siblings() {
+        // The source and hang tasks never finish by themselves.
+        let failures = run("source | hang | fail | hang | sink").await;
+        assert_eq!(failures, [failure("fail", 3, false)]);
+    }
+
+    #[tokio::test]
+    async fn join_drops_disconnected_errors() {
+        // The sink fails too, because fail has gone away, but only
+        // the error that caused that is reported.
+        let failures = run("source | fail | sink").await;
+        assert_eq!(failures, [failure("fail", 2, false)]);
+    }
+
+    #[tokio::test]
+    async fn join_reports_disconnected_errors() {
+        // When there's nothing else to report, the error
+        // from the sink losing its input is reported.
+        let failures = run("source | stop | sink").await;
+        assert_eq!(failures, [failure("sink", 3, true)]);
+    }
+
+    #[test]
+    fn task_error_display() {
+        let error = || ErrCommandNotFound { name: "x" }.build();
+        let err = TaskError {
+            name: "walk".to_string(),
+            pos: 0,
+            error: error(),
+        };
+        assert_eq!(err.to_string(), "walk: command x not found");
+        let err = TaskError {
+            name: "filter".to_string(),
+            pos: 7,
+            error: error(),
+        };
+        assert_eq!(err.to_string(), "filter (command 7): command x not found");
+    }
+}
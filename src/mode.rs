@@ -1,40 +1,285 @@
 use super::fstream;
 
+use super::fs;
 use super::CommandType;
+use super::Selector;
 use super::Value;
 
-pub fn new_command() -> impl super::Command {
-    Command(CommandType {
-        flags: vec![],
-        args: vec![super::Type::String],
-        var_args: None,
-        ret: super::Type::Selector,
-    })
+// mode returns a selector that selects entries by their kind and
+// permissions. The spec holds one or more comma-separated clauses,
+// all of which must match. Each clause is one of:
+//
+//	k, +k or -k where k is a kind letter as printed by print: d, f, l,
+//		b, c, p or s. The clause matches entries that are (or with
+//		-, are not) of that kind.
+//	[ugoa]*[+-=][rwxst]* as accepted by chmod(1), possibly with more
+//		than one operator (u+r-x). The + operator matches when all
+//		the given permissions are set, - when none of them are and =
+//		when the permissions for the given classes are exactly those
+//		given. When no class is given, all classes are implied.
+//	An octal mode such as 0755 or 1777, which matches the
+//		permissions exactly. When preceded by + or -, it matches
+//		when all the bits are set or when none of them are.
+//
+// For example, "f,o+w" selects world-writable files and "f,-x" selects
+// files that no one can execute. Note that "+s" selects sockets; setuid
+// and setgid bits are selected with "u+s" and "g+s".
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![types.string()],
+            var_args: None,
+            ret: types.selector(),
+        },
+    }
 }
 
-struct Command(CommandType);
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
 
 impl super::Command for Command {
     fn fs_type(&self) -> &super::CommandType {
-        return &self.0;
+        &self.ctype
     }
     fn start(
         &self,
         _tasks: &mut super::Tasks,
-        _flags: Vec<String>,
+        _flags: super::Flags,
         args: Vec<Value>,
-        _rest: Vec<Value>,
     ) -> fstream::Result<Value> {
         let mut args = args;
-        let spec = args.pop().unwrap().as_string()?;
-        match spec.as_ref() {
-            "d" => Ok(super::Value::Selector(Box::new(|entry, _path| {
-                entry.file_type().expect("file type").is_dir()
-            }))),
-            _ => Err(fstream::ErrUsage {
-                msg: format!("invalid mode {}", spec),
+        let spec = self.types.to_string(args.pop().unwrap());
+        let tests = parse_spec(&spec).map_err(|msg| {
+            fstream::ErrUsage {
+                msg: format!("invalid mode {:?}: {}", spec, msg),
+            }
+            .build()
+        })?;
+        Ok(self
+            .types
+            .from_selector(Selector::new(move |entry, _path, _depth| {
+                Ok(tests.iter().all(|test| test.matches(entry)))
+            })))
+    }
+}
+
+// Test holds a single test made by a mode spec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Test {
+    // Kind matches entries of the given kind, or
+    // entries of any other kind when negated.
+    Kind { kind: fstream::Kind, negate: bool },
+    // Bits matches entries with the permission bits in
+    // care set to exactly the bits in want.
+    Bits { care: u32, want: u32 },
+}
+
+impl Test {
+    // matches reports whether entry passes the test.
+    pub fn matches(&self, entry: &fstream::DirEntry) -> bool {
+        match *self {
+            Test::Kind { kind, negate } => (entry.kind == kind) != negate,
+            Test::Bits { care, want } => entry.mode & care == want,
+        }
+    }
+}
+
+const KINDS: &[fstream::Kind] = &[
+    fstream::Kind::File,
+    fstream::Kind::Dir,
+    fstream::Kind::Symlink,
+    fstream::Kind::BlockDevice,
+    fstream::Kind::CharDevice,
+    fstream::Kind::Fifo,
+    fstream::Kind::Socket,
+];
+
+// parse_spec parses a mode spec as described in the
+// documentation of new_command above.
+pub fn parse_spec(spec: &str) -> std::result::Result<Vec<Test>, String> {
+    let mut tests = vec![];
+    for clause in spec.split(',') {
+        parse_clause(clause, &mut tests)?;
+    }
+    Ok(tests)
+}
+
+fn parse_clause(clause: &str, tests: &mut Vec<Test>) -> std::result::Result<(), String> {
+    let (op, rest) = match clause.chars().next() {
+        Some(c @ '+') | Some(c @ '-') | Some(c @ '=') => (Some(c), &clause[1..]),
+        Some(_) => (None, clause),
+        None => return Err("empty clause".to_string()),
+    };
+    if !rest.is_empty() && rest.chars().all(|c| ('0'..='7').contains(&c)) {
+        let bits = u32::from_str_radix(rest, 8).map_err(|err| err.to_string())?;
+        if bits > 0o7777 {
+            return Err(format!("octal mode {} out of range", rest));
+        }
+        tests.push(bits_test(op.unwrap_or('='), 0o7777, bits));
+        return Ok(());
+    }
+    if op != Some('=') && rest.len() == 1 {
+        let letter = rest.chars().next().unwrap();
+        if let Some(&kind) = KINDS.iter().find(|k| k.letter() == letter) {
+            tests.push(Test::Kind {
+                kind,
+                negate: op == Some('-'),
+            });
+            return Ok(());
+        }
+    }
+    parse_symbolic(clause, tests)
+}
+
+// parse_symbolic parses a chmod-style symbolic clause.
+fn parse_symbolic(clause: &str, tests: &mut Vec<Test>) -> std::result::Result<(), String> {
+    let mut chars = clause.chars().peekable();
+    let mut who = 0;
+    while let Some(&c) = chars.peek() {
+        who |= match c {
+            'u' => 0o4700,
+            'g' => 0o2070,
+            'o' => 0o1007,
+            'a' => 0o7777,
+            _ => break,
+        };
+        chars.next();
+    }
+    if who == 0 {
+        who = 0o7777;
+    }
+    let mut op = match chars.next() {
+        Some(c @ '+') | Some(c @ '-') | Some(c @ '=') => c,
+        Some(c) => return Err(format!("unexpected character {:?}", c)),
+        None => return Err("missing operator".to_string()),
+    };
+    let mut perm = 0;
+    for c in chars {
+        match c {
+            '+' | '-' | '=' => {
+                tests.push(bits_test(op, who, perm));
+                op = c;
+                perm = 0;
+                continue;
             }
-            .build()),
+            _ => (),
+        }
+        perm |= who
+            & match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                's' => 0o6000,
+                't' => 0o1000,
+                _ => return Err(format!("unknown permission {:?}", c)),
+            };
+    }
+    tests.push(bits_test(op, who, perm));
+    Ok(())
+}
+
+// bits_test returns the test for the given operator applied to
+// the permission bits perm, where who holds the bits for
+// the classes of user that the operator applies to.
+fn bits_test(op: char, who: u32, perm: u32) -> Test {
+    match op {
+        '+' => Test::Bits {
+            care: perm,
+            want: perm,
+        },
+        '-' => Test::Bits {
+            care: perm,
+            want: 0,
+        },
+        _ => Test::Bits {
+            care: who,
+            want: perm,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(care: u32, want: u32) -> Test {
+        Test::Bits { care, want }
+    }
+
+    fn kind(kind: fstream::Kind, negate: bool) -> Test {
+        Test::Kind { kind, negate }
+    }
+
+    #[test]
+    fn parse_spec_kinds() {
+        assert_eq!(parse_spec("f"), Ok(vec![kind(fstream::Kind::File, false)]));
+        assert_eq!(parse_spec("+d"), Ok(vec![kind(fstream::Kind::Dir, false)]));
+        assert_eq!(
+            parse_spec("-l"),
+            Ok(vec![kind(fstream::Kind::Symlink, true)])
+        );
+        assert_eq!(
+            parse_spec("+s"),
+            Ok(vec![kind(fstream::Kind::Socket, false)])
+        );
+        assert_eq!(
+            parse_spec("f,o+w"),
+            Ok(vec![kind(fstream::Kind::File, false), bits(0o002, 0o002)])
+        );
+    }
+
+    #[test]
+    fn parse_spec_octal() {
+        assert_eq!(parse_spec("644"), Ok(vec![bits(0o7777, 0o644)]));
+        assert_eq!(parse_spec("=1777"), Ok(vec![bits(0o7777, 0o1777)]));
+        assert_eq!(parse_spec("+0111"), Ok(vec![bits(0o111, 0o111)]));
+        assert_eq!(parse_spec("-0022"), Ok(vec![bits(0o022, 0)]));
+        assert!(parse_spec("17777").is_err());
+    }
+
+    #[test]
+    fn parse_spec_symbolic() {
+        assert_eq!(parse_spec("o+w"), Ok(vec![bits(0o002, 0o002)]));
+        assert_eq!(parse_spec("-x"), Ok(vec![bits(0o111, 0)]));
+        assert_eq!(parse_spec("u=rw"), Ok(vec![bits(0o4700, 0o600)]));
+        assert_eq!(parse_spec("go=r"), Ok(vec![bits(0o3077, 0o044)]));
+        assert_eq!(parse_spec("u+s"), Ok(vec![bits(0o4000, 0o4000)]));
+        assert_eq!(parse_spec("+t"), Ok(vec![bits(0o1000, 0o1000)]));
+        assert_eq!(
+            parse_spec("u+r-x"),
+            Ok(vec![bits(0o400, 0o400), bits(0o100, 0)])
+        );
+    }
+
+    #[test]
+    fn parse_spec_errors() {
+        for spec in &["", "f,", "u", "u+q", "=f", "8", "ux+r"] {
+            assert!(parse_spec(spec).is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn bits_test_ops() {
+        assert_eq!(bits_test('+', 0o7777, 0o640), bits(0o640, 0o640));
+        assert_eq!(bits_test('-', 0o7777, 0o640), bits(0o640, 0));
+        assert_eq!(bits_test('=', 0o4700, 0o600), bits(0o4700, 0o600));
+        // = with no permissions matches when the classes have none.
+        assert_eq!(bits_test('=', 0o1007, 0), bits(0o1007, 0));
+    }
+
+    #[test]
+    fn matches() {
+        let mut entry = fstream::DirEntry::new("x", fstream::Kind::File);
+        entry.mode = 0o4755;
+        let tests = parse_spec("f,u+s,o-w,g=rx").unwrap();
+        assert!(tests.iter().all(|test| test.matches(&entry)));
+        for spec in &["d", "-f", "o+w", "644", "g=r", "+t"] {
+            let tests = parse_spec(spec).unwrap();
+            assert!(!tests.iter().all(|test| test.matches(&entry)), "{}", spec);
         }
     }
 }
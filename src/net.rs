@@ -0,0 +1,391 @@
+// The net module runs the fstream protocol over a stream connection
+// between processes, using the encoding in the fstream::wire module.
+// The sending side writes each message as a record and waits for the
+// receiving side to reply with an action before sending the next one,
+// so a remote receiver can still direct the sender, skipping
+// files and directories that it's not interested in.
+
+use super::fstream;
+use super::fstream::wire;
+use snafu::{ResultExt, Snafu};
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    ErrFstream { source: fstream::Error },
+    #[snafu(display("{}", source))]
+    ErrIO { source: std::io::Error },
+    #[snafu(display("cannot listen on {}: {}", addr, source))]
+    ErrListen {
+        addr: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("cannot connect to {}: {}", addr, source))]
+    ErrConnect {
+        addr: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("invalid stream: {}", msg))]
+    ErrFormat { msg: String },
+}
+
+// Addr holds the address of a stream socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Addr {
+    // Unix holds the path of a Unix-domain socket.
+    Unix(PathBuf),
+    // Tcp holds a TCP host:port address.
+    Tcp(String),
+}
+
+impl Addr {
+    // parse parses an address of the form unix:path or
+    // tcp:host:port. An address without either prefix
+    // is taken to be the path of a Unix-domain socket.
+    pub fn parse(s: &str) -> Addr {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Addr::Unix(PathBuf::from(path))
+        } else if let Some(addr) = s.strip_prefix("tcp:") {
+            Addr::Tcp(addr.to_string())
+        } else {
+            Addr::Unix(PathBuf::from(s))
+        }
+    }
+}
+
+impl std::fmt::Display for Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Addr::Unix(path) => write!(f, "unix:{}", path.display()),
+            Addr::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+// serve listens on addr, accepts a single connection and sends
+// root to it. A Unix-domain socket is removed when done.
+pub async fn serve(addr: &Addr, root: fstream::RecvRoot) -> Result<()> {
+    match addr {
+        Addr::Unix(path) => {
+            let listener = UnixListener::bind(path).context(ErrListen {
+                addr: addr.to_string(),
+            })?;
+            let result = match listener.accept().await.context(ErrIO) {
+                Ok((conn, _)) => send(conn, root).await,
+                Err(err) => Err(err),
+            };
+            let _ = std::fs::remove_file(path);
+            result
+        }
+        Addr::Tcp(host_port) => {
+            let listener = TcpListener::bind(host_port).await.context(ErrListen {
+                addr: addr.to_string(),
+            })?;
+            let (conn, _) = listener.accept().await.context(ErrIO)?;
+            send(conn, root).await
+        }
+    }
+}
+
+// connect connects to addr and sends the stream that's
+// served there to root.
+pub async fn connect(addr: &Addr, root: mpsc::Sender<fstream::FsMsg>) -> Result<()> {
+    match addr {
+        Addr::Unix(path) => {
+            let conn = UnixStream::connect(path).await.context(ErrConnect {
+                addr: addr.to_string(),
+            })?;
+            recv(conn, root).await
+        }
+        Addr::Tcp(host_port) => {
+            let conn = TcpStream::connect(host_port).await.context(ErrConnect {
+                addr: addr.to_string(),
+            })?;
+            recv(conn, root).await
+        }
+    }
+}
+
+// send relays all the messages from root to the connection,
+// relaying the replies back from the connection to the sender.
+pub async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: S,
+    root: fstream::RecvRoot,
+) -> Result<()> {
+    let mut conn = BufStream::new(conn);
+    let mut c = root.into_raw();
+    wire::write_header(&mut conn).await.context(ErrIO)?;
+    while let Some(msg) = c.recv().await {
+        wire::write_data(&mut conn, &msg.data)
+            .await
+            .context(ErrIO)?;
+        conn.flush().await.context(ErrIO)?;
+        let action = wire::read_action(&mut conn).await.context(ErrIO)?;
+        msg.reply
+            .send(action)
+            .await
+            .map_err(fstream::Error::from)
+            .context(ErrFstream)?;
+    }
+    conn.shutdown().await.context(ErrIO)
+}
+
+// recv relays all the messages from the connection to c,
+// relaying the replies back to the connection.
+pub async fn recv<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: S,
+    c: mpsc::Sender<fstream::FsMsg>,
+) -> Result<()> {
+    let mut conn = BufStream::new(conn);
+    let version = wire::read_header(&mut conn).await.context(ErrIO)?;
+    let (reply_tx, mut reply_rx) = mpsc::channel(1);
+    let mut pos = Position::Start;
+    while let Some(data) = wire::read_data_or_eof(&mut conn, version)
+        .await
+        .context(ErrIO)?
+    {
+        let record = pos.check(&data)?;
+        c.send(fstream::FsMsg {
+            data,
+            reply: reply_tx.clone(),
+        })
+        .await
+        .map_err(fstream::Error::from)
+        .context(ErrFstream)?;
+        let action = fstream::recv(&mut reply_rx).await.context(ErrFstream)?;
+        pos = pos.next(record, action);
+        wire::write_action(&mut conn, action).await.context(ErrIO)?;
+        conn.flush().await.context(ErrIO)?;
+    }
+    match pos {
+        Position::Start | Position::Done => Ok(()),
+        _ => format_error("connection closed before the end of the stream"),
+    }
+}
+
+// Position holds the position of the receiver within a stream
+// read from a connection. The receiving half of a channel assumes
+// that the protocol is followed, so recv uses it to check that
+// the records sent by the peer arrive in an order that's allowed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Position {
+    // Start is the position before the root.
+    Start,
+    // Dir holds the depth of the directory being received.
+    Dir(i32),
+    // File holds the depth of the directory holding
+    // the file being received.
+    File(i32),
+    // Done is the position after the end of the stream.
+    Done,
+}
+
+// Record holds the kind of a record received at some position.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Record {
+    Root,
+    DirEntry,
+    FileEntry,
+    Data,
+    Error,
+    End,
+}
+
+impl Position {
+    // check returns the kind of data, or an error if
+    // data can't be received at the position.
+    fn check(self, data: &fstream::FsData) -> Result<Record> {
+        use fstream::FsData;
+        let record = match data {
+            FsData::Root(..) => Record::Root,
+            FsData::DirEntry(_) => Record::DirEntry,
+            FsData::FileEntry(_) => Record::FileEntry,
+            FsData::Data(_) => Record::Data,
+            FsData::Error(_) => Record::Error,
+            FsData::End => Record::End,
+        };
+        match (self, record) {
+            (Position::Start, Record::Root) => Ok(record),
+            (Position::Start, _) => format_error("stream does not start with a root"),
+            (Position::Dir(_), Record::Root) | (Position::Dir(_), Record::Data) => {
+                format_error("unexpected record in directory")
+            }
+            (Position::Dir(_), _) => Ok(record),
+            (Position::File(_), Record::Data) | (Position::File(_), Record::End) => Ok(record),
+            (Position::File(_), _) => format_error("unexpected record in file"),
+            (Position::Done, _) => format_error("unexpected record after the end of the stream"),
+        }
+    }
+
+    // next returns the position after a record that was allowed
+    // by check has been received and the receiver has replied
+    // with action.
+    fn next(self, record: Record, action: fstream::Action) -> Position {
+        use fstream::Action;
+        let up = |depth| {
+            if depth > 1 {
+                Position::Dir(depth - 1)
+            } else {
+                Position::Done
+            }
+        };
+        match (self, record, action) {
+            (Position::Start, _, Action::Down) => Position::Dir(1),
+            (Position::Start, _, _) => Position::Done,
+            (Position::Dir(depth), Record::DirEntry, Action::Down) => Position::Dir(depth + 1),
+            (Position::Dir(depth), Record::FileEntry, Action::Down) => Position::File(depth),
+            (Position::Dir(depth), Record::DirEntry, Action::Skip)
+            | (Position::Dir(depth), Record::FileEntry, Action::Skip)
+            | (Position::Dir(depth), Record::End, _) => up(depth),
+            (Position::File(depth), Record::Data, Action::Skip)
+            | (Position::File(depth), Record::End, _) => Position::Dir(depth),
+            _ => self,
+        }
+    }
+}
+
+fn format_error<T>(msg: &str) -> Result<T> {
+    ErrFormat { msg }.fail()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fstream::{FsData, Kind};
+
+    // peer writes the given records to conn as a remote sender would,
+    // reading the action sent in reply to each one.
+    async fn peer(conn: tokio::io::DuplexStream, records: Vec<FsData>) {
+        let mut conn = BufStream::new(conn);
+        wire::write_header(&mut conn).await.unwrap();
+        for data in records {
+            wire::write_data(&mut conn, &data).await.unwrap();
+            conn.flush().await.unwrap();
+            if wire::read_action(&mut conn).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // receive receives the whole of root, descending into
+    // every directory and file.
+    async fn receive(root: fstream::RecvRoot) -> fstream::Result<()> {
+        let (_, mut dir) = root.dir().await?;
+        loop {
+            dir = match dir.entry().await? {
+                fstream::RecvEntry::Dir(_, action) => action.down().await?,
+                fstream::RecvEntry::File(_, action) => {
+                    let mut file = action.down().await?;
+                    loop {
+                        match file.data().await? {
+                            fstream::RecvData::Bytes(_, file1) => file = file1,
+                            fstream::RecvData::End(dir) => break dir,
+                        }
+                    }
+                }
+                fstream::RecvEntry::Error(_, dir) => dir,
+                fstream::RecvEntry::End(Some(dir)) => dir,
+                fstream::RecvEntry::End(None) => return Ok(()),
+            }
+        }
+    }
+
+    // relay relays records from a fake peer through recv
+    // to receive, returning the result of recv.
+    async fn relay(records: Vec<FsData>) -> Result<()> {
+        let (local, remote) = tokio::io::duplex(4096);
+        let (tx, root) = fstream::new_raw();
+        let peer = tokio::spawn(peer(remote, records));
+        let receiver = tokio::spawn(receive(root));
+        let result = recv(local, tx).await;
+        peer.await.unwrap();
+        let _ = receiver.await.unwrap();
+        result
+    }
+
+    fn root() -> FsData {
+        FsData::Root(PathBuf::from("/x"), fstream::Order::Sorted)
+    }
+
+    #[tokio::test]
+    async fn recv_valid_stream() {
+        relay(vec![
+            root(),
+            FsData::DirEntry(fstream::DirEntry::new("d", Kind::Dir)),
+            FsData::FileEntry(fstream::DirEntry::new("f", Kind::File)),
+            FsData::Data(b"hello".to_vec()),
+            FsData::End,
+            FsData::End,
+            FsData::End,
+        ])
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn recv_data_in_directory() {
+        let err = relay(vec![root(), FsData::Data(b"hello".to_vec())])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid stream: unexpected record in directory"
+        );
+    }
+
+    #[tokio::test]
+    async fn recv_root_in_directory() {
+        let err = relay(vec![root(), root()]).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid stream: unexpected record in directory"
+        );
+    }
+
+    #[tokio::test]
+    async fn recv_entry_in_file() {
+        let err = relay(vec![
+            root(),
+            FsData::FileEntry(fstream::DirEntry::new("f", Kind::File)),
+            FsData::FileEntry(fstream::DirEntry::new("g", Kind::File)),
+        ])
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "invalid stream: unexpected record in file");
+    }
+
+    #[tokio::test]
+    async fn recv_no_root() {
+        let err = relay(vec![FsData::End]).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid stream: stream does not start with a root"
+        );
+    }
+
+    #[tokio::test]
+    async fn recv_record_after_end() {
+        let err = relay(vec![root(), FsData::End, FsData::End])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid stream: unexpected record after the end of the stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn recv_truncated_stream() {
+        let err = relay(vec![root()]).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid stream: connection closed before the end of the stream"
+        );
+    }
+}
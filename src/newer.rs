@@ -0,0 +1,53 @@
+use super::fstream;
+
+use super::fs;
+use super::CommandType;
+use super::Selector;
+use super::Value;
+
+// newer returns a selector that selects entries that were modified
+// more recently than the file named by its argument.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![types.string()],
+            var_args: None,
+            ret: types.selector(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        _tasks: &mut super::Tasks,
+        _flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let path = self.types.to_string(args.pop().unwrap());
+        let mtime = std::fs::metadata(&path)
+            .and_then(|md| md.modified())
+            .map_err(|err| {
+                fstream::ErrUsage {
+                    msg: format!("cannot get reference time from {}: {}", path, err),
+                }
+                .build()
+            })?;
+        Ok(self
+            .types
+            .from_selector(Selector::new(move |entry, _path, _depth| {
+                Ok(entry.mtime > mtime)
+            })))
+    }
+}
@@ -0,0 +1,79 @@
+use super::fstream;
+use futures::FutureExt;
+
+use super::fs;
+use super::CommandType;
+use super::Scanner;
+use super::Selection;
+use super::Selector;
+use super::Value;
+
+// not returns a selector that selects exactly the entries
+// that its argument doesn't select.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![types.selector()],
+            var_args: None,
+            ret: types.selector(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        _tasks: &mut super::Tasks,
+        _flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let selector = self.types.to_selector(args.pop().unwrap());
+        Ok(self.types.from_selector(not(selector)))
+    }
+}
+
+// not returns a selector that selects exactly the entries
+// that the given selector doesn't select.
+pub fn not(selector: Selector) -> Selector {
+    match selector {
+        Selector::Sync(f) => Selector::new(move |entry, path, depth| Ok(!f(entry, path, depth)?)),
+        Selector::Async(f) => Selector::new_async(move |entry, path, depth| {
+            let selected = f(entry, path, depth);
+            async move { Ok(!selected.await?) }.boxed()
+        }),
+        Selector::Data(f) => Selector::Data(Box::new(move |entry, path, depth| {
+            let selection = f(entry, path, depth);
+            async move {
+                Ok(match selection.await? {
+                    Selection::Known(selected) => Selection::Known(!selected),
+                    Selection::Scan(scanner) => Selection::Scan(Box::new(Not(scanner))),
+                })
+            }
+            .boxed()
+        })),
+    }
+}
+
+// Not inverts the decision of a scanner.
+struct Not(Box<dyn Scanner>);
+
+impl Scanner for Not {
+    fn write(&mut self, data: &[u8]) -> Option<bool> {
+        self.0.write(data).map(|selected| !selected)
+    }
+
+    fn finish(&mut self) -> bool {
+        !self.0.finish()
+    }
+}
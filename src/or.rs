@@ -1,33 +1,48 @@
 use super::fstream;
 
+use super::and;
+use super::fs;
 use super::CommandType;
+use super::Selector;
 use super::Value;
 
-pub fn new_command() -> impl super::Command {
-    Command(CommandType {
-        flags: vec![],
-        args: vec![],
-        var_args: Some(super::Type::Selector),
-        ret: super::Type::Selector,
-    })
+// or returns a selector that selects the entries that any of its
+// arguments select. The arguments are tried in order until one
+// of them selects the entry.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![],
+            var_args: Some(types.selector()),
+            ret: types.selector(),
+        },
+    }
 }
 
-struct Command(CommandType);
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
 
 impl super::Command for Command {
     fn fs_type(&self) -> &super::CommandType {
-        return &self.0;
+        &self.ctype
     }
     fn start(
         &self,
         _tasks: &mut super::Tasks,
-        _flags: Vec<String>,
+        _flags: super::Flags,
         args: Vec<Value>,
-        _rest: Vec<Value>,
     ) -> fstream::Result<Value> {
-        let selectors = args.into_iter().map(|v| v.as_selector()).collect::<Result<Vec<_>, _>>()?;
-        Ok(super::Value::Selector(Box::new(move |entry, path| {
-                selectors.iter().any(|selector| selector(entry, path))
-         })))
+        let selectors: Vec<_> = args.into_iter().map(|v| self.types.to_selector(v)).collect();
+        Ok(self.types.from_selector(or(selectors)))
     }
 }
+
+// or returns a selector that selects the entries that any
+// of the given selectors select.
+pub fn or(selectors: Vec<Selector>) -> Selector {
+    and::short_circuit(selectors, true)
+}
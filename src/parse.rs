@@ -10,6 +10,7 @@ type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
+    #[snafu(display("{}", msg))]
     ErrParse { msg: String },
 }
 
@@ -37,7 +38,7 @@ fn parse_pipeline(lex: &mut Lexer) -> Result<ASTNode> {
 fn parse_command(lex: &mut Lexer) -> Result<Command> {
     let name = match lex.next() {
         Some(Token::Word) => lex.string(),
-        Some(Token::QuotedWord) => lex.string(),
+        Some(Token::QuotedWord) => unquote(lex.str()),
         tok => {
             return Err(ErrParse {
                 msg: format!("expected word, got {:?}", tok).to_string(),
@@ -45,9 +46,21 @@ fn parse_command(lex: &mut Lexer) -> Result<Command> {
             .build());
         }
     };
+    lex.commands += 1;
+    let pos = lex.commands;
+    let mut flags = vec![];
     let mut args = vec![];
     loop {
         match lex.peek() {
+            Some(Token::Flag) => {
+                if !args.is_empty() {
+                    return Err(ErrParse {
+                        msg: format!("flag {} must come before arguments to {}", lex.str(), name),
+                    }
+                    .build());
+                }
+                flags.push(parse_flag(lex.str()));
+            }
             Some(Token::Word) => {
                 args.push(ASTNode::Word(lex.source[lex.lexer.span()].to_string()));
             }
@@ -69,8 +82,10 @@ fn parse_command(lex: &mut Lexer) -> Result<Command> {
             }
             None | Some(Token::Pipe) | Some(Token::CloseCurly) => {
                 return Ok(Command {
-                    name: name,
-                    args: args,
+                    name,
+                    pos,
+                    flags,
+                    args,
                 });
             }
             _ => {
@@ -84,16 +99,78 @@ fn parse_command(lex: &mut Lexer) -> Result<Command> {
     }
 }
 
+// parse_flag parses a flag token of the form -name, -name=value
+// or -name='quoted value'.
+fn parse_flag(s: &str) -> Flag {
+    let s = &s[1..];
+    match s.find('=') {
+        Some(i) => {
+            let value = &s[i + 1..];
+            Flag {
+                name: s[..i].to_string(),
+                value: Some(if value.starts_with('\'') {
+                    unquote(value)
+                } else {
+                    value.to_string()
+                }),
+            }
+        }
+        None => Flag {
+            name: s.to_string(),
+            value: None,
+        },
+    }
+}
+
 fn unquote(s: &str) -> String {
-    return s[1..s.len() - 1].replace("''", "'");
+    s[1..s.len() - 1].replace("''", "'")
 }
 
 #[derive(Debug, Clone)]
 pub struct Command {
     pub name: String,
+    // pos holds the position of the command in the expression,
+    // counting from 1, or 0 if the command was added implicitly,
+    // such as a conversion.
+    pub pos: usize,
+    pub flags: Vec<Flag>,
     pub args: Vec<ASTNode>,
 }
 
+impl Command {
+    // flags_to_args moves flags that aren't in defined from the end of
+    // the command's flags to the start of its arguments as plain words,
+    // until it has want arguments. This means that an argument that
+    // looks like a flag, such as the mode in "mode -0022", needn't
+    // be quoted when the command doesn't define a flag of that name.
+    pub fn flags_to_args(&mut self, defined: &[&str], want: usize) {
+        while self.args.len() < want {
+            match self.flags.last() {
+                Some(flag) if !defined.contains(&flag.name.as_str()) => {
+                    let flag = self.flags.pop().unwrap();
+                    self.args.insert(
+                        0,
+                        ASTNode::Word(match flag.value {
+                            Some(value) => format!("-{}={}", flag.name, value),
+                            None => format!("-{}", flag.name),
+                        }),
+                    );
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+// Flag represents a flag given to a command. The name
+// does not include the leading hyphen; the value is
+// None when no value was given.
+#[derive(Debug, Clone)]
+pub struct Flag {
+    pub name: String,
+    pub value: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub enum ASTNode {
     Command(Command),
@@ -104,6 +181,9 @@ pub enum ASTNode {
 impl std::fmt::Display for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", quote(&self.name))?;
+        for flag in &self.flags {
+            write!(f, " {}", flag)?;
+        }
         let args: &Vec<ASTNode> = &self.args; // TODO there must be a neater way of doing this.
         for arg in args {
             if let ASTNode::Command(_) = arg {
@@ -116,27 +196,33 @@ impl std::fmt::Display for Command {
     }
 }
 
+impl std::fmt::Display for Flag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "-{}={}", self.name, quote(value)),
+            None => write!(f, "-{}", self.name),
+        }
+    }
+}
+
 impl std::fmt::Display for ASTNode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        Ok(match self {
-            ASTNode::Command(c) => {
-                write!(f, "{}", c)?;
-            }
-            ASTNode::Pipe(node, c) => {
-                write!(f, "{} | {}", node, c)?;
-            }
-            ASTNode::Word(s) => {
-                write!(f, "{}", quote(&s))?;
-            }
-        })
+        match self {
+            ASTNode::Command(c) => write!(f, "{}", c),
+            ASTNode::Pipe(node, c) => write!(f, "{} | {}", node, c),
+            ASTNode::Word(s) => write!(f, "{}", quote(s)),
+        }
     }
 }
 
+// quote returns s quoted if necessary so that it
+// will be parsed as a single word.
 fn quote(s: &str) -> String {
-    if !s.contains('\'') {
+    let mut lex = Token::lexer(s);
+    if lex.next() == Some(Token::Word) && lex.next().is_none() {
         s.to_string()
     } else {
-        s.replace("'", "''")
+        format!("'{}'", s.replace('\'', "''"))
     }
 }
 
@@ -144,6 +230,8 @@ struct Lexer<'src> {
     source: &'src str,
     lexer: logos::Lexer<'src, Token>,
     peeked: Option<Option<Token>>,
+    // commands holds the number of commands parsed so far.
+    commands: usize,
 }
 
 impl<'source> Lexer<'source> {
@@ -151,7 +239,8 @@ impl<'source> Lexer<'source> {
         Self {
             lexer: Token::lexer(source),
             peeked: None,
-            source: source,
+            source,
+            commands: 0,
         }
     }
 
@@ -182,11 +271,10 @@ impl<'source> Iterator for Lexer<'source> {
         }
     }
 }
-// TODO flags with values: -foo=bar, -foo='bar baz'
-
 #[derive(Logos, Debug, PartialEq, Clone)]
 enum Token {
-    #[regex("[ \r\t]", logos::skip)]
+    #[regex("[ \r\t\n]", logos::skip)]
+    #[regex("#[^\n]*", logos::skip)]
     #[error]
     Error,
 
@@ -200,11 +288,124 @@ enum Token {
     #[token("}")]
     CloseCurly,
 
-    //#[regex("-[a-zA-Z]+")]
-    //Option,
-    #[regex("[a-zA-Z0-9/]+")] // TODO allow more chars here
+    // Flags start with a hyphen followed by a letter or digit
+    // and may have a value: -foo, -foo=bar, -foo='bar baz'.
+    #[regex(
+        "-[a-zA-Z0-9][a-zA-Z0-9_-]*(=([a-zA-Z0-9/._~+,:@%=-]*|'([^']|'')*'))?",
+        priority = 10
+    )]
+    Flag,
+    #[regex("[a-zA-Z0-9/._~+,:@%=-]+")] // TODO allow more chars here
     Word,
 
     #[regex("'([^']|'')*'")]
     QuotedWord,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(s: &str) -> Command {
+        match parse(s).unwrap() {
+            ASTNode::Command(c) => c,
+            node => panic!("{} is not a command", node),
+        }
+    }
+
+    #[test]
+    fn lex_flags() {
+        let tokens: Vec<Token> =
+            Token::lexer("-x -depth=3 -name='a b' -0 -a-b_c=x=y foo - -=x").collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Flag,
+                Token::Flag,
+                Token::Flag,
+                Token::Flag,
+                Token::Flag,
+                Token::Word,
+                Token::Word,
+                Token::Word,
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_flags() {
+        let c = command("walk -x -depth=3 -name='it''s here' -0 /tmp");
+        let flags: Vec<(&str, Option<&str>)> = c
+            .flags
+            .iter()
+            .map(|f| (f.name.as_str(), f.value.as_deref()))
+            .collect();
+        assert_eq!(
+            flags,
+            vec![
+                ("x", None),
+                ("depth", Some("3")),
+                ("name", Some("it's here")),
+                ("0", None),
+            ]
+        );
+        assert_eq!(c.to_string(), "walk -x -depth=3 -name='it''s here' -0 /tmp");
+    }
+
+    #[test]
+    fn flag_after_argument() {
+        let err = parse("walk /tmp -x").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "flag -x must come before arguments to walk"
+        );
+    }
+
+    #[test]
+    fn parse_quoted() {
+        let c = command("'a b' 'it''s' '' '-x' {walk .}");
+        assert_eq!(c.name, "a b");
+        assert_eq!(c.to_string(), "'a b' 'it''s' '' '-x' {walk .}");
+    }
+
+    #[test]
+    fn quote_words() {
+        assert_eq!(quote("abc"), "abc");
+        assert_eq!(quote("/a/b.c"), "/a/b.c");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), "'it''s'");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("-x"), "'-x'");
+        assert_eq!(quote("-0022"), "'-0022'");
+        assert_eq!(quote("a|b"), "'a|b'");
+    }
+
+    #[test]
+    fn flags_to_args() {
+        for (expr, want, result) in &[
+            ("mode -0022", 1, "mode '-0022'"),
+            ("mode -d", 1, "mode '-d'"),
+            ("mode -x=y", 1, "mode '-x=y'"),
+            ("f -a -b", 1, "f -a '-b'"),
+            ("f -a -b", 2, "f '-a' '-b'"),
+            ("f -a -b x", 2, "f -a '-b' x"),
+        ] {
+            let mut c = command(expr);
+            c.flags_to_args(&[], *want);
+            assert_eq!(c.to_string(), *result, "{}", expr);
+        }
+
+        // Defined flags stay as flags.
+        let mut c = command("f -a -b");
+        c.flags_to_args(&["a"], 1);
+        assert_eq!(c.to_string(), "f -a '-b'");
+        let mut c = command("f -a");
+        c.flags_to_args(&["a"], 1);
+        assert_eq!(c.to_string(), "f -a");
+
+        // Flags aren't moved when there are enough arguments.
+        let mut c = command("walk -bogus /tmp");
+        c.flags_to_args(&[], 1);
+        assert_eq!(c.to_string(), "walk -bogus /tmp");
+    }
+}
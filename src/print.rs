@@ -1,79 +1,546 @@
 use super::fstream;
 use snafu::{ResultExt, Snafu};
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::SystemTime;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+use super::fs;
 use super::CommandType;
+use super::FlagType;
 use super::Value;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    ErrFstream { source: fstream::Error },
+    #[snafu(display("{}", source))]
+    ErrIO { source: std::io::Error },
 }
 
-pub fn new_command() -> impl super::Command {
-    Command(CommandType {
-        flags: vec![],
-        args: vec![super::Type::Fs], // TODO Entries
-        var_args: None,
-        ret: super::Type::Void,
-    })
+// print prints a line for each entry in its argument, by default
+// holding the entry's kind letter and its path. With -l, it prints
+// the entries in the style of ls -l, with -json it prints a JSON
+// object holding all the entry's metadata, and with -fmt=template it
+// prints the template with these directives replaced:
+//
+//	%p	the path of the entry
+//	%f	the name of the entry
+//	%y	the kind letter of the entry
+//	%s	the size in bytes
+//	%m	the permissions in octal, as four digits
+//	%M	the permissions in symbolic form, as shown by -l
+//	%u, %g	the name of the owner and group, or the number if unknown
+//	%U, %G	the numeric user and group ids
+//	%n	the number of hard links
+//	%i	the inode number
+//	%D	the device number
+//	%t	the modification time
+//	%T	the modification time in seconds since the Unix epoch
+//	%l	the target of a symbolic link
+//	%%	a literal %
+//
+// With -0, each entry is terminated by a NUL byte rather than a newline,
+// and only the path is printed unless another format is given, so the
+// output is suitable for xargs -0. Times are shown in UTC. JSON strings
+// must be valid UTF-8, so with -json any invalid sequences in a path,
+// name or link are replaced by U+FFFD; the other formats print them
+// as they are.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![
+                FlagType::bool("l"),
+                FlagType::bool("0"),
+                FlagType::bool("json"),
+                FlagType::value("fmt", types.string()),
+            ],
+            args: vec![types.entries()],
+            var_args: None,
+            ret: types.void(),
+        },
+    }
 }
 
-struct Command(CommandType);
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
 
 impl super::Command for Command {
     fn fs_type(&self) -> &super::CommandType {
-        return &self.0;
+        &self.ctype
     }
     fn start(
         &self,
         tasks: &mut super::Tasks,
-        _flags: Vec<String>,
+        flags: super::Flags,
         args: Vec<Value>,
-        _rest: Vec<Value>,
     ) -> fstream::Result<Value> {
         let mut args = args;
-        let root = args.pop().unwrap().as_fs()?;
-        tasks.add(tokio::spawn(async {
-            // TODO avoid unwrap here.
-            print(root).await.context(super::ErrPrint).unwrap();
-            Ok(())
+        let entries = self.types.to_entries(args.pop().unwrap());
+        let fmt = flags.string("fmt");
+        let formats = flags.has("l") as u32 + flags.has("json") as u32 + fmt.is_some() as u32;
+        if formats > 1 {
+            return Err(fstream::ErrUsage {
+                msg: "only one of -l, -json and -fmt may be given".to_string(),
+            }
+            .build());
+        }
+        let format = if flags.has("l") {
+            Format::Long
+        } else if flags.has("json") {
+            Format::Json
+        } else if let Some(fmt) = fmt {
+            Format::Template(parse_template(&fmt).map_err(|msg| {
+                fstream::ErrUsage {
+                    msg: format!("invalid format {:?}: {}", fmt, msg),
+                }
+                .build()
+            })?)
+        } else if flags.has("0") {
+            Format::Path
+        } else {
+            Format::Kind
+        };
+        let opts = Options {
+            format,
+            terminator: if flags.has("0") { b'\0' } else { b'\n' },
+        };
+        tasks.add(tokio::spawn(async move {
+            print(entries, opts).await.context(super::ErrPrint)
         }));
-        Ok(Value::Void)
+        Ok(self.types.from_void())
+    }
+}
+
+// Options holds options that control how print shows entries.
+#[derive(Debug, Clone)]
+pub struct Options {
+    // format holds the format of each entry.
+    pub format: Format,
+    // terminator holds the byte printed after each entry.
+    pub terminator: u8,
+}
+
+// Format specifies how print shows an entry.
+#[derive(Debug, Clone)]
+pub enum Format {
+    // Kind shows the kind letter of the entry followed by its path.
+    Kind,
+    // Path shows only the path.
+    Path,
+    // Long shows the entry in the style of ls -l.
+    Long,
+    // Json shows the entry as a JSON object.
+    Json,
+    // Template shows the entry according to a parsed -fmt template.
+    Template(Vec<Piece>),
+}
+
+// Piece holds part of a parsed -fmt template.
+#[derive(Debug, Clone)]
+pub enum Piece {
+    Literal(String),
+    Directive(char),
+}
+
+// parse_template parses a -fmt template into its pieces.
+fn parse_template(s: &str) -> std::result::Result<Vec<Piece>, String> {
+    let mut pieces = vec![];
+    let mut literal = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => literal.push('%'),
+            Some(c) if "pfysmMugUGnitDTl".contains(c) => {
+                if !literal.is_empty() {
+                    pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(Piece::Directive(c));
+            }
+            Some(c) => return Err(format!("unknown directive %{}", c)),
+            None => return Err("trailing %".to_string()),
+        }
     }
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    Ok(pieces)
 }
 
-// print prints some information about all the entries in root.
-pub async fn print(root: fstream::RecvRoot) -> Result<()> {
-    let (path, dir) = root.dir().await.context(ErrFstream)?;
-    let mut path = path;
-    print_dir(&mut path, dir).await?;
+// print prints some information about each of the given entries.
+// Errors in the stream are printed to the standard error.
+pub async fn print(entries: fstream::RecvEntries, opts: Options) -> Result<()> {
+    let mut entries = entries;
+    let mut printer = Printer {
+        opts,
+        names: Names::default(),
+        buf: vec![],
+    };
+    while let Some(item) = entries.recv().await {
+        match item {
+            Ok(msg) => printer.print(&msg.path, &msg.entry).context(ErrIO)?,
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+    std::io::stdout().flush().context(ErrIO)?;
     Ok(())
 }
 
-async fn print_dir(path: &mut std::path::PathBuf, dir: fstream::RecvDir) -> Result<()> {
-    let mut dir = dir;
-    loop {
-        match dir.entry().await.context(ErrFstream)? {
-            fstream::RecvEntry::File(entry, action) => {
-                path.push(entry.file_name());
-                println!("f {}", path.display());
-                path.pop();
-                dir = action.next().await.context(ErrFstream)?;
+// Printer prints entries according to a set of options.
+struct Printer {
+    opts: Options,
+    names: Names,
+    // buf holds the output for an entry so that
+    // it can be written to the standard output at once.
+    buf: Vec<u8>,
+}
+
+impl Printer {
+    // print prints the given entry, found at the given path.
+    fn print(&mut self, path: &Path, entry: &fstream::DirEntry) -> std::io::Result<()> {
+        let Printer { opts, names, buf } = self;
+        buf.clear();
+        match &opts.format {
+            Format::Kind => {
+                write!(buf, "{} ", entry.kind.letter())?;
+                buf.extend(path.as_os_str().as_bytes());
             }
-            fstream::RecvEntry::Dir(entry, action) => {
-                path.push(entry.file_name());
-                println!("d {}", path.display());
-                dir = action.down().await.context(ErrFstream)?;
+            Format::Path => buf.extend(path.as_os_str().as_bytes()),
+            Format::Long => {
+                write!(
+                    buf,
+                    "{} {:>3} {:<8} {:<8} ",
+                    mode_string(entry),
+                    entry.nlink,
+                    names.user(entry.uid),
+                    names.group(entry.gid)
+                )?;
+                match entry.kind {
+                    fstream::Kind::BlockDevice | fstream::Kind::CharDevice => {
+                        let (major, minor) = device_numbers(entry.rdev);
+                        write!(buf, "{:>4}, {:>4}", major, minor)?;
+                    }
+                    _ => write!(buf, "{:>10}", entry.size)?,
+                }
+                let t = civil_time(unix_time(entry.mtime).0);
+                write!(
+                    buf,
+                    " {:04}-{:02}-{:02} {:02}:{:02} ",
+                    t.0, t.1, t.2, t.3, t.4
+                )?;
+                buf.extend(path.as_os_str().as_bytes());
+                if let Some(link) = &entry.link {
+                    buf.extend(b" -> ");
+                    buf.extend(link.as_os_str().as_bytes());
+                }
             }
-            fstream::RecvEntry::End(Some(dir1)) => {
-                path.pop();
-                dir = dir1;
+            Format::Json => {
+                let (secs, nsecs) = unix_time(entry.mtime);
+                let t = civil_time(secs);
+                let mut mtime = format!(
+                    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                    t.0, t.1, t.2, t.3, t.4, t.5
+                );
+                if nsecs != 0 {
+                    mtime += &format!(".{:09}", nsecs);
+                }
+                mtime.push('Z');
+                write!(
+                    buf,
+                    "{{\"path\":{},\"name\":{},\"kind\":\"{}\",\"mode\":\"{:04o}\",\"size\":{},\"mtime\":\"{}\",\
+                     \"uid\":{},\"user\":{},\"gid\":{},\"group\":{},\"nlink\":{},\"dev\":{},\"ino\":{},\"rdev\":{}",
+                    json_string(&path.to_string_lossy()),
+                    json_string(&entry.name.to_string_lossy()),
+                    entry.kind.letter(),
+                    entry.mode,
+                    entry.size,
+                    mtime,
+                    entry.uid,
+                    json_string(&names.user(entry.uid)),
+                    entry.gid,
+                    json_string(&names.group(entry.gid)),
+                    entry.nlink,
+                    entry.dev,
+                    entry.ino,
+                    entry.rdev
+                )?;
+                if let Some(link) = &entry.link {
+                    write!(buf, ",\"link\":{}", json_string(&link.to_string_lossy()))?;
+                }
+                buf.push(b'}');
             }
-            fstream::RecvEntry::End(None) => {
-                return Ok(());
+            Format::Template(pieces) => {
+                for piece in pieces {
+                    match piece {
+                        Piece::Literal(s) => buf.extend(s.as_bytes()),
+                        Piece::Directive(c) => match c {
+                            'p' => buf.extend(path.as_os_str().as_bytes()),
+                            'f' => buf.extend(entry.name.as_bytes()),
+                            'y' => write!(buf, "{}", entry.kind.letter())?,
+                            's' => write!(buf, "{}", entry.size)?,
+                            'm' => write!(buf, "{:04o}", entry.mode)?,
+                            'M' => buf.extend(mode_string(entry).as_bytes()),
+                            'u' => buf.extend(names.user(entry.uid).as_bytes()),
+                            'g' => buf.extend(names.group(entry.gid).as_bytes()),
+                            'U' => write!(buf, "{}", entry.uid)?,
+                            'G' => write!(buf, "{}", entry.gid)?,
+                            'n' => write!(buf, "{}", entry.nlink)?,
+                            'i' => write!(buf, "{}", entry.ino)?,
+                            'D' => write!(buf, "{}", entry.dev)?,
+                            't' => {
+                                let t = civil_time(unix_time(entry.mtime).0);
+                                write!(
+                                    buf,
+                                    "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                                    t.0, t.1, t.2, t.3, t.4, t.5
+                                )?;
+                            }
+                            'T' => write!(buf, "{}", unix_time(entry.mtime).0)?,
+                            'l' => {
+                                if let Some(link) = &entry.link {
+                                    buf.extend(link.as_os_str().as_bytes());
+                                }
+                            }
+                            _ => unreachable!("unknown directive %{}", c),
+                        },
+                    }
+                }
+            }
+        }
+        buf.push(opts.terminator);
+        std::io::stdout().write_all(buf)
+    }
+}
+
+// Names maps user and group ids to names. It reads /etc/passwd
+// and /etc/group the first time that each is needed.
+#[derive(Debug, Default)]
+struct Names {
+    users: Option<HashMap<u32, String>>,
+    groups: Option<HashMap<u32, String>>,
+}
+
+impl Names {
+    // user returns the name of the user with the given id,
+    // or the id itself if there's no such user.
+    fn user(&mut self, uid: u32) -> String {
+        lookup_name(&mut self.users, "/etc/passwd", uid)
+    }
+
+    // group returns the name of the group with the given id,
+    // or the id itself if there's no such group.
+    fn group(&mut self, gid: u32) -> String {
+        lookup_name(&mut self.groups, "/etc/group", gid)
+    }
+}
+
+fn lookup_name(names: &mut Option<HashMap<u32, String>>, file: &str, id: u32) -> String {
+    names
+        .get_or_insert_with(|| read_names(file))
+        .get(&id)
+        .cloned()
+        .unwrap_or_else(|| id.to_string())
+}
+
+// read_names reads a file in the format of /etc/passwd or /etc/group,
+// where each line holds a name in its first field and an id in its
+// third, and returns a map from id to name. Where an id appears more
+// than once, the first name wins. A file that can't be read is
+// treated as empty.
+fn read_names(file: &str) -> HashMap<u32, String> {
+    let mut names = HashMap::new();
+    for line in std::fs::read_to_string(file).unwrap_or_default().lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 3 {
+            continue;
+        }
+        if let Ok(id) = fields[2].parse() {
+            names.entry(id).or_insert_with(|| fields[0].to_string());
+        }
+    }
+    names
+}
+
+// mode_string returns the kind and permissions of the entry
+// in the form shown by ls -l, such as drwxr-xr-x.
+fn mode_string(entry: &fstream::DirEntry) -> String {
+    let mut s = String::with_capacity(10);
+    s.push(match entry.kind {
+        fstream::Kind::File => '-',
+        kind => kind.letter(),
+    });
+    for (i, &(special, c)) in [(0o4000, 's'), (0o2000, 's'), (0o1000, 't')]
+        .iter()
+        .enumerate()
+    {
+        let bits = entry.mode >> (6 - 3 * i);
+        s.push(if bits & 4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 1 != 0, entry.mode & special != 0) {
+            (true, true) => c,
+            (false, true) => c.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+// device_numbers returns the major and minor numbers
+// of the given device number, as encoded by Linux.
+fn device_numbers(rdev: u64) -> (u64, u64) {
+    let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0xfff);
+    let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0xff);
+    (major, minor)
+}
+
+// unix_time returns the time as whole seconds since the Unix epoch,
+// rounded down, and the nanoseconds after that.
+fn unix_time(t: SystemTime) -> (i64, u32) {
+    match t.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(err) => {
+            let d = err.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                nsecs => (-(d.as_secs() as i64) - 1, 1_000_000_000 - nsecs),
             }
         }
     }
 }
+
+// civil_time returns the year, month, day, hour, minute and second
+// in UTC of the given number of seconds since the Unix epoch.
+fn civil_time(secs: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400) as u32;
+    // This is the days-to-civil algorithm from
+    // http://howardhinnant.github.io/date_algorithms.html.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+// json_string returns s as a quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_template_errors() {
+        for &(template, want) in &[
+            ("%p %q", "unknown directive %q"),
+            ("%", "trailing %"),
+            ("%p %", "trailing %"),
+            ("%%%", "trailing %"),
+        ] {
+            assert_eq!(
+                parse_template(template).unwrap_err(),
+                want,
+                "{:?}",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn parse_template_pieces() {
+        let pieces = parse_template("%%%p: %s%%").unwrap();
+        let pieces: Vec<String> = pieces
+            .iter()
+            .map(|piece| match piece {
+                Piece::Literal(s) => s.clone(),
+                Piece::Directive(c) => format!("<{}>", c),
+            })
+            .collect();
+        assert_eq!(pieces, ["%", "<p>", ": ", "<s>", "%"]);
+    }
+
+    #[test]
+    fn mode_strings() {
+        for &(kind, mode, want) in &[
+            (fstream::Kind::File, 0o644, "-rw-r--r--"),
+            (fstream::Kind::File, 0o4755, "-rwsr-xr-x"),
+            (fstream::Kind::File, 0o4644, "-rwSr--r--"),
+            (fstream::Kind::File, 0o2755, "-rwxr-sr-x"),
+            (fstream::Kind::File, 0o2745, "-rwxr-Sr-x"),
+            (fstream::Kind::Dir, 0o1777, "drwxrwxrwt"),
+            (fstream::Kind::Dir, 0o1776, "drwxrwxrwT"),
+            (fstream::Kind::Symlink, 0o777, "lrwxrwxrwx"),
+            (fstream::Kind::CharDevice, 0o620, "crw--w----"),
+        ] {
+            let mut entry = fstream::DirEntry::new("x", kind);
+            entry.mode = mode;
+            assert_eq!(mode_string(&entry), want, "{:o}", mode);
+        }
+    }
+
+    #[test]
+    fn civil_times() {
+        for &(secs, want) in &[
+            (0, (1970, 1, 1, 0, 0, 0)),
+            (-1, (1969, 12, 31, 23, 59, 59)),
+            (951_827_696, (2000, 2, 29, 12, 34, 56)),
+            (1_735_689_599, (2024, 12, 31, 23, 59, 59)),
+            (-2_203_891_200, (1900, 3, 1, 0, 0, 0)),
+            (-11_670_998_399, (1600, 2, 29, 0, 0, 1)),
+        ] {
+            assert_eq!(civil_time(secs), want, "{}", secs);
+        }
+    }
+
+    #[test]
+    fn unix_times() {
+        let t = SystemTime::UNIX_EPOCH - std::time::Duration::new(1, 250_000_000);
+        assert_eq!(unix_time(t), (-2, 750_000_000));
+        let t = SystemTime::UNIX_EPOCH + std::time::Duration::new(1, 250_000_000);
+        assert_eq!(unix_time(t), (1, 250_000_000));
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("a/b c"), r#""a/b c""#);
+        assert_eq!(json_string("\"\\\n\r\t"), r#""\"\\\n\r\t""#);
+        assert_eq!(json_string("\0\x1b\x1f "), r#""\u0000\u001b\u001f ""#);
+        assert_eq!(json_string("é\u{7f}"), "\"é\u{7f}\"");
+    }
+
+    #[test]
+    fn device_number() {
+        assert_eq!(device_numbers(0x801), (8, 1));
+        assert_eq!(device_numbers(0x1_2000_6783_459a), (0x12345, 0x6789a));
+    }
+}
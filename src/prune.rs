@@ -0,0 +1,77 @@
+use super::fstream;
+use snafu::ResultExt;
+
+use super::filter;
+use super::fs;
+use super::not;
+use super::walk::ErrorPolicy;
+use super::CommandType;
+use super::FlagType;
+use super::Selector;
+use super::Value;
+
+// prune discards all the entries in its input stream that are chosen by
+// its selector argument, along with everything underneath them. The
+// sender is told not to send the discarded entries, so a walk never
+// reads pruned directories at all. It's equivalent to filter with the
+// selector negated. The -e flag is as for filter; an entry
+// for which the selector fails is discarded.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![FlagType::value("e", types.string())],
+            args: vec![types.fs(), types.selector()],
+            var_args: None,
+            ret: types.fs(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        tasks: &mut super::Tasks,
+        flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let selector = self.types.to_selector(args.pop().unwrap());
+        let recv_root0 = self.types.to_fs(args.pop().unwrap());
+        let errors = ErrorPolicy::parse(flags.string("e").as_deref())?;
+        let (send_root1, recv_root1) = fstream::new();
+        tasks.add(tokio::spawn(async move {
+            prune(recv_root0, send_root1, errors, selector)
+                .await
+                .context(super::ErrPrune)
+        }));
+        Ok(self.types.from_fs(recv_root1))
+    }
+}
+
+// prune prunes by reading from recv_root and sending to send_root,
+// discarding the entries that discard selects. The errors policy
+// specifies what happens when discard fails.
+pub async fn prune(
+    recv_root: fstream::RecvRoot,
+    send_root: fstream::SendRoot,
+    errors: ErrorPolicy,
+    discard: Selector,
+) -> filter::Result<()> {
+    filter::filter(
+        recv_root,
+        send_root,
+        filter::Mode::Prune,
+        errors,
+        not::not(discard),
+    )
+    .await
+}
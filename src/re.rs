@@ -0,0 +1,60 @@
+use super::fstream;
+use regex::bytes::RegexBuilder;
+use std::os::unix::ffi::OsStrExt;
+
+use super::fs;
+use super::CommandType;
+use super::FlagType;
+use super::Selector;
+use super::Value;
+
+// re returns a selector that selects entries whose path matches
+// a regular expression, using the syntax of the regex crate.
+// The expression isn't anchored, so it matches if it matches any
+// part of the path; use ^ and $ to match all of it. With the -i
+// flag, the match is case-insensitive.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![FlagType::bool("i")],
+            args: vec![types.string()],
+            var_args: None,
+            ret: types.selector(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        _tasks: &mut super::Tasks,
+        flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let pattern = self.types.to_string(args.pop().unwrap());
+        let re = RegexBuilder::new(&pattern)
+            .case_insensitive(flags.has("i"))
+            .build()
+            .map_err(|err| {
+                fstream::ErrUsage {
+                    msg: format!("invalid regular expression {:?}: {}", pattern, err),
+                }
+                .build()
+            })?;
+        Ok(self
+            .types
+            .from_selector(Selector::new(move |_entry, path, _depth| {
+                Ok(re.is_match(path.as_os_str().as_bytes()))
+            })))
+    }
+}
@@ -0,0 +1,48 @@
+use super::fstream;
+use snafu::ResultExt;
+
+use super::fs;
+use super::net;
+use super::CommandType;
+use super::Value;
+
+// serve listens on the address given as its argument and sends its input
+// stream to the first process that connects there with the connect
+// command. The address is either unix:path or tcp:host:port;
+// an address without a prefix is taken to be a Unix-domain socket.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![types.fs(), types.string()],
+            var_args: None,
+            ret: types.void(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        tasks: &mut super::Tasks,
+        _flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let addr = net::Addr::parse(&self.types.to_string(args.pop().unwrap()));
+        let root = self.types.to_fs(args.pop().unwrap());
+        tasks.add(tokio::spawn(async move {
+            net::serve(&addr, root).await.context(super::ErrServe)
+        }));
+        Ok(self.types.from_void())
+    }
+}
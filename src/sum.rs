@@ -0,0 +1,277 @@
+use super::fstream;
+use sha2::Digest;
+use snafu::{ResultExt, Snafu};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use super::fs;
+use super::CommandType;
+use super::FlagType;
+use super::Value;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    ErrFstream { source: fstream::Error },
+    #[snafu(display("{}", source))]
+    ErrIO { source: std::io::Error },
+}
+
+// sum prints a checksum of the data in each regular file in its input
+// stream, one line per file in the format printed by sha256sum(1), so
+// that the output can be checked with sha256sum -c or with check.
+// The -a flag names the algorithm: sha256 (the default), blake3 or
+// crc32. Other kinds of entry are skipped.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![FlagType::value("a", types.string())],
+            args: vec![types.fs()],
+            var_args: None,
+            ret: types.void(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        tasks: &mut super::Tasks,
+        flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let root = self.types.to_fs(args.pop().unwrap());
+        let algorithm = Algorithm::parse(flags.string("a").as_deref())?;
+        tasks.add(tokio::spawn(async move {
+            sum(root, algorithm).await.context(super::ErrSum)
+        }));
+        Ok(self.types.from_void())
+    }
+}
+
+// Algorithm specifies a checksum algorithm.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Blake3,
+    Crc32,
+}
+
+impl Algorithm {
+    // parse returns the algorithm with the given name, as given
+    // to the -a flag. The algorithm is sha256 when there's no name
+    // because the flag wasn't given.
+    pub fn parse(name: Option<&str>) -> fstream::Result<Algorithm> {
+        match name {
+            None | Some("sha256") => Ok(Algorithm::Sha256),
+            Some("blake3") => Ok(Algorithm::Blake3),
+            Some("crc32") => Ok(Algorithm::Crc32),
+            Some(name) => Err(fstream::ErrUsage {
+                msg: format!("unknown checksum algorithm {:?}", name),
+            }
+            .build()),
+        }
+    }
+
+    // hasher returns a new hasher that uses the algorithm.
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            Algorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+}
+
+// Hasher computes a checksum of the data written to it.
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    // update adds data to the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+            Hasher::Crc32(h) => h.update(data),
+        }
+    }
+
+    // finish returns the checksum in hexadecimal.
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(h) => hex(&h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            Hasher::Crc32(h) => format!("{:08x}", h.finalize()),
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// sum prints the checksums of all the files in root.
+pub async fn sum(root: fstream::RecvRoot, algorithm: Algorithm) -> Result<()> {
+    let (path, dir) = root.dir().await.context(ErrFstream)?;
+    let mut path = path;
+    let mut dir = dir;
+    loop {
+        match dir.entry().await.context(ErrFstream)? {
+            fstream::RecvEntry::File(entry, action) => {
+                if !entry.is_file() {
+                    dir = action.next().await.context(ErrFstream)?;
+                    continue;
+                }
+                path.push(entry.file_name());
+                let file = action.down().await.context(ErrFstream)?;
+                let (sum, dir1) = sum_file(file, algorithm).await.context(ErrFstream)?;
+                std::io::stdout()
+                    .write_all(&manifest_line(&sum, &path))
+                    .context(ErrIO)?;
+                path.pop();
+                dir = dir1;
+            }
+            fstream::RecvEntry::Dir(entry, action) => {
+                path.push(entry.file_name());
+                dir = action.down().await.context(ErrFstream)?;
+            }
+            fstream::RecvEntry::Error(err, dir1) => {
+                eprintln!("{}", err);
+                dir = dir1;
+            }
+            fstream::RecvEntry::End(Some(dir1)) => {
+                path.pop();
+                dir = dir1;
+            }
+            fstream::RecvEntry::End(None) => {
+                std::io::stdout().flush().context(ErrIO)?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+// sum_file returns the checksum of all the data in file
+// and the directory that the file was in.
+pub async fn sum_file(
+    file: fstream::RecvFile,
+    algorithm: Algorithm,
+) -> fstream::Result<(String, fstream::RecvDir)> {
+    let mut file = file;
+    let mut hasher = algorithm.hasher();
+    loop {
+        match file.data().await? {
+            fstream::RecvData::Bytes(data, file1) => {
+                hasher.update(&data);
+                file = file1;
+            }
+            fstream::RecvData::End(dir) => return Ok((hasher.finish(), dir)),
+        }
+    }
+}
+
+// manifest_line returns the line that sha256sum(1) prints for a file with
+// the given path and checksum. As for sha256sum, if the path holds a
+// backslash, newline or carriage return, the line starts with a
+// backslash and those characters are escaped.
+pub fn manifest_line(sum: &str, path: &Path) -> Vec<u8> {
+    let path = path.as_os_str().as_bytes();
+    let mut line = Vec::with_capacity(sum.len() + path.len() + 4);
+    let escape = path.iter().any(|&b| b == b'\\' || b == b'\n' || b == b'\r');
+    if escape {
+        line.push(b'\\');
+    }
+    line.extend(sum.as_bytes());
+    line.extend(b"  ");
+    for &b in path {
+        match b {
+            b'\\' => line.extend(b"\\\\"),
+            b'\n' => line.extend(b"\\n"),
+            b'\r' => line.extend(b"\\r"),
+            b => line.push(b),
+        }
+    }
+    line.push(b'\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answers() {
+        for &(algorithm, data, want) in &[
+            (
+                Algorithm::Sha256,
+                &b""[..],
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                Algorithm::Sha256,
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                Algorithm::Blake3,
+                b"",
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                Algorithm::Blake3,
+                b"abc",
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+            ),
+            (Algorithm::Crc32, b"", "00000000"),
+            (Algorithm::Crc32, b"abc", "352441c2"),
+            (Algorithm::Crc32, b"123456789", "cbf43926"),
+        ] {
+            let mut hasher = algorithm.hasher();
+            hasher.update(data);
+            assert_eq!(hasher.finish(), want, "{:?} {:?}", algorithm, data);
+            // The data may arrive in any number of blocks.
+            let mut hasher = algorithm.hasher();
+            for b in data {
+                hasher.update(&[*b]);
+            }
+            assert_eq!(hasher.finish(), want, "{:?} {:?}", algorithm, data);
+        }
+    }
+
+    #[test]
+    fn parse_algorithms() {
+        assert_eq!(Algorithm::parse(None).unwrap(), Algorithm::Sha256);
+        assert_eq!(Algorithm::parse(Some("sha256")).unwrap(), Algorithm::Sha256);
+        assert_eq!(Algorithm::parse(Some("blake3")).unwrap(), Algorithm::Blake3);
+        assert_eq!(Algorithm::parse(Some("crc32")).unwrap(), Algorithm::Crc32);
+        assert!(Algorithm::parse(Some("md5")).is_err());
+    }
+
+    #[test]
+    fn manifest_lines() {
+        assert_eq!(manifest_line("0f", Path::new("t/a b")), b"0f  t/a b\n");
+        assert_eq!(
+            manifest_line("0f", Path::new("t/a\\b\nc")),
+            b"\\0f  t/a\\\\b\\nc\n"
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use super::fstream;
+use super::fstream::wire;
+use async_recursion::async_recursion;
+use snafu::{ResultExt, Snafu};
+use tokio::io::AsyncRead;
+
+use super::fs;
+use super::CommandType;
+use super::Value;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+type Reader = dyn AsyncRead + Unpin + Send;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    ErrFstream { source: fstream::Error },
+    #[snafu(display("{}", source))]
+    ErrIO { source: std::io::Error },
+    #[snafu(display("invalid bundle: {}", msg))]
+    ErrFormat { msg: String },
+}
+
+// unbundle reads a bundle written by the bundle command from
+// the named file, or from the standard input if the name is "-".
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![],
+            args: vec![types.string()],
+            var_args: None,
+            ret: types.fs(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        tasks: &mut super::Tasks,
+        _flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let path = self.types.to_string(args.pop().unwrap());
+        let (send_root, recv_root) = fstream::new();
+        tasks.add(tokio::spawn(async move {
+            unbundle_from(&path, send_root).await.context(super::ErrUnbundle)
+        }));
+        Ok(self.types.from_fs(recv_root))
+    }
+}
+
+// unbundle_from reads a bundle from the named file.
+async fn unbundle_from(path: &str, root: fstream::SendRoot) -> Result<()> {
+    if path == "-" {
+        unbundle(&mut tokio::io::BufReader::new(tokio::io::stdin()), root).await
+    } else {
+        let f = tokio::fs::File::open(path).await.context(ErrIO)?;
+        unbundle(&mut tokio::io::BufReader::new(f), root).await
+    }
+}
+
+// unbundle reads a bundle from r and sends it to root. Any parts of
+// the bundle that the receiver isn't interested in are read and discarded.
+pub async fn unbundle<R: AsyncRead + Unpin + Send + 'static>(
+    r: &mut R,
+    root: fstream::SendRoot,
+) -> Result<()> {
+    let r: &mut Reader = r;
+    let version = wire::read_header(r).await.context(ErrIO)?;
+    let (path, order) = match wire::read_data(r, version).await.context(ErrIO)? {
+        fstream::FsData::Root(path, order) => (path, order),
+        _ => return format_error("bundle does not start with a root"),
+    };
+    if let Some(dir) = root.dir(path, order).await.context(ErrFstream)? {
+        unbundle_dir(r, version, dir).await?;
+    }
+    Ok(())
+}
+
+#[async_recursion]
+async fn unbundle_dir(
+    r: &mut Reader,
+    version: u32,
+    dir: fstream::SendDir,
+) -> Result<Option<fstream::SendDir>> {
+    let mut dir = dir;
+    loop {
+        match read(r, version).await? {
+            fstream::FsData::DirEntry(entry) => match dir.dir(entry).await.context(ErrFstream)? {
+                fstream::SendDirEntryAction::Down(child) => {
+                    // Note: subdirectories will always return Some(dir)
+                    // because None can only happen at the root and
+                    // we know that the child is at least one level down.
+                    dir = unbundle_dir(r, version, child).await?.unwrap();
+                }
+                fstream::SendDirEntryAction::Next(next) => {
+                    skip(r, version).await?;
+                    dir = next;
+                }
+                fstream::SendDirEntryAction::Skip(parent) => {
+                    // Skip the directory's contents and then the
+                    // rest of the directory that contains it.
+                    skip(r, version).await?;
+                    skip(r, version).await?;
+                    return Ok(Some(parent));
+                }
+                fstream::SendDirEntryAction::End => {
+                    return Ok(None);
+                }
+            },
+            fstream::FsData::FileEntry(entry) => match dir.file(entry).await.context(ErrFstream)? {
+                fstream::SendFileEntryAction::Down(file) => {
+                    dir = unbundle_file(r, version, file).await?;
+                }
+                fstream::SendFileEntryAction::Next(next) => {
+                    skip(r, version).await?;
+                    dir = next;
+                }
+                fstream::SendFileEntryAction::Skip(parent) => {
+                    skip(r, version).await?;
+                    skip(r, version).await?;
+                    return Ok(Some(parent));
+                }
+                fstream::SendFileEntryAction::End => {
+                    return Ok(None);
+                }
+            },
+            fstream::FsData::Error(err) => {
+                dir = dir.error(err).await.context(ErrFstream)?;
+            }
+            fstream::FsData::End => {
+                return dir.end().await.context(ErrFstream);
+            }
+            _ => return format_error("unexpected record in directory"),
+        }
+    }
+}
+
+async fn unbundle_file(
+    r: &mut Reader,
+    version: u32,
+    file: fstream::SendFile,
+) -> Result<fstream::SendDir> {
+    let mut file = file;
+    loop {
+        match read(r, version).await? {
+            fstream::FsData::Data(data) => match file.data(data).await.context(ErrFstream)? {
+                fstream::SendFileAction::Next(next) => {
+                    file = next;
+                }
+                fstream::SendFileAction::Skip(dir) => {
+                    skip(r, version).await?;
+                    return Ok(dir);
+                }
+            },
+            fstream::FsData::End => {
+                return file.end().await.context(ErrFstream);
+            }
+            _ => return format_error("unexpected record in file"),
+        }
+    }
+}
+
+// skip reads and discards records up to and including the End
+// record that terminates the current file or directory.
+// Every file entry in a bundle is followed by its data and an End
+// record, so nesting can be tracked without knowing which
+// entries were files.
+async fn skip(r: &mut Reader, version: u32) -> Result<()> {
+    let mut depth = 1;
+    while depth > 0 {
+        match read(r, version).await? {
+            fstream::FsData::DirEntry(_) | fstream::FsData::FileEntry(_) => depth += 1,
+            fstream::FsData::End => depth -= 1,
+            fstream::FsData::Data(_) | fstream::FsData::Error(_) => (),
+            fstream::FsData::Root(..) => return format_error("unexpected root record"),
+        }
+    }
+    Ok(())
+}
+
+// read reads a record encoded with the given version of the format.
+async fn read(r: &mut Reader, version: u32) -> Result<fstream::FsData> {
+    wire::read_data(r, version).await.context(ErrIO)
+}
+
+fn format_error<T>(msg: &str) -> Result<T> {
+    ErrFormat { msg }.fail()
+}
@@ -1,102 +1,347 @@
 use super::fstream;
 use async_recursion::async_recursion;
-use snafu::{ResultExt, Snafu};
+use snafu::{IntoError, ResultExt, Snafu};
+use std::collections::VecDeque;
 use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task;
 
+use super::fs;
 use super::CommandType;
+use super::FlagType;
 use super::Value;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub fn new_command(types: fs::Types) -> impl super::Command {
-    Command(CommandType {
-        flags: vec![],
-        args: vec![types::string()],
-        var_args: None,
-        ret: types::fs(),
-    })
+// walk walks the directory hierarchy rooted at the path given as
+// its argument. Symbolic links are not followed unless the -L flag
+// is given. The -maxdepth=n flag stops the walk from reading
+// directories below depth n, where the entries in the root are at
+// depth 1, and -mindepth=n omits files above depth n. With the -x
+// flag, directories on other file systems are sent but not read.
+// With -j=n, up to n upcoming subdirectories are read concurrently
+// ahead of time; the entries are sent in the same order regardless.
+// The entries in each directory are sorted by name unless the -U flag
+// is given, in which case they're sent in the order they're read
+// from disk without waiting for the whole directory to be read;
+// -j has no effect with -U. The -e flag specifies what happens when
+// part of the file system can't be read: with -e=abort (the default),
+// the walk fails; with -e=report, the error is sent in the stream and
+// the walk carries on; with -e=ignore, the walk carries on regardless.
+// A root directory that can't be read fails the walk unless errors
+// are ignored, because there's nowhere to send the error.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![
+                FlagType::bool("L"),
+                FlagType::value("maxdepth", types.int()),
+                FlagType::value("mindepth", types.int()),
+                FlagType::bool("x"),
+                FlagType::value("j", types.int()),
+                FlagType::bool("U"),
+                FlagType::value("e", types.string()),
+            ],
+            args: vec![types.string()],
+            var_args: None,
+            ret: types.fs(),
+        },
+    }
 }
 
-struct Command{
-	types: fs::Types,
-	ctype: CommandType,
-};
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
 
 impl super::Command for Command {
     fn fs_type(&self) -> &super::CommandType {
-        return &self.ctype;
+        &self.ctype
     }
     fn start(
         &self,
         tasks: &mut super::Tasks,
-        _flags: Vec<String>,
+        flags: super::Flags,
         args: Vec<Value>,
-        _rest: Vec<Value>,
     ) -> fstream::Result<Value> {
         let mut args = args;
 
-        let path = self.types.to_string(args.pop().unwrap())?;
+        let path = self.types.to_string(args.pop().unwrap());
+        let errors = ErrorPolicy::parse(flags.string("e").as_deref())?;
+        let opts = Options {
+            follow: flags.has("L"),
+            max_depth: int_flag(&flags, "maxdepth", i32::MAX as i64)?.map(|n| n as i32),
+            min_depth: int_flag(&flags, "mindepth", i32::MAX as i64)?.unwrap_or(0) as i32,
+            one_file_system: flags.has("x"),
+            jobs: int_flag(&flags, "j", MAX_JOBS)?.unwrap_or(0) as usize,
+            order: if flags.has("U") {
+                fstream::Order::Unsorted
+            } else {
+                fstream::Order::Sorted
+            },
+            errors,
+        };
         let (send_root, recv_root) = fstream::new();
-        tasks.add(tokio::spawn(async {
-            // TODO avoid unwrap here.
-            walk(path, send_root).await.unwrap();
-            Ok(())
+        tasks.add(tokio::spawn(async move {
+            walk(path, send_root, opts).await.context(super::ErrWalk)
         }));
         Ok(self.types.from_fs(recv_root))
     }
 }
 
+// MAX_JOBS holds the largest value accepted for -j, which is far
+// more than is useful, and well within what a Semaphore can hold.
+const MAX_JOBS: i64 = 1024;
+
+// int_flag returns the value of the named flag,
+// which must be between 0 and max inclusive.
+fn int_flag(flags: &super::Flags, name: &str, max: i64) -> fstream::Result<Option<i64>> {
+    match flags.int(name) {
+        Some(n) if n < 0 || n > max => Err(fstream::ErrUsage {
+            msg: format!("-{} must be between 0 and {}, not {}", name, max, n),
+        }
+        .build()),
+        n => Ok(n),
+    }
+}
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("root path is not a directory"))]
     ErrNotDirectory,
+    #[snafu(display("{}", source))]
     ErrFstream {
         source: fstream::Error,
     },
+    #[snafu(display("{}: {}", path.display(), source))]
     ErrIO {
+        path: PathBuf,
         source: std::io::Error,
     },
+    #[snafu(display("{}", source))]
+    ErrTaskJoin {
+        source: task::JoinError,
+    },
+}
+
+// Options holds options that control a walk.
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    // follow specifies that symbolic links are followed.
+    // A link to a directory that's already being walked
+    // is sent as a symbolic link rather than followed.
+    pub follow: bool,
+    // max_depth holds the maximum depth of entry to send.
+    // Directories at that depth are sent but not read.
+    pub max_depth: Option<i32>,
+    // min_depth holds the minimum depth of non-directory entry to
+    // send. Directories above that depth are still sent so that
+    // the entries underneath them have somewhere to go.
+    pub min_depth: i32,
+    // one_file_system specifies that directories on a different
+    // device from the root are sent but not read.
+    pub one_file_system: bool,
+    // jobs holds the maximum number of directories that are read
+    // speculatively at once, before the receiver has asked for
+    // them. If it's zero, directories are only read when needed.
+    pub jobs: usize,
+    // order holds the order in which the entries of
+    // each directory are sent.
+    pub order: fstream::Order,
+    // errors holds what to do about parts of the
+    // file system that can't be read.
+    pub errors: ErrorPolicy,
+}
+
+// ErrorPolicy specifies what a walk does when part of the file
+// system can't be read, and what filter does when its selector fails.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    // Abort stops the command with the error.
+    #[default]
+    Abort,
+    // Report sends the error in the stream and carries on.
+    Report,
+    // Ignore carries on as if the unreadable
+    // parts didn't exist.
+    Ignore,
+}
+
+impl ErrorPolicy {
+    // parse returns the policy with the given name, as given to
+    // the -e flag: abort, report or ignore. The policy is abort
+    // when there's no name because the flag wasn't given.
+    pub fn parse(name: Option<&str>) -> fstream::Result<ErrorPolicy> {
+        match name {
+            None | Some("abort") => Ok(ErrorPolicy::Abort),
+            Some("report") => Ok(ErrorPolicy::Report),
+            Some("ignore") => Ok(ErrorPolicy::Ignore),
+            Some(policy) => Err(fstream::ErrUsage {
+                msg: format!("unknown error policy {:?}", policy),
+            }
+            .build()),
+        }
+    }
 }
 
 // walk walks the directory hierarchy rooted at the given path, sending the results to root.
-pub async fn walk<P: AsRef<std::path::Path>>(path_ref: P, root: fstream::SendRoot) -> Result<()> {
+pub async fn walk<P: AsRef<std::path::Path>>(
+    path_ref: P,
+    root: fstream::SendRoot,
+    opts: Options,
+) -> Result<()> {
     let mut path = std::path::PathBuf::new();
     path.push(path_ref.as_ref());
-    let d = std::fs::metadata(path_ref.as_ref()).context(ErrIO)?;
+    let d = tokio::fs::metadata(&path)
+        .await
+        .context(ErrIO { path: &path })?;
     if !d.is_dir() {
         return Err(ErrNotDirectory.build());
     }
-    Ok(
-        if let Some(dir) = root.dir(path.clone()).await.context(ErrFstream)? {
-            walk_dir(&mut path, dir).await?;
-        },
-    )
+    // ancestors holds the device and inode numbers of
+    // all the directories above the current one, so that
+    // we can avoid walking around cycles of symbolic links.
+    let mut ancestors = vec![(d.dev(), d.ino())];
+    let jobs = if opts.jobs > 0 {
+        Some(Arc::new(Semaphore::new(opts.jobs)))
+    } else {
+        None
+    };
+    if let Some(dir) = root
+        .dir(path.clone(), opts.order)
+        .await
+        .context(ErrFstream)?
+    {
+        walk_dir(&mut path, dir, &opts, &jobs, &mut ancestors, None).await?;
+    }
+    Ok(())
 }
 
 #[async_recursion]
 async fn walk_dir(
     path: &mut std::path::PathBuf,
     dir: fstream::SendDir,
+    opts: &Options,
+    jobs: &Option<Arc<Semaphore>>,
+    ancestors: &mut Vec<(u64, u64)>,
+    listing: Option<Prefetch>,
 ) -> Result<Option<fstream::SendDir>> {
     let mut dir = dir;
-    let mut paths: Vec<fstream::DirEntry> = vec![];
-    for entry in std::fs::read_dir(&path).context(ErrIO)? {
-        paths.push(entry.context(ErrIO)?);
+    let depth = dir.depth();
+    if opts.max_depth.is_some_and(|max| depth > max) {
+        return dir.end().await.context(ErrFstream);
     }
-    paths.sort_by_key(|entry| entry.path());
-    for entry in paths {
+    // upcoming holds the subdirectories that will be read if the
+    // receiver asks for them, and prefetched holds the speculative
+    // reads of the next few of them.
+    let mut upcoming: VecDeque<(usize, PathBuf, (u64, u64))> = VecDeque::new();
+    let mut prefetched: VecDeque<(usize, Prefetch)> = VecDeque::new();
+    // Reading a directory can block for a long time on a slow file
+    // system, so do it on a blocking thread rather than holding up
+    // the other tasks in the pipeline.
+    let mut entries = if opts.order == fstream::Order::Unsorted {
+        match stream_dir(path, opts.follow, ancestors).await {
+            Ok(stream) => Entries::Streamed(stream),
+            Err(err) => return unreadable(dir, err, opts.errors).await,
+        }
+    } else {
+        let paths = match listing {
+            Some(listing) => listing.wait().await,
+            None => {
+                let (dir_path, follow, ancestors1) = (path.clone(), opts.follow, ancestors.clone());
+                task::spawn_blocking(move || read_dir(&dir_path, follow, &ancestors1))
+                    .await
+                    .context(ErrTaskJoin)
+                    .and_then(|paths| paths)
+            }
+        };
+        let paths = match paths {
+            Ok(paths) => paths,
+            Err(err) => return unreadable(dir, err, opts.errors).await,
+        };
+        if let Some(jobs) = jobs {
+            if opts.max_depth.is_none_or(|max| depth < max) {
+                for (i, entry) in paths.iter().enumerate() {
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(_) => continue,
+                    };
+                    let id = (entry.dev, entry.ino);
+                    if entry.is_dir()
+                        && !ancestors.contains(&id)
+                        && !crosses_device(opts, ancestors, id)
+                    {
+                        upcoming.push_back((i, path.join(entry.file_name()), id));
+                    }
+                }
+            }
+            prefetch(jobs, opts, ancestors, &mut upcoming, &mut prefetched);
+        }
+        Entries::Listed(paths.into_iter())
+    };
+    let mut n = 0;
+    while let Some(entry) = entries.next().await {
+        let i = n;
+        n += 1;
+        // Take the speculative read of this entry if there is
+        // one; any others that have been passed over are
+        // cancelled when they're dropped.
+        let mut listing = None;
+        while let Some((j, _)) = prefetched.front() {
+            if *j > i {
+                break;
+            }
+            if *j == i {
+                listing = prefetched.pop_front().map(|(_, p)| p);
+            } else {
+                prefetched.pop_front();
+            }
+        }
+        if let Some(jobs) = jobs {
+            prefetch(jobs, opts, ancestors, &mut upcoming, &mut prefetched);
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                dir = report(dir, err, opts.errors).await?;
+                continue;
+            }
+        };
+        if !entry.is_dir() && depth < opts.min_depth {
+            continue;
+        }
         // We need to push the file name before calling the
         // dir method because we're handing off ownership
         // by doing that.
         path.push(entry.file_name());
         // Could use defer to pop the path here?
-        if entry.file_type().context(ErrIO)?.is_dir() {
+        if entry.is_dir() {
+            let id = (entry.dev, entry.ino);
             match dir.dir(entry).await.context(ErrFstream)? {
                 fstream::SendDirEntryAction::Down(child) => {
                     // Note: subdirectories will always return Some(dir)
                     // because None can only happen at the root and
                     // we know that the child is at least one level down.
-                    dir = walk_dir(path, child).await?.unwrap();
+                    if ancestors.contains(&id) {
+                        // The directory is its own ancestor without
+                        // any symbolic link involved (a bind mount, for
+                        // example), so send it as empty.
+                        dir = child.end().await.context(ErrFstream)?.unwrap();
+                    } else if crosses_device(opts, ancestors, id) {
+                        // The directory is a mount point.
+                        dir = child.end().await.context(ErrFstream)?.unwrap();
+                    } else {
+                        ancestors.push(id);
+                        dir = walk_dir(path, child, opts, jobs, ancestors, listing)
+                            .await?
+                            .unwrap();
+                        ancestors.pop();
+                    }
                 }
                 fstream::SendDirEntryAction::Next(next) => {
                     dir = next;
@@ -111,9 +356,17 @@ async fn walk_dir(
                 }
             }
         } else {
+            let is_file = entry.is_file();
             match dir.file(entry).await.context(ErrFstream)? {
                 fstream::SendFileEntryAction::Down(file) => {
-                    dir = walk_file(path, file).await?;
+                    // Never open anything but a regular file: opening
+                    // a FIFO can block forever and opening a device
+                    // can have side effects.
+                    dir = if is_file {
+                        walk_file(path, file, opts.errors).await?
+                    } else {
+                        file.end().await.context(ErrFstream)?
+                    };
                 }
                 fstream::SendFileEntryAction::Next(next) => dir = next,
                 fstream::SendFileEntryAction::Skip(parent) => {
@@ -128,29 +381,832 @@ async fn walk_dir(
         }
         path.pop();
     }
-    Ok(dir.end().await.context(ErrFstream)?)
+    dir.end().await.context(ErrFstream)
+}
+
+// report deals with an error reading part of the file
+// system as directed by policy, returning the directory
+// to carry on sending to.
+async fn report(
+    dir: fstream::SendDir,
+    err: Error,
+    policy: ErrorPolicy,
+) -> Result<fstream::SendDir> {
+    match (policy, err) {
+        (ErrorPolicy::Ignore, Error::ErrIO { .. }) => Ok(dir),
+        (ErrorPolicy::Report, Error::ErrIO { path, source }) => dir
+            .error(fstream::ErrorEntry::new(path, &source))
+            .await
+            .context(ErrFstream),
+        (_, err) => Err(err),
+    }
+}
+
+// unreadable deals with err, an error opening dir for reading, as
+// directed by policy. Unless the walk is aborted, the directory is
+// ended, empty, and the error is dealt with in its parent, so that
+// an error follows the entry that it's about. There's no parent
+// to report an error reading the root in, so that's only carried
+// on from when errors are ignored.
+async fn unreadable(
+    dir: fstream::SendDir,
+    err: Error,
+    policy: ErrorPolicy,
+) -> Result<Option<fstream::SendDir>> {
+    if policy == ErrorPolicy::Abort {
+        return Err(err);
+    }
+    match dir.end().await.context(ErrFstream)? {
+        Some(parent) => Ok(Some(report(parent, err, policy).await?)),
+        None if policy == ErrorPolicy::Ignore => Ok(None),
+        None => Err(err),
+    }
+}
+
+// crosses_device reports whether the directory with the given
+// device and inode numbers shouldn't be read because it's on
+// a different file system from the root.
+fn crosses_device(opts: &Options, ancestors: &[(u64, u64)], id: (u64, u64)) -> bool {
+    opts.one_file_system && id.0 != ancestors[0].0
+}
+
+// Prefetch represents a speculative read of a directory. If it's
+// dropped before the read has started, the read is cancelled; a read
+// that has already started runs to completion on its blocking thread
+// and its result is discarded.
+struct Prefetch(task::JoinHandle<Result<Vec<Result<fstream::DirEntry>>>>);
+
+impl Prefetch {
+    // wait waits for the read to finish and returns its result.
+    async fn wait(mut self) -> Result<Vec<Result<fstream::DirEntry>>> {
+        (&mut self.0).await.context(ErrTaskJoin)?
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// prefetch starts speculative reads of directories from the front
+// of upcoming, adding them to prefetched, until the number in
+// prefetched reaches the limit held by jobs. The jobs semaphore
+// also limits the number of reads in progress across all levels of
+// the walk.
+fn prefetch(
+    jobs: &Arc<Semaphore>,
+    opts: &Options,
+    ancestors: &[(u64, u64)],
+    upcoming: &mut VecDeque<(usize, PathBuf, (u64, u64))>,
+    prefetched: &mut VecDeque<(usize, Prefetch)>,
+) {
+    while prefetched.len() < opts.jobs {
+        let (i, path, id) = match upcoming.pop_front() {
+            Some(next) => next,
+            None => return,
+        };
+        let jobs = jobs.clone();
+        let follow = opts.follow;
+        let mut ancestors = ancestors.to_vec();
+        ancestors.push(id);
+        let handle = tokio::spawn(async move {
+            // Note: the semaphore is never closed.
+            let permit = jobs.acquire_owned().await.unwrap();
+            task::spawn_blocking(move || {
+                // Hold the permit until the read finishes, even if
+                // the prefetch is dropped in the meantime, so that
+                // jobs really does bound the reads in progress.
+                let _permit = permit;
+                read_dir(&path, follow, &ancestors)
+            })
+            .await
+            .context(ErrTaskJoin)?
+        });
+        prefetched.push_back((i, Prefetch(handle)));
+    }
+}
+
+// Entries holds the entries of a directory that are yet to be sent.
+enum Entries {
+    // Listed holds all the entries, read in advance.
+    Listed(std::vec::IntoIter<Result<fstream::DirEntry>>),
+    // Streamed holds a stream that delivers the entries
+    // as they're read.
+    Streamed(DirStream),
+}
+
+impl Entries {
+    // next returns the next entry or the error from reading it,
+    // or None if there are no more.
+    async fn next(&mut self) -> Option<Result<fstream::DirEntry>> {
+        match self {
+            Entries::Listed(entries) => entries.next(),
+            Entries::Streamed(stream) => stream.next().await,
+        }
+    }
+}
+
+// ENTRY_BATCH holds the number of entries that are read from
+// a directory at a time when streaming.
+const ENTRY_BATCH: usize = 64;
+
+// stream_dir opens the directory at path and starts reading its
+// entries, returning a stream that delivers them in the order
+// they're read.
+async fn stream_dir(path: &Path, follow: bool, ancestors: &[(u64, u64)]) -> Result<DirStream> {
+    let path = path.to_path_buf();
+    let ancestors = ancestors.to_vec();
+    let reader = task::spawn_blocking(move || {
+        let dir = std::fs::read_dir(&path).context(ErrIO { path: &path })?;
+        Ok(DirReader {
+            dir,
+            path,
+            follow,
+            ancestors,
+        })
+    })
+    .await
+    .context(ErrTaskJoin)??;
+    Ok(DirStream {
+        batch: vec![].into_iter(),
+        next: Some(task::spawn_blocking(move || reader.read_batch())),
+    })
+}
+
+// DirStream delivers the entries of a directory as they're read.
+// Each batch of entries is read on a blocking thread while the one
+// before it is being sent, but no thread waits for the receiver,
+// so the walk doesn't tie up a thread for every directory that's
+// being streamed, which could use up the blocking thread pool and
+// stop files from being read. When the stream is dropped, at most
+// one more batch is read.
+struct DirStream {
+    // batch holds the entries that are ready to send.
+    batch: std::vec::IntoIter<Result<fstream::DirEntry>>,
+    // next holds the read of the next batch, or None
+    // if all the entries have been read.
+    next: Option<task::JoinHandle<Batch>>,
+}
+
+// Batch holds the result of DirReader::read_batch.
+type Batch = (Option<DirReader>, Vec<Result<fstream::DirEntry>>);
+
+impl DirStream {
+    // next returns the next entry or the error from reading it,
+    // or None if there are no more.
+    async fn next(&mut self) -> Option<Result<fstream::DirEntry>> {
+        loop {
+            if let Some(entry) = self.batch.next() {
+                return Some(entry);
+            }
+            let (reader, batch) = match self.next.take()?.await {
+                Ok(next) => next,
+                Err(err) => return Some(Err(ErrTaskJoin.into_error(err))),
+            };
+            self.next = reader.map(|reader| task::spawn_blocking(move || reader.read_batch()));
+            self.batch = batch.into_iter();
+        }
+    }
+}
+
+// DirReader reads the entries of an open directory.
+struct DirReader {
+    dir: std::fs::ReadDir,
+    path: PathBuf,
+    follow: bool,
+    ancestors: Vec<(u64, u64)>,
+}
+
+impl DirReader {
+    // read_batch reads up to ENTRY_BATCH entries. It returns
+    // the reader too unless there are no more entries to read.
+    fn read_batch(mut self) -> Batch {
+        let mut batch = Vec::with_capacity(ENTRY_BATCH);
+        while batch.len() < ENTRY_BATCH {
+            match self.dir.next() {
+                Some(entry) => batch.push(
+                    entry
+                        .context(ErrIO { path: &self.path })
+                        .and_then(|entry| read_entry(&entry, self.follow, &self.ancestors)),
+                ),
+                None => return (None, batch),
+            }
+        }
+        (Some(self), batch)
+    }
+}
+
+// read_dir reads all the entries in the directory at path,
+// sorted by the bytes of their names. Any errors reading
+// them follow the entries; it fails only if the directory
+// can't be opened.
+fn read_dir(
+    path: &Path,
+    follow: bool,
+    ancestors: &[(u64, u64)],
+) -> Result<Vec<Result<fstream::DirEntry>>> {
+    let dir = std::fs::read_dir(path).context(ErrIO { path })?;
+    let mut entries = vec![];
+    let mut errors = vec![];
+    for entry in dir {
+        match entry
+            .context(ErrIO { path })
+            .and_then(|entry| read_entry(&entry, follow, ancestors))
+        {
+            Ok(entry) => entries.push(entry),
+            Err(err) => errors.push(Err(err)),
+        }
+    }
+    entries.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+    Ok(entries.into_iter().map(Ok).chain(errors).collect())
+}
+
+// read_entry reads the information about a directory entry.
+// Symbolic links are followed if follow is true and the link
+// target exists, except for links to any of the given ancestor
+// directories.
+fn read_entry(
+    entry: &std::fs::DirEntry,
+    follow: bool,
+    ancestors: &[(u64, u64)],
+) -> Result<fstream::DirEntry> {
+    let path = entry.path();
+    let md = entry.metadata().context(ErrIO { path: &path })?;
+    if follow && md.file_type().is_symlink() {
+        if let Ok(md) = std::fs::metadata(&path) {
+            // Don't follow a link back to a directory
+            // that we're already in.
+            if !(md.is_dir() && ancestors.contains(&(md.dev(), md.ino()))) {
+                return Ok(fstream::DirEntry::from_metadata(entry.file_name(), &md));
+            }
+        }
+    }
+    let mut result = fstream::DirEntry::from_metadata(entry.file_name(), &md);
+    if result.kind == fstream::Kind::Symlink {
+        result.link = Some(std::fs::read_link(&path).context(ErrIO { path: &path })?);
+    }
+    Ok(result)
 }
 
 const BLOCK_SIZE: usize = 8192;
 
+// READ_AHEAD holds the number of blocks that are read from
+// a file ahead of the block that's being sent.
+const READ_AHEAD: usize = 4;
+
+// walk_file sends the contents of the file at path. An error
+// reading it ends the file and is dealt with as directed by errors.
 pub async fn walk_file(
-    path: &mut std::path::PathBuf,
+    path: &Path,
     file: fstream::SendFile,
+    errors: ErrorPolicy,
 ) -> Result<fstream::SendDir> {
     let mut file = file;
-    let mut f = std::fs::File::open(path).context(ErrIO)?;
+    let (tx, mut rx) = mpsc::channel(READ_AHEAD);
+    let file_path = path.to_path_buf();
+    let reader = task::spawn_blocking(move || read_file(&file_path, tx));
+    loop {
+        match rx.recv().await {
+            Some(Ok(data)) => match file.data(data).await.context(ErrFstream)? {
+                fstream::SendFileAction::Next(next) => {
+                    file = next;
+                }
+                // Note: the reader stops when it finds that rx
+                // has been dropped.
+                fstream::SendFileAction::Skip(dir) => return Ok(dir),
+            },
+            Some(Err(err)) => {
+                let err = ErrIO { path }.into_error(err);
+                if errors == ErrorPolicy::Abort {
+                    return Err(err);
+                }
+                let dir = file.end().await.context(ErrFstream)?;
+                return report(dir, err, errors).await;
+            }
+            None => {
+                reader.await.context(ErrTaskJoin)?;
+                return file.end().await.context(ErrFstream);
+            }
+        }
+    }
+}
+
+// read_file reads the file at path in blocks, sending each one
+// on tx until the end of the file, an error or until the
+// receiver is dropped.
+fn read_file(path: &Path, tx: mpsc::Sender<std::io::Result<Vec<u8>>>) {
+    let mut f = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(err) => {
+            let _ = tx.blocking_send(Err(err));
+            return;
+        }
+    };
     loop {
         let mut data = vec![0; BLOCK_SIZE];
-        let n = f.read(&mut data).context(ErrIO)?;
-        if n == 0 {
-            return Ok(file.end().await.context(ErrFstream)?);
+        let result = match f.read(&mut data) {
+            Ok(0) => return,
+            Ok(n) => {
+                data.truncate(n);
+                Ok(data)
+            }
+            Err(err) => Err(err),
+        };
+        let failed = result.is_err();
+        if tx.blocking_send(result).is_err() || failed {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LONG_NAME is long enough that a few levels of directories
+    // with this name make a path that's too long to open.
+    const LONG_NAME: &str = "nnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnn";
+
+    // unreadable_tree creates a tree under base holding a file a, a
+    // directory d and a file z, and returns the path of its root. The
+    // directory d holds a chain of directories whose path grows too
+    // long for the deepest of them to be opened, which makes it
+    // unreadable regardless of the permissions of the user running
+    // the tests. The chain is created through symbolic links outside
+    // the tree so that no path used to create it is too long.
+    fn unreadable_tree(base: &Path) -> PathBuf {
+        let _ = std::fs::remove_dir_all(base);
+        let root = base.join("t");
+        let links = base.join("links");
+        std::fs::create_dir_all(root.join("d")).unwrap();
+        std::fs::create_dir(&links).unwrap();
+        std::fs::write(root.join("a"), "a").unwrap();
+        std::fs::write(root.join("z"), "z").unwrap();
+        std::os::unix::fs::symlink(root.join("d"), links.join("l0")).unwrap();
+        let mut len = root.join("d").as_os_str().len();
+        let mut i = 0;
+        while len < 4096 {
+            std::fs::create_dir(links.join(format!("l{}", i)).join(LONG_NAME)).unwrap();
+            std::os::unix::fs::symlink(
+                format!("l{}/{}", i, LONG_NAME),
+                links.join(format!("l{}", i + 1)),
+            )
+            .unwrap();
+            len += LONG_NAME.len() + 1;
+            i += 1;
+        }
+        root
+    }
+
+    // receive receives the whole of root, descending into every
+    // directory, and returns a line for each record: the kind and
+    // name of an entry, "end" or "error".
+    async fn receive(root: fstream::RecvRoot) -> fstream::Result<Vec<String>> {
+        let mut records = vec![];
+        let (_, mut dir) = root.dir().await?;
+        loop {
+            dir = match dir.entry().await? {
+                fstream::RecvEntry::Dir(entry, action) => {
+                    records.push(format!("d {}", short_name(&entry)));
+                    action.down().await?
+                }
+                fstream::RecvEntry::File(entry, action) => {
+                    records.push(format!("f {}", short_name(&entry)));
+                    action.next().await?
+                }
+                fstream::RecvEntry::Error(_, dir) => {
+                    records.push("error".to_string());
+                    dir
+                }
+                fstream::RecvEntry::End(dir) => {
+                    records.push("end".to_string());
+                    match dir {
+                        Some(dir) => dir,
+                        None => return Ok(records),
+                    }
+                }
+            }
+        }
+    }
+
+    fn short_name(entry: &fstream::DirEntry) -> String {
+        if entry.name == LONG_NAME {
+            "long".to_string()
+        } else {
+            entry.name.to_string_lossy().into_owned()
+        }
+    }
+
+    async fn walk_unreadable(name: &str, opts: Options) -> (Result<()>, Vec<String>) {
+        let base =
+            std::env::temp_dir().join(format!("fstream-walk-test-{}-{}", std::process::id(), name));
+        let root = unreadable_tree(&base);
+        let (send_root, recv_root) = fstream::new();
+        let receiver = tokio::spawn(receive(recv_root));
+        let result = walk(&root, send_root, opts).await;
+        let records = receiver.await.unwrap().unwrap_or_default();
+        std::fs::remove_dir_all(&base).unwrap();
+        (result, records)
+    }
+
+    // check_records checks the records from walking an unreadable
+    // tree: the unreadable directory, as the last of a chain of
+    // directories, is ended straight away and is followed by an
+    // error if report is true, and the walk carries on after it.
+    fn check_records(records: &[String], report: bool) {
+        let mut want = vec!["f a", "d d"];
+        let depth = records.iter().filter(|r| *r == "d long").count();
+        assert!(depth > 1, "{:?}", records);
+        want.extend(std::iter::repeat_n("d long", depth));
+        want.push("end");
+        if report {
+            want.push("error");
+        }
+        want.extend(std::iter::repeat_n("end", depth));
+        want.extend(&["f z", "end"]);
+        assert_eq!(records, &want[..]);
+    }
+
+    #[tokio::test]
+    async fn walk_abort() {
+        let (result, records) = walk_unreadable("abort", Options::default()).await;
+        let err = result.unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("File name too long (os error 36)"),
+            "{}",
+            err
+        );
+        assert!(records.is_empty());
+    }
+
+    #[tokio::test]
+    async fn walk_report() {
+        for (i, (order, jobs)) in [
+            (fstream::Order::Sorted, 0),
+            (fstream::Order::Sorted, 2),
+            (fstream::Order::Unsorted, 0),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let opts = Options {
+                order: *order,
+                jobs: *jobs,
+                errors: ErrorPolicy::Report,
+                ..Options::default()
+            };
+            let (result, mut records) = walk_unreadable(&format!("report{}", i), opts).await;
+            result.unwrap();
+            if *order == fstream::Order::Unsorted {
+                sort_top_level(&mut records);
+            }
+            check_records(&records, true);
+        }
+    }
+
+    #[tokio::test]
+    async fn walk_ignore() {
+        let opts = Options {
+            errors: ErrorPolicy::Ignore,
+            ..Options::default()
+        };
+        let (result, records) = walk_unreadable("ignore", opts).await;
+        result.unwrap();
+        check_records(&records, false);
+    }
+
+    // sort_top_level puts the files at the top level of records from
+    // an unsorted walk of an unreadable tree in their sorted places.
+    fn sort_top_level(records: &mut Vec<String>) {
+        let files: Vec<String> = records
+            .iter()
+            .filter(|r| *r == "f a" || *r == "f z")
+            .cloned()
+            .collect();
+        assert_eq!(files.len(), 2, "{:?}", records);
+        records.retain(|r| r != "f a" && r != "f z");
+        records.insert(0, "f a".to_string());
+        let end = records.len() - 1;
+        records.insert(end, "f z".to_string());
+    }
+
+    fn temp_tree(name: &str) -> PathBuf {
+        let base =
+            std::env::temp_dir().join(format!("fstream-walk-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir(&base).unwrap();
+        base
+    }
+
+    // small_tree creates a file a, a directory d holding a file b,
+    // and a directory d/e holding a file c underneath base.
+    fn small_tree(name: &str) -> PathBuf {
+        let base = temp_tree(name);
+        std::fs::create_dir_all(base.join("d/e")).unwrap();
+        std::fs::write(base.join("a"), "a").unwrap();
+        std::fs::write(base.join("d/b"), "b").unwrap();
+        std::fs::write(base.join("d/e/c"), "c").unwrap();
+        base
+    }
+
+    // receive_paths is like receive, but it records the path of each
+    // entry relative to the root, and doesn't descend into the
+    // directories with the given paths. It also returns the order
+    // recorded in the root.
+    async fn receive_paths(
+        root: fstream::RecvRoot,
+        skip: &[&str],
+    ) -> fstream::Result<(fstream::Order, Vec<String>)> {
+        let mut records = vec![];
+        let (_, mut dir) = root.dir().await?;
+        let order = dir.order();
+        let mut path = PathBuf::new();
+        loop {
+            dir = match dir.entry().await? {
+                fstream::RecvEntry::Dir(entry, action) => {
+                    let p = path.join(entry.file_name());
+                    records.push(format!("d {}", p.display()));
+                    if skip.contains(&p.to_str().unwrap()) {
+                        action.next().await?
+                    } else {
+                        path = p;
+                        action.down().await?
+                    }
+                }
+                fstream::RecvEntry::File(entry, action) => {
+                    records.push(format!("f {}", path.join(entry.file_name()).display()));
+                    action.next().await?
+                }
+                fstream::RecvEntry::Error(_, dir) => {
+                    records.push("error".to_string());
+                    dir
+                }
+                fstream::RecvEntry::End(dir) => {
+                    records.push("end".to_string());
+                    path.pop();
+                    match dir {
+                        Some(dir) => dir,
+                        None => return Ok((order, records)),
+                    }
+                }
+            }
+        }
+    }
+
+    async fn walk_paths(
+        root: &Path,
+        opts: Options,
+        skip: &[&str],
+    ) -> (fstream::Order, Vec<String>) {
+        let (send_root, recv_root) = fstream::new();
+        let skip: Vec<String> = skip.iter().map(|s| s.to_string()).collect();
+        let receiver = tokio::spawn(async move {
+            let skip: Vec<&str> = skip.iter().map(|s| s.as_str()).collect();
+            receive_paths(recv_root, &skip).await
+        });
+        walk(root, send_root, opts).await.unwrap();
+        receiver.await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn walk_max_depth() {
+        let base = small_tree("maxdepth");
+        for &(max_depth, want) in &[
+            (0, &["end"][..]),
+            (1, &["f a", "d d", "end", "end"]),
+            (2, &["f a", "d d", "f d/b", "d d/e", "end", "end", "end"]),
+            (
+                3,
+                &[
+                    "f a", "d d", "f d/b", "d d/e", "f d/e/c", "end", "end", "end",
+                ],
+            ),
+        ] {
+            let opts = Options {
+                max_depth: Some(max_depth),
+                ..Options::default()
+            };
+            let (_, records) = walk_paths(&base, opts, &[]).await;
+            assert_eq!(records, want, "{}", max_depth);
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn walk_min_depth() {
+        let base = small_tree("mindepth");
+        for &(min_depth, want) in &[
+            (
+                1,
+                &[
+                    "f a", "d d", "f d/b", "d d/e", "f d/e/c", "end", "end", "end",
+                ][..],
+            ),
+            (
+                2,
+                &["d d", "f d/b", "d d/e", "f d/e/c", "end", "end", "end"],
+            ),
+            (3, &["d d", "d d/e", "f d/e/c", "end", "end", "end"]),
+        ] {
+            let opts = Options {
+                min_depth,
+                ..Options::default()
+            };
+            let (_, records) = walk_paths(&base, opts, &[]).await;
+            assert_eq!(records, want, "{}", min_depth);
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn walk_depth_out_of_range() {
+        for &(expr, want) in &[
+            (
+                "walk -maxdepth=-1 /",
+                "-maxdepth must be between 0 and 2147483647, not -1",
+            ),
+            (
+                "walk -maxdepth=4294967297 /",
+                "-maxdepth must be between 0 and 2147483647, not 4294967297",
+            ),
+            (
+                "walk -mindepth=-1 /",
+                "-mindepth must be between 0 and 2147483647, not -1",
+            ),
+        ] {
+            assert!(usage_error(expr).await.contains(want), "{}", expr);
+        }
+    }
+
+    #[tokio::test]
+    async fn walk_one_file_system() {
+        // /proc is on a file system of its own, so a link to it
+        // that's followed leads to another file system.
+        let base = temp_tree("one-file-system");
+        let proc_dev = std::fs::metadata("/proc").map(|md| md.dev());
+        if proc_dev.ok() == Some(std::fs::metadata(&base).unwrap().dev()) {
+            return;
         }
-        data.truncate(n);
-        match file.data(data).await.context(ErrFstream)? {
-            fstream::SendFileAction::Next(next) => {
-                file = next;
+        std::fs::write(base.join("a"), "a").unwrap();
+        std::os::unix::fs::symlink("/proc", base.join("p")).unwrap();
+        let opts = Options {
+            follow: true,
+            one_file_system: true,
+            ..Options::default()
+        };
+        let (_, records) = walk_paths(&base, opts, &[]).await;
+        assert_eq!(records, ["f a", "d p", "end", "end"]);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    // wide_tree creates n directories underneath base, each holding
+    // n directories that each hold a file.
+    fn wide_tree(name: &str, n: usize) -> PathBuf {
+        let base = temp_tree(name);
+        for i in 0..n {
+            for j in 0..n {
+                let dir = base.join(format!("d{}/s{}", i, j));
+                std::fs::create_dir_all(&dir).unwrap();
+                std::fs::write(dir.join("f"), "f").unwrap();
+            }
+        }
+        base
+    }
+
+    #[tokio::test]
+    async fn walk_jobs() {
+        let base = wide_tree("jobs", 6);
+        for &(skip, n) in &[
+            (&[][..], 1 + 6 * 2 + 36 * 3),
+            (
+                &["d1", "d3/s2", "d5/s0", "d5/s5"],
+                1 + 6 * 2 + 36 * 3 - 19 - 3 * 2,
+            ),
+        ] {
+            let (_, want) = walk_paths(&base, Options::default(), skip).await;
+            assert_eq!(want.len(), n);
+            for &jobs in &[1, 3, 100] {
+                let opts = Options {
+                    jobs,
+                    ..Options::default()
+                };
+                let (_, records) = walk_paths(&base, opts, skip).await;
+                assert_eq!(records, want, "{} {:?}", jobs, skip);
+            }
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    // usage_error returns the error from running the given expression,
+    // which is expected to be a usage error.
+    async fn usage_error(expr: &str) -> String {
+        match crate::Engine::new().run(expr).await {
+            Err(crate::Error::ErrFstream {
+                source: err @ fstream::Error::ErrUsage { .. },
+            }) => err.to_string(),
+            result => panic!("{}: unexpected result {:?}", expr, result),
+        }
+    }
+
+    #[tokio::test]
+    async fn walk_jobs_out_of_range() {
+        for &(expr, want) in &[
+            ("walk -j=-1 /", "-j must be between 0 and 1024, not -1"),
+            ("walk -j=1025 /", "-j must be between 0 and 1024, not 1025"),
+            (
+                "walk -j=18446744073709551 /",
+                "-j must be between 0 and 1024, not 18446744073709551",
+            ),
+        ] {
+            assert!(usage_error(expr).await.contains(want), "{}", expr);
+        }
+    }
+
+    #[tokio::test]
+    async fn prefetch_cancelled() {
+        // Hold the only permit so that the prefetch can't start.
+        let jobs = Arc::new(Semaphore::new(1));
+        let permit = jobs.clone().acquire_owned().await.unwrap();
+        let opts = Options {
+            jobs: 1,
+            ..Options::default()
+        };
+        let mut upcoming = VecDeque::new();
+        upcoming.push_back((0, PathBuf::from("/"), (0, 0)));
+        let mut prefetched = VecDeque::new();
+        prefetch(&jobs, &opts, &[], &mut upcoming, &mut prefetched);
+        assert_eq!(prefetched.len(), 1);
+        assert_eq!(Arc::strong_count(&jobs), 3);
+        // As when the receiver skips the directory, drop the prefetch.
+        // Its task goes away, along with its reference to the
+        // semaphore, without ever getting the permit.
+        prefetched.clear();
+        for _ in 0..100 {
+            if Arc::strong_count(&jobs) == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(Arc::strong_count(&jobs), 2);
+        drop(permit);
+        assert_eq!(jobs.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn walk_unsorted() {
+        let base = wide_tree("unsorted", 3);
+        for i in 0..3 * ENTRY_BATCH {
+            std::fs::write(base.join(format!("f{}", i)), "").unwrap();
+            std::fs::write(base.join(format!("d1/f{}", i)), "").unwrap();
+        }
+        let (order, mut want) = walk_paths(&base, Options::default(), &["d2"]).await;
+        assert_eq!(order, fstream::Order::Sorted);
+        let opts = Options {
+            order: fstream::Order::Unsorted,
+            ..Options::default()
+        };
+        let (order, mut records) = walk_paths(&base, opts, &["d2"]).await;
+        assert_eq!(order, fstream::Order::Unsorted);
+        // Each directory ends after all its entries, so compare
+        // everything but the ends regardless of order.
+        assert_eq!(records.iter().filter(|r| *r == "end").count(), 1 + 2 + 6);
+        records.sort();
+        want.sort();
+        assert_eq!(records, want);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn walk_unsorted_few_threads() {
+        // Streaming a deep tree of large directories
+        // doesn't need a blocking thread for each level.
+        let base = temp_tree("unsorted-deep");
+        let mut dir = base.clone();
+        for _ in 0..10 {
+            for i in 0..2 * ENTRY_BATCH {
+                std::fs::write(dir.join(format!("f{}", i)), "").unwrap();
             }
-            fstream::SendFileAction::Skip(dir) => return Ok(dir),
+            dir.push("s");
+            std::fs::create_dir(&dir).unwrap();
         }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .max_blocking_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        let opts = Options {
+            order: fstream::Order::Unsorted,
+            ..Options::default()
+        };
+        let (_, records) = runtime.block_on(async {
+            let walk = walk_paths(&base, opts, &[]);
+            tokio::time::timeout(std::time::Duration::from_secs(30), walk)
+                .await
+                .expect("walk timed out")
+        });
+        assert_eq!(records.len(), 10 * (2 * ENTRY_BATCH + 2) + 1);
+        std::fs::remove_dir_all(&base).unwrap();
     }
 }
@@ -0,0 +1,427 @@
+use super::fstream;
+use async_recursion::async_recursion;
+use snafu::{ResultExt, Snafu};
+use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use super::fs;
+use super::CommandType;
+use super::FlagType;
+use super::Value;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    ErrFstream { source: fstream::Error },
+    #[snafu(display("{}: {}", path.display(), source))]
+    ErrIO {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("{}: invalid entry name {:?}", dir.display(), name))]
+    ErrName { dir: PathBuf, name: OsString },
+}
+
+// write writes all the files and directories in its input stream
+// underneath the destination directory, creating it if needed.
+// With the -m flag, the permissions of files and directories are
+// preserved; with the -t flag, their modification times are.
+// Devices, FIFOs and sockets are not written. An existing directory
+// is written into, and an existing file or symbolic link is replaced;
+// symbolic links are never followed, so nothing is written outside the
+// destination. It's an error if the name of an entry isn't a single
+// path component.
+pub fn new_command(types: &fs::Types) -> impl super::Command {
+    Command {
+        types: types.clone(),
+        ctype: CommandType {
+            flags: vec![FlagType::bool("m"), FlagType::bool("t")],
+            args: vec![types.fs(), types.string()],
+            var_args: None,
+            ret: types.void(),
+        },
+    }
+}
+
+struct Command {
+    types: fs::Types,
+    ctype: CommandType,
+}
+
+impl super::Command for Command {
+    fn fs_type(&self) -> &super::CommandType {
+        &self.ctype
+    }
+    fn start(
+        &self,
+        tasks: &mut super::Tasks,
+        flags: super::Flags,
+        args: Vec<Value>,
+    ) -> fstream::Result<Value> {
+        let mut args = args;
+        let dest = self.types.to_string(args.pop().unwrap());
+        let root = self.types.to_fs(args.pop().unwrap());
+        let opts = Options {
+            mode: flags.has("m"),
+            mtime: flags.has("t"),
+        };
+        tasks.add(tokio::spawn(async move {
+            write(root, dest, opts).await.context(super::ErrWrite)
+        }));
+        Ok(self.types.from_void())
+    }
+}
+
+// Options holds the attributes that write preserves.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Options {
+    // mode specifies that permissions are preserved.
+    pub mode: bool,
+    // mtime specifies that modification times are preserved.
+    pub mtime: bool,
+}
+
+// write writes the contents of root underneath dest.
+pub async fn write<P: AsRef<Path>>(root: fstream::RecvRoot, dest: P, opts: Options) -> Result<()> {
+    let mut path = dest.as_ref().to_path_buf();
+    std::fs::create_dir_all(&path).context(ErrIO { path: &path })?;
+    let (_, dir) = root.dir().await.context(ErrFstream)?;
+    write_dir(&mut path, dir, opts).await?;
+    Ok(())
+}
+
+#[async_recursion]
+async fn write_dir(
+    path: &mut PathBuf,
+    dir: fstream::RecvDir,
+    opts: Options,
+) -> Result<Option<fstream::RecvDir>> {
+    let mut dir = dir;
+    loop {
+        match dir.entry().await.context(ErrFstream)? {
+            fstream::RecvEntry::Dir(entry, action) => {
+                push_name(path, &entry)?;
+                if !is_dir(path) {
+                    replace(path, |path| std::fs::create_dir(path))?;
+                }
+                let child = action.down().await.context(ErrFstream)?;
+                // Note: subdirectories will always return Some(dir)
+                // because None can only happen at the root and
+                // we know that the child is at least one level down.
+                dir = write_dir(path, child, opts).await?.unwrap();
+                // Set the attributes after writing the contents
+                // so that writing doesn't change the modification time
+                // and a read-only directory can still be written.
+                set_attrs(path, &entry, opts)?;
+                path.pop();
+            }
+            fstream::RecvEntry::File(entry, action) => {
+                push_name(path, &entry)?;
+                match entry.kind {
+                    fstream::Kind::File => {
+                        let file = action.down().await.context(ErrFstream)?;
+                        dir = write_file(path, file).await?;
+                        set_attrs(path, &entry, opts)?;
+                    }
+                    fstream::Kind::Symlink => {
+                        if let Some(link) = &entry.link {
+                            replace(path, |path| std::os::unix::fs::symlink(link, path))?;
+                        }
+                        dir = action.next().await.context(ErrFstream)?;
+                    }
+                    _ => {
+                        dir = action.next().await.context(ErrFstream)?;
+                    }
+                }
+                path.pop();
+            }
+            fstream::RecvEntry::Error(err, dir1) => {
+                // There's nothing to write, but don't
+                // let the error go unnoticed.
+                eprintln!("{}", err);
+                dir = dir1;
+            }
+            fstream::RecvEntry::End(dir) => {
+                return Ok(dir);
+            }
+        }
+    }
+}
+
+async fn write_file(path: &Path, file: fstream::RecvFile) -> Result<fstream::RecvDir> {
+    let mut f = replace(path, |path| {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+    })?;
+    let mut file = file;
+    loop {
+        match file.data().await.context(ErrFstream)? {
+            fstream::RecvData::Bytes(data, file1) => {
+                f.write_all(&data).context(ErrIO { path })?;
+                file = file1;
+            }
+            fstream::RecvData::End(dir) => {
+                return Ok(dir);
+            }
+        }
+    }
+}
+
+// push_name pushes the name of entry onto path. It fails if the name
+// isn't a single normal path component, because the entry would then
+// be written somewhere else, possibly outside the destination.
+fn push_name(path: &mut PathBuf, entry: &fstream::DirEntry) -> Result<()> {
+    let name = entry.file_name();
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') {
+        return ErrName {
+            dir: path.clone(),
+            name,
+        }
+        .fail();
+    }
+    path.push(name);
+    Ok(())
+}
+
+// is_dir reports whether there's a directory at path,
+// without following a symbolic link.
+fn is_dir(path: &Path) -> bool {
+    std::fs::symlink_metadata(path)
+        .map(|m| m.is_dir())
+        .unwrap_or(false)
+}
+
+// replace calls create to create a new entry at path. If there's
+// already a file or symbolic link there, it's removed and create is
+// called again, so a symbolic link is replaced rather than followed.
+fn replace<T, F>(path: &Path, create: F) -> Result<T>
+where
+    F: Fn(&Path) -> std::io::Result<T>,
+{
+    match create(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists && !is_dir(path) => {
+            std::fs::remove_file(path).context(ErrIO { path })?;
+            create(path).context(ErrIO { path })
+        }
+        result => result.context(ErrIO { path }),
+    }
+}
+
+// set_attrs sets the attributes of the file or directory at path
+// from entry as specified by opts.
+fn set_attrs(path: &Path, entry: &fstream::DirEntry, opts: Options) -> Result<()> {
+    if opts.mtime {
+        let f = std::fs::File::open(path).context(ErrIO { path })?;
+        f.set_modified(entry.mtime).context(ErrIO { path })?;
+    }
+    if opts.mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode))
+            .context(ErrIO { path })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::walk;
+    use std::os::unix::fs::MetadataExt;
+    use std::time::{Duration, SystemTime};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "fstream-write-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    // send sends a root directory holding the given entries, with
+    // data "x" in each regular file. Directories are empty.
+    async fn send(root: fstream::SendRoot, entries: Vec<fstream::DirEntry>) -> fstream::Result<()> {
+        let mut dir = root
+            .dir(PathBuf::from("t"), fstream::Order::Sorted)
+            .await?
+            .unwrap();
+        for entry in entries {
+            if entry.is_dir() {
+                dir = match dir.dir(entry).await? {
+                    fstream::SendDirEntryAction::Down(child) => child.end().await?.unwrap(),
+                    fstream::SendDirEntryAction::Next(dir) => dir,
+                    _ => return Ok(()),
+                };
+                continue;
+            }
+            dir = match dir.file(entry).await? {
+                fstream::SendFileEntryAction::Down(file) => match file.data(b"x".to_vec()).await? {
+                    fstream::SendFileAction::Next(file) => file.end().await?,
+                    fstream::SendFileAction::Skip(dir) => dir,
+                },
+                fstream::SendFileEntryAction::Next(dir) => dir,
+                _ => return Ok(()),
+            };
+        }
+        dir.end().await?;
+        Ok(())
+    }
+
+    async fn write_entries(
+        dest: &Path,
+        entries: Vec<fstream::DirEntry>,
+        opts: Options,
+    ) -> Result<()> {
+        let (send_root, recv_root) = fstream::new();
+        let sender = tokio::spawn(send(send_root, entries));
+        let result = write(recv_root, dest, opts).await;
+        // If write fails, the sender is left waiting for a reply,
+        // so stop it as Tasks::join would.
+        sender.abort();
+        result
+    }
+
+    // contents returns a line for each entry underneath dir, in order,
+    // holding its path relative to dir and what it holds.
+    fn contents(dir: &Path) -> Vec<String> {
+        let mut lines = vec![];
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        paths.sort();
+        for path in paths {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let meta = std::fs::symlink_metadata(&path).unwrap();
+            if meta.file_type().is_symlink() {
+                let link = std::fs::read_link(&path).unwrap();
+                lines.push(format!("{} -> {}", name, link.display()));
+            } else if meta.is_dir() {
+                lines.push(format!("{}/", name));
+                for line in contents(&path) {
+                    lines.push(format!("{}/{}", name, line));
+                }
+            } else {
+                let data = std::fs::read(&path).unwrap();
+                lines.push(format!("{} {:?}", name, String::from_utf8_lossy(&data)));
+            }
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn write_bad_names() {
+        let base = temp_dir("bad-names");
+        let dest = base.join("dest");
+        for &name in &["", ".", "..", "../escape", "a/b", "/escape"] {
+            for &kind in &[fstream::Kind::File, fstream::Kind::Dir] {
+                let entries = vec![fstream::DirEntry::new(name, kind)];
+                let err = write_entries(&dest, entries, Options::default())
+                    .await
+                    .unwrap_err();
+                assert!(
+                    err.to_string().contains("invalid entry name"),
+                    "{:?}: {}",
+                    name,
+                    err
+                );
+            }
+        }
+        assert!(contents(&dest).is_empty());
+        assert_eq!(contents(&base), vec!["dest/"]);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn write_does_not_follow_symlinks() {
+        let base = temp_dir("symlinks");
+        let dest = base.join("dest");
+        let outside = base.join("outside");
+        std::fs::create_dir_all(outside.join("d")).unwrap();
+        std::fs::write(outside.join("f"), "outside").unwrap();
+        std::fs::create_dir(&dest).unwrap();
+        std::os::unix::fs::symlink(outside.join("d"), dest.join("d")).unwrap();
+        std::os::unix::fs::symlink(outside.join("f"), dest.join("f")).unwrap();
+        let mut link = fstream::DirEntry::new("l", fstream::Kind::Symlink);
+        link.link = Some(PathBuf::from("f"));
+        let entries = vec![
+            fstream::DirEntry::new("d", fstream::Kind::Dir),
+            fstream::DirEntry::new("f", fstream::Kind::File),
+            link,
+        ];
+        // Writing twice replaces the symbolic link
+        // written the first time.
+        for _ in 0..2 {
+            write_entries(&dest, entries.clone(), Options::default())
+                .await
+                .unwrap();
+            assert_eq!(contents(&dest), vec!["d/", "f \"x\"", "l -> f"]);
+        }
+        assert_eq!(contents(&outside), vec!["d/", "f \"outside\""]);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn write_attrs() {
+        let base = temp_dir("attrs");
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut file = fstream::DirEntry::new("f", fstream::Kind::File);
+        file.mode = 0o640;
+        file.mtime = mtime;
+        let mut dir = fstream::DirEntry::new("d", fstream::Kind::Dir);
+        dir.mode = 0o750;
+        dir.mtime = mtime + Duration::from_secs(1);
+        let entries = vec![dir, file];
+
+        let dest = base.join("plain");
+        write_entries(&dest, entries.clone(), Options::default())
+            .await
+            .unwrap();
+        let meta = std::fs::metadata(dest.join("f")).unwrap();
+        assert!(meta.modified().unwrap() > mtime + Duration::from_secs(1_000_000));
+
+        let dest = base.join("attrs");
+        let opts = Options {
+            mode: true,
+            mtime: true,
+        };
+        write_entries(&dest, entries, opts).await.unwrap();
+        let meta = std::fs::metadata(dest.join("f")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o640);
+        assert_eq!(meta.modified().unwrap(), mtime);
+        let meta = std::fs::metadata(dest.join("d")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o750);
+        assert_eq!(meta.modified().unwrap(), mtime + Duration::from_secs(1));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn write_walk_round_trip() {
+        let base = temp_dir("round-trip");
+        let src = base.join("src");
+        std::fs::create_dir_all(src.join("d/e")).unwrap();
+        std::fs::write(src.join("a"), "hello").unwrap();
+        std::fs::write(src.join("d/b"), vec![7; 100_000]).unwrap();
+        std::fs::write(src.join("d/empty"), "").unwrap();
+        std::os::unix::fs::symlink("../a", src.join("d/l")).unwrap();
+        let dest = base.join("dest");
+        // Writing into an existing tree gives the same result.
+        for _ in 0..2 {
+            let (send_root, recv_root) = fstream::new();
+            let walker = tokio::spawn(walk::walk(src.clone(), send_root, walk::Options::default()));
+            write(recv_root, &dest, Options::default()).await.unwrap();
+            walker.await.unwrap().unwrap();
+            assert_eq!(contents(&dest), contents(&src));
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}